@@ -65,6 +65,10 @@ async fn main() -> anyhow::Result<()> {
         }
     };
 
+    if let Err(e) = pedicab_db::migration::run(&db, cli.global.do_migrations) {
+        error!("error occurred migrating database: {e}");
+        process::exit(1);
+    }
 
     let dal = pedicab_db::dal::DataAccessLayer::new(db);
 
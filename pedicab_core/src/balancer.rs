@@ -0,0 +1,490 @@
+use std::{
+    cmp::min,
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use pedicab_db::{
+    data::rule::{Endpoint, RuleAddr, RuleOutlierDetection, RuleTargetPolicy, RuleTargetState},
+    model::rule::Rule,
+};
+use serde::Serialize;
+use tokio::sync::watch;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+// The live state of the targets of every rule, kept by address so that a rule restarted with new
+// settings still knows about the connections its targets carry.
+#[derive(Clone, Default)]
+pub struct Balancers(Arc<RwLock<HashMap<Uuid, TargetStates>>>);
+
+type TargetStates = HashMap<RuleAddr, Arc<TargetState>>;
+
+impl Balancers {
+    pub fn for_rule(&self, rule: &Rule) -> Balancer {
+        let mut rules = self.0.write().unwrap();
+        let states = rules.entry(rule.id.as_uuid()).or_default();
+
+        // targets dropped from the rule keep their established connections, they are just not tracked
+        states.retain(|addr, _| rule.target.addrs.iter().any(|target| target.addr == *addr));
+
+        let targets = rule
+            .target
+            .addrs
+            .iter()
+            .map(|target| {
+                let state = states.entry(target.addr.clone()).or_default();
+
+                match target.state {
+                    RuleTargetState::Disabled => state.close(),
+                    // re-enabled targets start over, their old connections are gone already
+                    _ if state.is_closed() => *state = Arc::default(),
+                    _ => {}
+                }
+
+                Target {
+                    addr: target.addr.clone(),
+                    weight: target.weight.max(1),
+                    backup: target.backup,
+                    active: target.state == RuleTargetState::Active,
+                    state: state.clone(),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let outliers = rule.config.outlier_detection.clone().map(|settings| {
+            Arc::new(Outliers {
+                settings,
+                states: targets.iter().map(|target| target.state.clone()).collect(),
+            })
+        });
+
+        Balancer {
+            policy: rule.target.policy.clone(),
+            cursor: AtomicUsize::new(0),
+            current_weights: Mutex::new(vec![0; targets.len()]),
+            targets,
+            outliers,
+        }
+    }
+
+    pub fn remove(&self, rule_id: Uuid) {
+        self.0.write().unwrap().remove(&rule_id);
+    }
+
+    // The state of the targets of a rule as far as it has been running.
+    pub fn status(&self, rule: &Rule) -> Vec<TargetStatus> {
+        let rules = self.0.read().unwrap();
+        let states = rules.get(&rule.id.as_uuid());
+        let now = Instant::now();
+
+        rule.target
+            .addrs
+            .iter()
+            .map(|target| {
+                let state = states.and_then(|states| states.get(&target.addr));
+                let health = state
+                    .map(|state| state.health.lock().unwrap().clone())
+                    .unwrap_or_default();
+
+                TargetStatus {
+                    addr: target.addr.clone(),
+                    state: target.state,
+                    connections: state.map_or(0, |state| state.connections.load(Ordering::Relaxed)),
+                    failures: health.failures,
+                    consecutive_failures: health.consecutive_failures,
+                    ejections: health.ejections,
+                    ejected_for: health
+                        .ejected_until
+                        .filter(|until| *until > now)
+                        .map(|until| until.duration_since(now).as_secs().max(1)),
+                }
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TargetStatus {
+    pub addr: RuleAddr,
+    pub state: RuleTargetState,
+    pub connections: usize,
+    pub failures: u64,
+    pub consecutive_failures: u32,
+    pub ejections: u64,
+    // seconds left until an ejected target takes connections again
+    pub ejected_for: Option<u64>,
+}
+
+pub struct TargetState {
+    connections: AtomicUsize,
+    closed: watch::Sender<bool>,
+    health: Mutex<TargetHealth>,
+}
+
+impl Default for TargetState {
+    fn default() -> Self {
+        TargetState {
+            connections: AtomicUsize::new(0),
+            closed: watch::Sender::new(false),
+            health: Mutex::default(),
+        }
+    }
+}
+
+impl TargetState {
+    fn close(&self) {
+        self.closed.send_replace(true);
+    }
+
+    fn is_closed(&self) -> bool {
+        *self.closed.borrow()
+    }
+
+    fn is_ejected(&self, now: Instant) -> bool {
+        self.health.lock().unwrap().is_ejected(now)
+    }
+}
+
+#[derive(Clone, Default)]
+struct TargetHealth {
+    failures: u64,
+    consecutive_failures: u32,
+    ejections: u64,
+    // ejections following closely on each other, each doubling the ejection time
+    ejection_streak: u32,
+    ejected_until: Option<Instant>,
+}
+
+impl TargetHealth {
+    fn is_ejected(&self, now: Instant) -> bool {
+        self.ejected_until.is_some_and(|until| until > now)
+    }
+}
+
+// The outlier detection settings of a rule along with all of its targets, which the share of
+// ejected targets is counted over.
+struct Outliers {
+    settings: RuleOutlierDetection,
+    states: Vec<Arc<TargetState>>,
+}
+
+impl Outliers {
+    fn eject(&self, state: &TargetState, endpoint: &Endpoint) {
+        let now = Instant::now();
+
+        let ejected = self.states.iter().filter(|state| state.is_ejected(now)).count();
+        if (ejected + 1) * 100 > self.states.len() * self.settings.max_ejected_percent as usize {
+            debug!("not ejecting {}, too many targets are ejected already", endpoint);
+            return;
+        }
+
+        let mut health = state.health.lock().unwrap();
+        if health.is_ejected(now) {
+            return;
+        }
+
+        let max_ejection_time = Duration::from_secs(self.settings.max_ejection_time);
+        // a target that stayed in long enough starts over with the base ejection time
+        if health
+            .ejected_until
+            .is_some_and(|until| now.duration_since(until) > max_ejection_time)
+        {
+            health.ejection_streak = 0;
+        }
+
+        let ejection_time = min(
+            Duration::from_secs(self.settings.ejection_time).saturating_mul(1 << min(health.ejection_streak, 16)),
+            max_ejection_time,
+        );
+
+        warn!(
+            "ejecting {} for {}s after {} failures in a row",
+            endpoint,
+            ejection_time.as_secs(),
+            health.consecutive_failures
+        );
+
+        health.ejections += 1;
+        health.ejection_streak += 1;
+        health.consecutive_failures = 0;
+        health.ejected_until = Some(now + ejection_time);
+    }
+}
+
+struct Target {
+    addr: RuleAddr,
+    weight: u32,
+    backup: bool,
+    // draining and disabled targets take no new connections
+    active: bool,
+    state: Arc<TargetState>,
+}
+
+// Picks the targets of new connections of a rule according to its policy.
+pub struct Balancer {
+    policy: RuleTargetPolicy,
+    targets: Vec<Target>,
+    // next target of the round robin policy
+    cursor: AtomicUsize,
+    // running weights of the smooth weighted round robin, one per target
+    current_weights: Mutex<Vec<i64>>,
+    outliers: Option<Arc<Outliers>>,
+}
+
+impl Balancer {
+    // The targets to try for a new connection in order. The target picked by the policy comes first,
+    // then the other active targets, and the backups after all of them. Ejected targets sit out until
+    // their ejection ends.
+    pub fn candidates(&self, offset: usize, client_addr: Option<SocketAddr>) -> Vec<Candidate> {
+        let now = Instant::now();
+
+        let (backups, primaries): (Vec<_>, Vec<_>) = (0..self.targets.len())
+            .filter(|&index| self.targets[index].active && !self.targets[index].state.is_ejected(now))
+            .partition(|&index| self.targets[index].backup);
+
+        self.order(primaries, client_addr)
+            .into_iter()
+            .chain(self.order(backups, client_addr))
+            .map(|index| {
+                let target = &self.targets[index];
+                Candidate {
+                    endpoint: target.addr.resolve(offset),
+                    tracked: Some(Tracked {
+                        state: target.state.clone(),
+                        outliers: self.outliers.clone(),
+                    }),
+                }
+            })
+            .collect()
+    }
+
+    fn order(&self, mut group: Vec<usize>, client_addr: Option<SocketAddr>) -> Vec<usize> {
+        if group.len() < 2 {
+            return group;
+        }
+
+        let weight = |index: usize| self.targets[index].weight as u64;
+
+        let client_key = match (&self.policy, client_addr) {
+            (RuleTargetPolicy::SourceIpHash, Some(client_addr)) => Some(ip_key(client_addr.ip())),
+            (RuleTargetPolicy::SourceAddrHash, Some(client_addr)) => {
+                let mut key = ip_key(client_addr.ip());
+                key.extend_from_slice(&client_addr.port().to_be_bytes());
+                Some(key)
+            }
+            _ => None,
+        };
+
+        if let Some(client_key) = client_key {
+            // weighted rendezvous hashing: every target draws a score from the client and its own
+            // address, the highest score wins. Adding or removing a target only moves the clients whose
+            // best score changes, and the runner-ups are the targets to fail over to.
+            let score = |index: usize| {
+                let target = &self.targets[index];
+                let hash = hash_key(&client_key, target.addr.to_string().as_bytes());
+                // a uniform value in (0, 1)
+                let unit = ((hash >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+                target.weight as f64 / -unit.ln()
+            };
+
+            let mut scored = group.into_iter().map(|index| (score(index), index)).collect::<Vec<_>>();
+            scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+            return scored.into_iter().map(|(_, index)| index).collect();
+        }
+
+        let first = match self.policy {
+            // clients without an address, like those of unix sockets, have nothing to hash
+            RuleTargetPolicy::Fallback | RuleTargetPolicy::SourceIpHash | RuleTargetPolicy::SourceAddrHash => 0,
+            RuleTargetPolicy::RoundRobin => self.cursor.fetch_add(1, Ordering::Relaxed) % group.len(),
+            RuleTargetPolicy::LeastConnections => {
+                // fewest connections per unit of weight, earlier targets win ties
+                let load = |index: usize| self.targets[index].state.connections.load(Ordering::Relaxed) as u64;
+                (0..group.len())
+                    .min_by(|&a, &b| (load(group[a]) * weight(group[b])).cmp(&(load(group[b]) * weight(group[a]))))
+                    .unwrap()
+            }
+            RuleTargetPolicy::Random => rand::random_range(0..group.len()),
+            RuleTargetPolicy::WeightedRoundRobin => {
+                // smooth weighted round robin: every target gains its weight, the one ahead is picked and
+                // pays back the total, which interleaves the targets instead of sending bursts to each
+                let mut current_weights = self.current_weights.lock().unwrap();
+                let total = group.iter().map(|&index| weight(index) as i64).sum::<i64>();
+
+                for &index in &group {
+                    current_weights[index] += weight(index) as i64;
+                }
+                let first = (0..group.len())
+                    .max_by(|&a, &b| {
+                        current_weights[group[a]]
+                            .cmp(&current_weights[group[b]])
+                            .then(b.cmp(&a))
+                    })
+                    .unwrap();
+                current_weights[group[first]] -= total;
+
+                first
+            }
+            RuleTargetPolicy::WeightedRandom => {
+                let total = group.iter().map(|&index| weight(index)).sum::<u64>();
+                let mut point = rand::random_range(0..total);
+
+                group
+                    .iter()
+                    .position(|&index| match point.checked_sub(weight(index)) {
+                        Some(rest) => {
+                            point = rest;
+                            false
+                        }
+                        None => true,
+                    })
+                    .unwrap()
+            }
+        };
+
+        // the rest stays in list order to fail over to
+        let first = group.remove(first);
+        group.insert(0, first);
+        group
+    }
+}
+
+// IPv4 clients hash the same whether they come in over IPv4 or as IPv4-mapped IPv6 addresses.
+fn ip_key(ip: IpAddr) -> Vec<u8> {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+// FNV-1a finished with the splitmix64 mixer. Placements have to survive restarts and upgrades, so
+// this can't use a randomly seeded hasher or one whose output may change between releases.
+fn hash_key(client_key: &[u8], target_key: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in client_key.iter().chain([0xff].iter()).chain(target_key) {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
+
+#[derive(Clone)]
+struct Tracked {
+    state: Arc<TargetState>,
+    outliers: Option<Arc<Outliers>>,
+}
+
+impl Tracked {
+    fn report(&self, endpoint: &Endpoint, failed: bool) {
+        let mut health = self.state.health.lock().unwrap();
+
+        if !failed {
+            health.consecutive_failures = 0;
+            return;
+        }
+
+        health.failures += 1;
+        health.consecutive_failures += 1;
+
+        if let Some(outliers) = &self.outliers
+            && health.consecutive_failures >= outliers.settings.consecutive_failures
+        {
+            // counting the ejected targets locks each of them
+            drop(health);
+            outliers.eject(&self.state, endpoint);
+        }
+    }
+}
+
+// A target a connection may go to, either picked by a balancer or fixed by the connection itself,
+// like an original destination or a matched route.
+#[derive(Clone)]
+pub struct Candidate {
+    pub endpoint: Endpoint,
+    tracked: Option<Tracked>,
+}
+
+impl Candidate {
+    pub fn fixed(endpoint: Endpoint) -> Self {
+        Candidate {
+            endpoint,
+            tracked: None,
+        }
+    }
+
+    pub fn report_failure(&self) {
+        if let Some(tracked) = &self.tracked {
+            tracked.report(&self.endpoint, true);
+        }
+    }
+
+    // Counts a connection against the target for as long as the lease lives.
+    pub fn lease(self) -> TargetLease {
+        if let Some(tracked) = &self.tracked {
+            tracked.state.connections.fetch_add(1, Ordering::Relaxed);
+        }
+
+        TargetLease {
+            endpoint: self.endpoint,
+            tracked: self.tracked,
+        }
+    }
+}
+
+// A connection established to a target.
+pub struct TargetLease {
+    endpoint: Endpoint,
+    tracked: Option<Tracked>,
+}
+
+impl TargetLease {
+    pub fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+
+    // The target served the connection well, which ends a run of failures.
+    pub fn report_success(&self) {
+        if let Some(tracked) = &self.tracked {
+            tracked.report(&self.endpoint, false);
+        }
+    }
+
+    pub fn report_failure(&self) {
+        if let Some(tracked) = &self.tracked {
+            tracked.report(&self.endpoint, true);
+        }
+    }
+
+    // Resolves once the target gets disabled and its connections have to be closed.
+    pub async fn closed(&self) {
+        if let Some(tracked) = &self.tracked
+            && tracked
+                .state
+                .closed
+                .subscribe()
+                .wait_for(|closed| *closed)
+                .await
+                .is_ok()
+        {
+            return;
+        }
+
+        std::future::pending().await
+    }
+}
+
+impl Drop for TargetLease {
+    fn drop(&mut self) {
+        if let Some(tracked) = &self.tracked {
+            tracked.state.connections.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
@@ -0,0 +1,530 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use pedicab_db::data::rule::Endpoint;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+pub const DEFAULT_DURATION: Duration = Duration::from_secs(60);
+pub const MAX_DURATION: Duration = Duration::from_secs(60 * 60);
+pub const DEFAULT_SIZE: u64 = 16 * 1024 * 1024;
+pub const MAX_SIZE: u64 = 256 * 1024 * 1024;
+
+// payload carried by a single synthesized packet, larger reads are split over several segments
+const MAX_PAYLOAD: usize = 65000;
+// the capture file is kept in parts of this size, so that downloads share them instead of copying
+const FILE_PART_SIZE: usize = 1024 * 1024;
+
+// pcapng block types
+const SECTION_HEADER_BLOCK: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 0x0000_0001;
+const ENHANCED_PACKET_BLOCK: u32 = 0x0000_0006;
+// raw IPv4 or IPv6 packets without a link layer
+const LINKTYPE_RAW: u16 = 101;
+
+const PROTOCOL_TCP: u8 = 6;
+const PROTOCOL_UDP: u8 = 17;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+
+static CAPTURE_IDS: AtomicU64 = AtomicU64::new(1);
+// ports standing in for unix socket clients, so that their connections stay apart in a capture
+static UNIX_PORTS: AtomicU16 = AtomicU16::new(1);
+
+#[derive(Debug, Default, Deserialize)]
+pub struct CaptureParams {
+    // seconds
+    pub duration: Option<u64>,
+    // bytes of the capture file
+    pub max_size: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CaptureStatus {
+    // unix timestamp in milliseconds
+    pub started_at: u64,
+    pub duration: u64,
+    pub max_size: u64,
+    pub size: u64,
+    pub packets: u64,
+    pub finished: bool,
+}
+
+// Captures of the relayed traffic of rules, at most one per rule. A capture is kept after it
+// finished until it is replaced or removed, so that it can still be downloaded.
+#[derive(Clone, Default)]
+pub struct Captures(Arc<RwLock<HashMap<Uuid, Arc<CaptureSlot>>>>);
+
+impl Captures {
+    fn slot(&self, rule_id: Uuid) -> Arc<CaptureSlot> {
+        self.0.write().unwrap().entry(rule_id).or_default().clone()
+    }
+
+    pub fn start(&self, rule_id: Uuid, duration: Duration, max_size: u64) -> Arc<Capture> {
+        let capture = Arc::new(Capture::new(duration, max_size));
+
+        let slot = self.slot(rule_id);
+        let mut current = slot.capture.write().unwrap();
+        *current = Some(capture.clone());
+        slot.recording.store(capture.id, Ordering::Relaxed);
+
+        capture
+    }
+
+    pub fn get(&self, rule_id: Uuid) -> Option<Arc<Capture>> {
+        let slot = self.0.read().unwrap().get(&rule_id).cloned()?;
+        slot.capture.read().unwrap().clone()
+    }
+
+    // Stops and drops the capture of a rule. The slot goes too unless forwarders of the rule hold it,
+    // they would not see a later capture otherwise.
+    pub fn discard(&self, rule_id: Uuid) -> Option<Arc<Capture>> {
+        let slot = {
+            let mut slots = self.0.write().unwrap();
+            let slot = slots.get(&rule_id).cloned()?;
+            // held by the map and this function only
+            if Arc::strong_count(&slot) == 2 {
+                slots.remove(&rule_id);
+            }
+            slot
+        };
+
+        let mut current = slot.capture.write().unwrap();
+        slot.recording.store(0, Ordering::Relaxed);
+        current.take()
+    }
+
+    // Forgets a rule that no longer runs. Its capture is dropped right away, connections left over
+    // from the rule may hold the slot for a while.
+    pub fn remove(&self, rule_id: Uuid) {
+        let Some(slot) = self.0.write().unwrap().remove(&rule_id) else {
+            return;
+        };
+
+        let mut current = slot.capture.write().unwrap();
+        slot.recording.store(0, Ordering::Relaxed);
+        current.take();
+    }
+
+    pub(crate) fn for_rule(&self, rule_id: Uuid) -> RuleCapture {
+        RuleCapture(self.slot(rule_id))
+    }
+}
+
+// The capture of a rule, if there is one.
+#[derive(Default)]
+struct CaptureSlot {
+    // id of the capture while it may still record, zero otherwise, so that rules without one skip
+    // the lock
+    recording: AtomicU64,
+    capture: RwLock<Option<Arc<Capture>>>,
+}
+
+// The view of a forwarder on the capture of its rule, checked for every relayed chunk so that a
+// capture started later also covers connections that are already open.
+#[derive(Clone)]
+pub struct RuleCapture(Arc<CaptureSlot>);
+
+impl RuleCapture {
+    pub fn active(&self) -> Option<Arc<Capture>> {
+        if self.0.recording.load(Ordering::Relaxed) == 0 {
+            return None;
+        }
+
+        let capture = self.0.capture.read().unwrap().clone()?;
+        if capture.is_active() {
+            return Some(capture);
+        }
+
+        // the capture is over, unless it has been replaced in the meantime
+        let _ = self
+            .0
+            .recording
+            .compare_exchange(capture.id, 0, Ordering::Relaxed, Ordering::Relaxed);
+        None
+    }
+}
+
+// A pcapng file built in memory. The relayed payload is wrapped into synthesized IP and TCP or UDP
+// headers carrying the client and target addresses, as it is seen by pedicab, i.e. after TLS
+// termination.
+pub struct Capture {
+    id: u64,
+    started_at: SystemTime,
+    deadline: Instant,
+    duration: Duration,
+    max_size: u64,
+    finished: AtomicBool,
+    state: Mutex<CaptureState>,
+}
+
+struct CaptureState {
+    // the file in full parts and the part being written
+    parts: Vec<Bytes>,
+    buffer: Vec<u8>,
+    size: u64,
+    packets: u64,
+}
+
+impl Capture {
+    fn new(duration: Duration, max_size: u64) -> Self {
+        let mut buffer = Vec::with_capacity(4096);
+
+        write_block(&mut buffer, SECTION_HEADER_BLOCK, |body| {
+            // byte order magic, version 1.0 and an unspecified section length
+            body.extend_from_slice(&0x1A2B_3C4Du32.to_le_bytes());
+            body.extend_from_slice(&1u16.to_le_bytes());
+            body.extend_from_slice(&0u16.to_le_bytes());
+            body.extend_from_slice(&(-1i64).to_le_bytes());
+        });
+        write_block(&mut buffer, INTERFACE_DESCRIPTION_BLOCK, |body| {
+            body.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+            body.extend_from_slice(&0u16.to_le_bytes());
+            // no snap length
+            body.extend_from_slice(&0u32.to_le_bytes());
+        });
+
+        Capture {
+            id: CAPTURE_IDS.fetch_add(1, Ordering::Relaxed),
+            started_at: SystemTime::now(),
+            deadline: Instant::now() + duration,
+            duration,
+            max_size,
+            finished: AtomicBool::new(false),
+            state: Mutex::new(CaptureState {
+                parts: Vec::new(),
+                size: buffer.len() as u64,
+                buffer,
+                packets: 0,
+            }),
+        }
+    }
+
+    pub fn status(&self) -> CaptureStatus {
+        let state = self.state.lock().unwrap();
+
+        CaptureStatus {
+            started_at: self
+                .started_at
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis() as u64),
+            duration: self.duration.as_secs(),
+            max_size: self.max_size,
+            size: state.size,
+            packets: state.packets,
+            finished: !self.is_active(),
+        }
+    }
+
+    // The pcapng file with everything captured so far, in parts shared with the capture. Only the
+    // part being written is copied.
+    pub fn file(&self) -> Vec<Bytes> {
+        let state = self.state.lock().unwrap();
+
+        let mut file = state.parts.clone();
+        file.push(Bytes::copy_from_slice(&state.buffer));
+        file
+    }
+
+    fn is_active(&self) -> bool {
+        !self.finished.load(Ordering::Relaxed) && Instant::now() < self.deadline
+    }
+
+    // Records a datagram relayed between a udp client and its target.
+    pub fn record_datagram(&self, src: SocketAddr, dst: SocketAddr, data: &[u8]) {
+        let (src, dst) = same_family(src, dst);
+        let data = &data[..data.len().min(MAX_PAYLOAD)];
+
+        let mut segment = Vec::with_capacity(8 + data.len());
+        segment.extend_from_slice(&src.port().to_be_bytes());
+        segment.extend_from_slice(&dst.port().to_be_bytes());
+        segment.extend_from_slice(&((8 + data.len()) as u16).to_be_bytes());
+        segment.extend_from_slice(&[0, 0]);
+        segment.extend_from_slice(data);
+
+        self.record_packet(src, dst, PROTOCOL_UDP, segment, 6);
+    }
+
+    fn record_packet(&self, src: SocketAddr, dst: SocketAddr, protocol: u8, mut segment: Vec<u8>, checksum_at: usize) {
+        if !self.is_active() {
+            return;
+        }
+
+        let checksum = transport_checksum(src.ip(), dst.ip(), protocol, &segment);
+        // a zero udp checksum means no checksum, an all ones one is sent instead
+        let checksum = if protocol == PROTOCOL_UDP && checksum == 0 {
+            0xFFFF
+        } else {
+            checksum
+        };
+        segment[checksum_at..checksum_at + 2].copy_from_slice(&checksum.to_be_bytes());
+
+        let packet = ip_packet(src.ip(), dst.ip(), protocol, &segment);
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_micros() as u64);
+
+        let mut block = Vec::with_capacity(packet.len() + 32);
+        write_block(&mut block, ENHANCED_PACKET_BLOCK, |body| {
+            // interface, timestamp in microseconds, captured and original length
+            body.extend_from_slice(&0u32.to_le_bytes());
+            body.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+            body.extend_from_slice(&(timestamp as u32).to_le_bytes());
+            body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+            body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+            body.extend_from_slice(&packet);
+        });
+
+        let mut state = self.state.lock().unwrap();
+        if state.size + block.len() as u64 > self.max_size {
+            self.finished.store(true, Ordering::Relaxed);
+            return;
+        }
+        state.buffer.extend_from_slice(&block);
+        state.size += block.len() as u64;
+        state.packets += 1;
+
+        if state.buffer.len() >= FILE_PART_SIZE {
+            let part = std::mem::replace(&mut state.buffer, Vec::with_capacity(FILE_PART_SIZE));
+            state.parts.push(Bytes::from(part));
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Direction {
+    ToTarget,
+    ToClient,
+}
+
+// A tcp connection as it appears in captures. The handshake is synthesized when the first segment
+// of the connection is recorded by a capture, so connections that were open before the capture
+// started still show up as whole streams.
+pub struct TcpFlow {
+    client: SocketAddr,
+    target: SocketAddr,
+    state: Mutex<TcpFlowState>,
+}
+
+#[derive(Default)]
+struct TcpFlowState {
+    // the capture the handshake was recorded in
+    capture: Option<u64>,
+    client_seq: u32,
+    target_seq: u32,
+}
+
+impl TcpFlow {
+    pub fn new(client: Option<SocketAddr>, target: &Endpoint) -> Self {
+        let client = client.unwrap_or_else(|| {
+            SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                UNIX_PORTS.fetch_add(1, Ordering::Relaxed).max(1),
+            )
+        });
+        let target = match target {
+            Endpoint::Inet(addr) => *addr,
+            Endpoint::Unix(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        };
+        let (client, target) = same_family(client, target);
+
+        TcpFlow {
+            client,
+            target,
+            state: Mutex::new(TcpFlowState::default()),
+        }
+    }
+
+    pub fn record(&self, capture: &Capture, direction: Direction, data: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        self.open(capture, &mut state);
+
+        for chunk in data.chunks(MAX_PAYLOAD) {
+            self.segment(capture, &mut state, direction, TCP_PSH | TCP_ACK, chunk);
+        }
+    }
+
+    // Records both sides closing, if the connection is part of the capture.
+    pub fn close(&self, capture: &Capture) {
+        let mut state = self.state.lock().unwrap();
+        if state.capture != Some(capture.id) {
+            return;
+        }
+
+        self.segment(capture, &mut state, Direction::ToTarget, TCP_FIN | TCP_ACK, &[]);
+        self.segment(capture, &mut state, Direction::ToClient, TCP_FIN | TCP_ACK, &[]);
+        self.segment(capture, &mut state, Direction::ToTarget, TCP_ACK, &[]);
+    }
+
+    fn open(&self, capture: &Capture, state: &mut TcpFlowState) {
+        if state.capture == Some(capture.id) {
+            return;
+        }
+
+        *state = TcpFlowState {
+            capture: Some(capture.id),
+            ..Default::default()
+        };
+        self.segment(capture, state, Direction::ToTarget, TCP_SYN, &[]);
+        self.segment(capture, state, Direction::ToClient, TCP_SYN | TCP_ACK, &[]);
+        self.segment(capture, state, Direction::ToTarget, TCP_ACK, &[]);
+    }
+
+    fn segment(&self, capture: &Capture, state: &mut TcpFlowState, direction: Direction, flags: u8, data: &[u8]) {
+        let (src, dst, seq, ack) = match direction {
+            Direction::ToTarget => (self.client, self.target, state.client_seq, state.target_seq),
+            Direction::ToClient => (self.target, self.client, state.target_seq, state.client_seq),
+        };
+
+        let mut segment = Vec::with_capacity(20 + data.len());
+        segment.extend_from_slice(&src.port().to_be_bytes());
+        segment.extend_from_slice(&dst.port().to_be_bytes());
+        segment.extend_from_slice(&seq.to_be_bytes());
+        segment.extend_from_slice(&(if flags & TCP_ACK != 0 { ack } else { 0 }).to_be_bytes());
+        // header length of five words
+        segment.push(5 << 4);
+        segment.push(flags);
+        segment.extend_from_slice(&u16::MAX.to_be_bytes());
+        segment.extend_from_slice(&[0, 0, 0, 0]);
+        segment.extend_from_slice(data);
+
+        capture.record_packet(src, dst, PROTOCOL_TCP, segment, 16);
+
+        // SYN and FIN take up a sequence number of their own
+        let consumed = data.len() as u32 + u32::from(flags & (TCP_SYN | TCP_FIN) != 0);
+        match direction {
+            Direction::ToTarget => state.client_seq = state.client_seq.wrapping_add(consumed),
+            Direction::ToClient => state.target_seq = state.target_seq.wrapping_add(consumed),
+        }
+    }
+}
+
+// Blocks are padded to 32 bits and carry their total length at both ends.
+fn write_block(buffer: &mut Vec<u8>, block_type: u32, body: impl FnOnce(&mut Vec<u8>)) {
+    let start = buffer.len();
+
+    buffer.extend_from_slice(&block_type.to_le_bytes());
+    buffer.extend_from_slice(&0u32.to_le_bytes());
+    body(buffer);
+    buffer.resize(buffer.len().next_multiple_of(4), 0);
+
+    let length = (buffer.len() - start + 4) as u32;
+    buffer[start + 4..start + 8].copy_from_slice(&length.to_le_bytes());
+    buffer.extend_from_slice(&length.to_le_bytes());
+}
+
+// Packets of a flow need a single address family, IPv4 addresses are mapped if the sides differ.
+fn same_family(a: SocketAddr, b: SocketAddr) -> (SocketAddr, SocketAddr) {
+    match (a, b) {
+        (SocketAddr::V4(_), SocketAddr::V6(_)) => (to_mapped(a), b),
+        (SocketAddr::V6(_), SocketAddr::V4(_)) => (a, to_mapped(b)),
+        _ => (a, b),
+    }
+}
+
+fn to_mapped(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port()),
+        IpAddr::V6(_) => addr,
+    }
+}
+
+fn ip_packet(src: IpAddr, dst: IpAddr, protocol: u8, segment: &[u8]) -> Vec<u8> {
+    match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let mut header = Vec::with_capacity(20 + segment.len());
+            header.push(0x45);
+            header.push(0);
+            header.extend_from_slice(&((20 + segment.len()) as u16).to_be_bytes());
+            // identification, don't fragment
+            header.extend_from_slice(&[0, 0, 0x40, 0]);
+            header.push(64);
+            header.push(protocol);
+            header.extend_from_slice(&[0, 0]);
+            header.extend_from_slice(&src.octets());
+            header.extend_from_slice(&dst.octets());
+
+            let checksum = checksum(&[&header]);
+            header[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+            header.extend_from_slice(segment);
+            header
+        }
+        _ => {
+            let (src, dst) = (to_v6(src), to_v6(dst));
+
+            let mut header = Vec::with_capacity(40 + segment.len());
+            header.extend_from_slice(&[0x60, 0, 0, 0]);
+            header.extend_from_slice(&(segment.len() as u16).to_be_bytes());
+            header.push(protocol);
+            header.push(64);
+            header.extend_from_slice(&src.octets());
+            header.extend_from_slice(&dst.octets());
+
+            header.extend_from_slice(segment);
+            header
+        }
+    }
+}
+
+fn to_v6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+fn transport_checksum(src: IpAddr, dst: IpAddr, protocol: u8, segment: &[u8]) -> u16 {
+    let pseudo_header = match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => [
+            &src.octets()[..],
+            &dst.octets(),
+            &[0, protocol],
+            &(segment.len() as u16).to_be_bytes(),
+        ]
+        .concat(),
+        _ => [
+            &to_v6(src).octets()[..],
+            &to_v6(dst).octets(),
+            &(segment.len() as u32).to_be_bytes(),
+            &[0, 0, 0, protocol],
+        ]
+        .concat(),
+    };
+
+    checksum(&[&pseudo_header, segment])
+}
+
+// internet checksum over the concatenation of the parts, each of which but the last has to be of
+// even length
+fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u64;
+
+    for part in parts {
+        for word in part.chunks(2) {
+            let word = match word {
+                [high, low] => u16::from_be_bytes([*high, *low]),
+                [high] => u16::from_be_bytes([*high, 0]),
+                _ => unreachable!(),
+            };
+            sum += u64::from(word);
+        }
+    }
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
@@ -0,0 +1,107 @@
+use std::{
+    collections::HashMap,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use pedicab_db::{
+    dal::DataAccessLayer,
+    data::{
+        history::{HostHistoryPoint, RuleHistoryPoint},
+        rule::RuleStats,
+    },
+};
+use sysinfo::{Networks, System};
+use tracing::debug;
+use uuid::Uuid;
+
+// Turns the stats of the running rules and the load of the host into samples of their history.
+pub struct HistoryRecorder {
+    dal: DataAccessLayer,
+    // bandwidth of every running rule at the last sample, the bytes of a sample are the difference
+    bandwidth: HashMap<Uuid, u64>,
+    system: System,
+    networks: Networks,
+    last_sample: Instant,
+}
+
+impl HistoryRecorder {
+    pub fn new(dal: DataAccessLayer) -> Self {
+        HistoryRecorder {
+            dal,
+            bandwidth: HashMap::new(),
+            system: System::new(),
+            networks: Networks::new_with_refreshed_list(),
+            last_sample: Instant::now(),
+        }
+    }
+
+    pub async fn sample(&mut self, stats: HashMap<Uuid, RuleStats>) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        // a late tick covers more than a second
+        let seconds = (self.last_sample.elapsed().as_secs_f64().round() as u64).max(1);
+        self.last_sample = Instant::now();
+
+        for (rule_id, stat) in &stats {
+            // the first sample of a rule only sets the baseline, and a stats reset starts it over
+            let bytes = match self.bandwidth.insert(*rule_id, stat.bandwidth) {
+                Some(last) if last <= stat.bandwidth => stat.bandwidth - last,
+                Some(_) => stat.bandwidth,
+                None => 0,
+            };
+
+            let sample = RuleHistoryPoint {
+                timestamp,
+                seconds,
+                bytes,
+                speed: bytes / seconds,
+                connections: stat.connections.tcp + stat.connections.udp,
+            };
+            if let Err(e) = self.dal.history.record_rule(*rule_id, sample).await {
+                debug!("error occurred recording history of rule {}: {}", rule_id, e);
+            }
+        }
+
+        // rules that stopped running are dropped, along with their history once they were deleted, as
+        // they were still sampled until the deletion stopped them
+        let stopped = self
+            .bandwidth
+            .keys()
+            .filter(|rule_id| !stats.contains_key(rule_id))
+            .copied()
+            .collect::<Vec<_>>();
+        for rule_id in stopped {
+            self.bandwidth.remove(&rule_id);
+            if let Ok(None) = self.dal.rule.find_by_id(rule_id).await
+                && let Err(e) = self.dal.history.delete_rule(rule_id).await
+            {
+                debug!("error occurred deleting history of rule {}: {}", rule_id, e);
+            }
+        }
+
+        self.system.refresh_cpu_usage();
+        self.system.refresh_memory();
+        self.networks.refresh(true);
+
+        let interfaces = self
+            .networks
+            .iter()
+            .filter(|&(interface_name, _)| interface_name != "lo" && interface_name != "lo0");
+        let (received, sent) = interfaces.fold((0, 0), |(received, sent), (_, data)| {
+            (received + data.received(), sent + data.transmitted())
+        });
+
+        let sample = HostHistoryPoint {
+            timestamp,
+            seconds,
+            cpu_usage: self.system.global_cpu_usage(),
+            memory_used: self.system.used_memory(),
+            received,
+            sent,
+        };
+        if let Err(e) = self.dal.history.record_host(sample).await {
+            debug!("error occurred recording history of host: {}", e);
+        }
+    }
+}
@@ -0,0 +1,402 @@
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use http::{
+    HeaderMap, HeaderName, HeaderValue, Request, Response, StatusCode, Uri, Version,
+    header::{CONNECTION, HOST, UPGRADE},
+};
+use http_body_util::{BodyExt, Full, combinators::BoxBody};
+use hyper::{body::Incoming, client::conn::http1::SendRequest, service::service_fn};
+use hyper_util::rt::TokioIo;
+use pedicab_db::data::rule::{Endpoint, RuleStats, RuleStatsConnections, RuleStatsHttp};
+use tokio::{sync::Mutex, task::JoinSet};
+use tracing::{Span, debug, info_span, trace, warn};
+
+use crate::{
+    balancer::Candidate,
+    stream::{BoxedStream, Metered},
+    tcp::{TcpForward, spawn_stats_updater},
+};
+
+type ProxyBody = BoxBody<Bytes, hyper::Error>;
+
+const DEFAULT_ROUTE: &str = "default";
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+
+// headers that only apply to a single connection and are not forwarded
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+// Serves HTTP/1.1 requests of a client connection, forwarding each of them to the target its route
+// points at.
+pub async fn handle_connection(
+    client_stream: BoxedStream, peer_addr: Option<SocketAddr>, offset: usize, default_targets: Vec<Candidate>,
+    source_addr: Option<SocketAddr>, forward: Arc<TcpForward>,
+) {
+    let rule_id = forward.rule.id.as_uuid();
+
+    let span = info_span!("handle_http_connection", rule_id = rule_id.to_string());
+
+    let (client_stream, proto) = match &forward.tls {
+        Some(tls) => match tls.accept(client_stream).await {
+            Ok(stream) => (stream, if tls.terminates() { "https" } else { "http" }),
+            Err(e) => {
+                warn!(parent: &span, "tls handshake with client failed: {}", e);
+                return;
+            }
+        },
+        None => (client_stream, "http"),
+    };
+
+    update_stats(&forward, 1, 0).await;
+
+    let transferred_bytes = Arc::new(AtomicU64::new(0));
+    let stats_updater = spawn_stats_updater(
+        rule_id,
+        transferred_bytes.clone(),
+        forward.stats_cache.clone(),
+        Duration::from_millis(forward.config.stats_update_interval),
+    );
+
+    // requests of a connection may go to different targets, so only the client is accounted
+    let meter = forward.telemetry.open_connection(peer_addr, None);
+
+    let proxy = Arc::new(Proxy {
+        forward: forward.clone(),
+        offset,
+        default_targets,
+        source_addr,
+        client_ip: peer_addr.map(|addr| addr.ip().to_string()),
+        proto,
+        upstream: Mutex::new(None),
+        upgrades: std::sync::Mutex::new(JoinSet::new()),
+        span: span.clone(),
+    });
+
+    let service = service_fn({
+        let proxy = proxy.clone();
+        move |request| {
+            let proxy = proxy.clone();
+            async move { Ok::<_, Infallible>(proxy.handle(request).await) }
+        }
+    });
+
+    if let Err(e) = hyper::server::conn::http1::Builder::new()
+        .serve_connection(
+            TokioIo::new(Metered::new(client_stream, transferred_bytes.clone(), meter)),
+            service,
+        )
+        .with_upgrades()
+        .await
+    {
+        debug!(parent: &span, "http connection closed with error: {}", e);
+    }
+
+    // upgraded connections are relayed on after hyper lets go of them, and still count as the
+    // connection until they close or the rule stops
+    let mut upgrades = std::mem::take(&mut *proxy.upgrades.lock().unwrap());
+    tokio::select! {
+        _ = async { while upgrades.join_next().await.is_some() {} } => {}
+        _ = forward.stopped() => {}
+    }
+    drop(upgrades);
+
+    stats_updater.abort();
+    // the bytes counted since the last update would be lost otherwise
+    update_stats(&forward, -1, transferred_bytes.swap(0, Ordering::Relaxed)).await;
+}
+
+struct Proxy {
+    forward: Arc<TcpForward>,
+    offset: usize,
+    // the targets of the rule in the order picked for this client connection
+    default_targets: Vec<Candidate>,
+    source_addr: Option<SocketAddr>,
+    client_ip: Option<String>,
+    proto: &'static str,
+    // kept alive between the requests of the client connection
+    upstream: Mutex<Option<(Endpoint, SendRequest<Incoming>)>>,
+    // relays of the upgraded requests, websockets and the like
+    upgrades: std::sync::Mutex<JoinSet<()>>,
+    span: Span,
+}
+
+impl Proxy {
+    async fn handle(&self, mut request: Request<Incoming>) -> Response<ProxyBody> {
+        let (label, targets) = self.route(&request);
+
+        trace!(parent: &self.span, "{} {} routed to {}", request.method(), request.uri(), label);
+
+        let upgrade = is_upgrade(request.headers());
+        let client_upgrade = upgrade.then(|| hyper::upgrade::on(&mut request));
+
+        let response = match self
+            .forward_request(self.prepare(request, upgrade), &targets, upgrade)
+            .await
+        {
+            Ok(mut response) => {
+                if response.status() == StatusCode::SWITCHING_PROTOCOLS
+                    && let Some(client_upgrade) = client_upgrade
+                {
+                    let server_upgrade = hyper::upgrade::on(&mut response);
+                    let span = self.span.clone();
+
+                    self.upgrades.lock().unwrap().spawn(async move {
+                        match tokio::try_join!(client_upgrade, server_upgrade) {
+                            Ok((client, server)) => {
+                                let _ =
+                                    tokio::io::copy_bidirectional(&mut TokioIo::new(client), &mut TokioIo::new(server))
+                                        .await;
+                            }
+                            Err(e) => debug!(parent: &span, "failed to upgrade connection: {}", e),
+                        }
+                    });
+                } else {
+                    remove_hop_by_hop_headers(response.headers_mut());
+                }
+
+                response.map(BodyExt::boxed)
+            }
+            Err(e) => {
+                warn!(parent: &self.span, "failed to forward request routed to {}: {}", label, e);
+
+                Response::builder()
+                    .status(StatusCode::BAD_GATEWAY)
+                    .body(
+                        Full::new(Bytes::from_static(b"Bad Gateway"))
+                            .map_err(|e| match e {})
+                            .boxed(),
+                    )
+                    .unwrap()
+            }
+        };
+
+        self.record(label, response.status()).await;
+
+        response
+    }
+
+    // Picks the first route matching the host and path of the request.
+    fn route(&self, request: &Request<Incoming>) -> (String, Vec<Candidate>) {
+        let host = request
+            .headers()
+            .get(HOST)
+            .and_then(|host| host.to_str().ok())
+            .or_else(|| request.uri().authority().map(|authority| authority.as_str()))
+            .map(|host| strip_port(host).to_ascii_lowercase());
+        let path = request.uri().path();
+
+        let route = self
+            .forward
+            .rule
+            .config
+            .http
+            .iter()
+            .flat_map(|http| http.routes.iter())
+            .find(|route| {
+                let host_matches = match (&route.host, &host) {
+                    (None, _) => true,
+                    (Some(_), None) => false,
+                    (Some(pattern), Some(host)) => {
+                        let pattern = pattern.to_ascii_lowercase();
+                        match pattern.strip_prefix('*') {
+                            Some(suffix) => suffix.starts_with('.') && host.ends_with(suffix),
+                            None => *host == pattern,
+                        }
+                    }
+                };
+                let path_matches = route
+                    .path_prefix
+                    .as_ref()
+                    .is_none_or(|prefix| path.starts_with(prefix.as_str()));
+
+                host_matches && path_matches
+            });
+
+        match route {
+            Some(route) => (route.label(), vec![Candidate::fixed(route.target.resolve(self.offset))]),
+            None => (String::from(DEFAULT_ROUTE), self.default_targets.clone()),
+        }
+    }
+
+    fn prepare(&self, mut request: Request<Incoming>, upgrade: bool) -> Request<Incoming> {
+        let headers = request.headers_mut();
+
+        let upgrade_protocol = headers.get(UPGRADE).cloned();
+        remove_hop_by_hop_headers(headers);
+        if upgrade && let Some(upgrade_protocol) = upgrade_protocol {
+            headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+            headers.insert(UPGRADE, upgrade_protocol);
+        }
+
+        if let Some(client_ip) = &self.client_ip {
+            let forwarded_for = match headers.get(X_FORWARDED_FOR).and_then(|value| value.to_str().ok()) {
+                Some(forwarded_for) => format!("{}, {}", forwarded_for, client_ip),
+                None => client_ip.clone(),
+            };
+            if let Ok(value) = HeaderValue::from_str(&forwarded_for) {
+                headers.insert(X_FORWARDED_FOR, value);
+            }
+        }
+        headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static(self.proto));
+
+        // targets get the origin form of the uri
+        if let Some(path_and_query) = request.uri().path_and_query().cloned() {
+            *request.uri_mut() = Uri::from(path_and_query);
+        }
+        *request.version_mut() = Version::HTTP_11;
+
+        request
+    }
+
+    async fn forward_request(
+        &self, request: Request<Incoming>, targets: &[Candidate], upgrade: bool,
+    ) -> anyhow::Result<Response<Incoming>> {
+        // upgraded connections can't be reused, so they always get a fresh one
+        let reusable = match self.upstream.lock().await.take() {
+            Some((endpoint, sender))
+                if !upgrade && targets.iter().any(|target| target.endpoint == endpoint) && !sender.is_closed() =>
+            {
+                Some((endpoint, sender))
+            }
+            _ => None,
+        };
+
+        let (endpoint, mut sender) = match reusable {
+            Some(reusable) => reusable,
+            None => self.connect(targets).await?,
+        };
+
+        sender.ready().await?;
+        let sent = Instant::now();
+        let response = sender.send_request(request).await?;
+        self.forward.telemetry.record_first_byte(sent.elapsed());
+
+        if !upgrade {
+            *self.upstream.lock().await = Some((endpoint, sender));
+        }
+
+        Ok(response)
+    }
+
+    // Connects to the first of the targets that can be reached.
+    async fn connect(&self, targets: &[Candidate]) -> anyhow::Result<(Endpoint, SendRequest<Incoming>)> {
+        let (target, stream) = self.forward.connect_any(targets, self.source_addr, &self.span).await?;
+
+        let (sender, connection) = match hyper::client::conn::http1::handshake(TokioIo::new(stream)).await {
+            Ok(handshake) => handshake,
+            Err(e) => {
+                target.report_failure();
+                return Err(e.into());
+            }
+        };
+
+        // the connection counts against its target until it closes, or is closed along with a disabled
+        // target
+        let lease = target.clone().lease();
+        let span = self.span.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                result = connection.with_upgrades() => match result {
+                    Ok(_) => lease.report_success(),
+                    Err(e) => {
+                        debug!(parent: &span, "target connection closed with error: {}", e);
+                        lease.report_failure();
+                    }
+                },
+                _ = lease.closed() => {
+                    debug!(parent: &span, "target disabled, closing connection");
+                }
+            }
+        });
+
+        Ok((target.endpoint.clone(), sender))
+    }
+
+    async fn record(&self, label: String, status: StatusCode) {
+        let rule_id = self.forward.rule.id.as_uuid();
+        let stats_cache = &self.forward.stats_cache;
+
+        let mut stats = stats_cache.get(&rule_id).await.unwrap_or_default();
+        let route = stats.http.entry(label).or_insert_with(RuleStatsHttp::default);
+        route.requests += 1;
+        *route.statuses.entry(status.as_u16()).or_default() += 1;
+
+        stats_cache.insert(rule_id, stats).await;
+    }
+}
+
+async fn update_stats(forward: &TcpForward, connections: i64, bytes: u64) {
+    let rule_id = forward.rule.id.as_uuid();
+
+    let prev_stats = forward.stats_cache.get(&rule_id).await.unwrap_or_default();
+    forward
+        .stats_cache
+        .insert(
+            rule_id,
+            RuleStats {
+                connections: RuleStatsConnections {
+                    tcp: prev_stats.connections.tcp.saturating_add_signed(connections),
+                    ..prev_stats.connections
+                },
+                bandwidth: prev_stats.bandwidth + bytes,
+                ..prev_stats
+            },
+        )
+        .await;
+}
+
+fn is_upgrade(headers: &HeaderMap) -> bool {
+    headers.contains_key(UPGRADE)
+        && headers
+            .get_all(CONNECTION)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+}
+
+fn remove_hop_by_hop_headers(headers: &mut HeaderMap) {
+    // headers named by the connection header are hop-by-hop as well
+    let named = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::try_from(name.trim()).ok())
+        .collect::<Vec<_>>();
+
+    for name in named {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP_HEADERS {
+        headers.remove(*name);
+    }
+}
+
+fn strip_port(host: &str) -> &str {
+    match host.strip_prefix('[') {
+        // IPv6 literal
+        Some(rest) => rest.split(']').next().unwrap_or(rest),
+        None => host.split(':').next().unwrap_or(host),
+    }
+}
@@ -1,4 +1,21 @@
+pub mod balancer;
+pub mod capture;
+mod history;
+mod http;
 pub mod manager;
+mod mirror;
+mod proxy;
+mod routing;
+mod socks;
+mod stream;
 mod tcp;
+pub mod telemetry;
+mod tls;
+mod transparent;
+mod tunnel;
 mod udp;
+mod udp_over_tcp;
+#[cfg(unix)]
+mod unix;
+mod upstream;
 mod utils;
@@ -1,21 +1,44 @@
-use std::{collections::HashMap, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::anyhow;
+use bytes::Bytes;
 use moka::future::Cache;
 use pedicab_cli::AgentConfig;
 use pedicab_db::{
     dal::DataAccessLayer,
-    data::rule::{RuleProtocol, RuleStats, RuleStatus},
+    data::rule::{RuleProtocol, RuleStats, RuleStatsQuota, RuleStatus},
     model::rule::Rule,
 };
 use tokio::{sync::RwLock, task::JoinHandle, time};
 use tracing::{debug, error, info, info_span, trace, warn};
 use uuid::Uuid;
 
-use crate::{tcp::start_tcp_forward, udp::start_udp_forward, utils};
+use crate::{
+    balancer::{Balancers, TargetStatus},
+    capture::{self, CaptureParams, CaptureStatus, Captures},
+    history::HistoryRecorder,
+    tcp::start_tcp_forward,
+    telemetry::{Telemetry, TopTalkers},
+    tunnel::Peers,
+    udp::start_udp_forward,
+    utils,
+};
 
 pub type StatsCache = Cache<Uuid, RuleStats, ahash::RandomState>;
 
+// Registries shared by the forwards of all rules, each forward takes the part of its own rule.
+#[derive(Clone, Default)]
+pub struct Services {
+    pub captures: Captures,
+    pub peers: Peers,
+    pub balancers: Balancers,
+    pub telemetry: Telemetry,
+}
+
 #[derive(Clone)]
 pub struct ForwardManager {
     dal: DataAccessLayer,
@@ -23,6 +46,9 @@ pub struct ForwardManager {
     stats_cache: StatsCache,
     rules: Arc<RwLock<Vec<(Uuid, u64)>>>, // [1] is rule digest
     tasks: Arc<RwLock<HashMap<Uuid, JoinHandle<()>>>>,
+    services: Services,
+    // byte quota usage, kept out of the cached stats the connections of the rules keep rewriting
+    quotas: Arc<Mutex<HashMap<Uuid, RuleStatsQuota>>>,
 }
 
 impl ForwardManager {
@@ -35,6 +61,8 @@ impl ForwardManager {
                 .build_with_hasher(ahash::RandomState::default()),
             rules: Arc::new(RwLock::new(Vec::new())),
             tasks: Arc::new(RwLock::new(HashMap::new())),
+            services: Services::default(),
+            quotas: Arc::new(Mutex::new(HashMap::new())),
         };
 
         info!("forward manager initiated");
@@ -47,9 +75,7 @@ impl ForwardManager {
             }
         }
 
-        let _ = manager.load_rules().await;
-
-        // load persistent stats from db
+        // load persistent stats from db, before the rules start and their quotas are checked
         {
             let persistent_stats = manager
                 .dal
@@ -77,30 +103,93 @@ impl ForwardManager {
                         },
                     )
                     .await;
+
+                // usage is counted from the loaded bandwidth on
+                manager.quotas.lock().unwrap().insert(
+                    key,
+                    RuleStatsQuota {
+                        base: value.bandwidth,
+                        ..value.quota
+                    },
+                );
             }
         }
 
+        let _ = manager.load_rules().await;
+
         manager
     }
 
     async fn load_rules(&self) {
         let span = info_span!("load_rules");
 
-        let db_rules = self.dal.rule.find_all().await.unwrap_or_else(|e| {
+        let mut db_rules = self.dal.rule.find_all().await.unwrap_or_else(|e| {
             error!(parent: &span, "error occurred loading rules: {}", e);
             Vec::new()
         });
 
         let mut current_rules = self.rules.write().await;
 
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+
+        // enabled rules that stay stopped for now, outside of their schedule or over their byte quota
+        let mut held = Vec::new();
+        for rule in db_rules.iter_mut().filter(|rule| rule.enabled) {
+            let rule_id = rule.id.as_uuid();
+            let running = current_rules.iter().any(|(id, _)| *id == rule_id);
+
+            if rule.config.expires_at.is_some_and(|expires_at| expires_at <= now) {
+                info!(parent: &span, "rule {} expired, disabling it", rule_id);
+                match self.dal.rule.disable(rule_id).await {
+                    Ok(_) => rule.enabled = false,
+                    Err(e) => error!(parent: &span, "error occurred disabling rule {}: {}", rule_id, e),
+                }
+                continue;
+            }
+
+            let exhausted = self.check_quota(rule, now).await;
+            let scheduled = rule
+                .config
+                .schedule
+                .as_ref()
+                .is_none_or(|schedule| schedule.is_active(now));
+            if !exhausted && scheduled {
+                continue;
+            }
+
+            held.push(rule_id);
+
+            if running && exhausted {
+                info!(parent: &span, "rule {} used up its quota, stopping it until the next period", rule_id);
+            } else if running {
+                info!(parent: &span, "rule {} is outside of its schedule, stopping it", rule_id);
+            } else if rule.status == RuleStatus::Running {
+                // rules held at startup were never started
+                let _ = self.dal.rule.update_status(rule_id, RuleStatus::Stopped).await;
+            }
+        }
+
         // stop disabled rules
         for (rule_id, _) in current_rules.iter() {
-            if !db_rules.iter().any(|r| &r.id.as_uuid() == rule_id && r.enabled) {
-                let _ = self.stop_rule(*rule_id).await;
+            let enabled = db_rules.iter().any(|r| &r.id.as_uuid() == rule_id && r.enabled);
+            if !enabled || held.contains(rule_id) {
+                let _ = self.abort_rule(*rule_id).await;
+                self.services.balancers.remove(*rule_id);
+            }
+
+            // held rules keep their clients, to tell who used up the quota
+            if !enabled {
+                self.services.telemetry.remove(*rule_id);
+                self.services.captures.remove(*rule_id);
             }
         }
 
-        for rule in db_rules.iter().filter(|rule| rule.status != RuleStatus::Error) {
+        for rule in db_rules
+            .iter()
+            .filter(|rule| rule.status != RuleStatus::Error && !held.contains(&rule.id.as_uuid()))
+        {
             // start enabled rules
             if rule.enabled && !current_rules.iter().any(|(rule_id, _)| *rule_id == rule.id.as_uuid()) {
                 let _ = self.start_rule(rule.id.as_uuid()).await;
@@ -112,14 +201,14 @@ impl ForwardManager {
                 .find(|(rule_id, _)| *rule_id == rule.id.as_uuid())
                 && *rule_digest != rule.digest_config()
             {
-                let _ = self.stop_rule(*rule_id).await;
+                let _ = self.abort_rule(*rule_id).await;
                 let _ = self.start_rule(*rule_id).await;
             }
         }
 
         let wanted_rules = db_rules
             .into_iter()
-            .filter(|rule| rule.enabled && rule.status != RuleStatus::Error)
+            .filter(|rule| rule.enabled && rule.status != RuleStatus::Error && !held.contains(&rule.id.as_uuid()))
             .map(|rule| (rule.id.as_uuid(), rule.digest_config()))
             .collect::<Vec<(_, _)>>();
 
@@ -135,6 +224,52 @@ impl ForwardManager {
         trace!(parent: &span, size = wanted_rules.len(), rules, "rules loaded");
     }
 
+    // Brings the quota usage of a rule up to date with the bytes it moved since the last check,
+    // starting over when a new quota period began, and tells whether the rule used up its quota.
+    async fn check_quota(&self, rule: &Rule, now: u64) -> bool {
+        let Some(quota) = &rule.config.quota else {
+            return false;
+        };
+
+        let id = rule.id.as_uuid();
+        let bandwidth = self.stats_cache.get(&id).await.map_or(0, |stats| stats.bandwidth);
+        let (period_start, period_end) = quota.period.bounds(now);
+
+        let mut quotas = self.quotas.lock().unwrap();
+        let usage = quotas.entry(id).or_insert_with(|| RuleStatsQuota {
+            base: bandwidth,
+            ..Default::default()
+        });
+
+        let moved = match bandwidth >= usage.base {
+            true => bandwidth - usage.base,
+            false => bandwidth,
+        };
+        *usage = RuleStatsQuota {
+            used: match usage.period_start == period_start {
+                true => usage.used + moved,
+                false => 0,
+            },
+            base: bandwidth,
+            period_start,
+            period_end,
+        };
+
+        usage.used >= quota.bytes
+    }
+
+    // Resetting the stats starts the bandwidth of a rule over, its quota usage carries on.
+    fn rebase_quota(&self, id: Uuid, bandwidth: u64) {
+        if let Some(usage) = self.quotas.lock().unwrap().get_mut(&id) {
+            usage.used += bandwidth.saturating_sub(usage.base);
+            usage.base = 0;
+        }
+    }
+
+    fn quota(&self, id: Uuid) -> RuleStatsQuota {
+        self.quotas.lock().unwrap().get(&id).cloned().unwrap_or_default()
+    }
+
     pub async fn start_polling(&self) -> anyhow::Result<()> {
         let interval_duration = if cfg!(debug_assertions) {
             Duration::from_secs(5)
@@ -143,6 +278,9 @@ impl ForwardManager {
         };
         let mut interval = time::interval(interval_duration);
 
+        let manager = self.clone();
+        tokio::spawn(async move { manager.record_history().await });
+
         loop {
             interval.tick().await;
             self.flush_stats().await;
@@ -150,6 +288,16 @@ impl ForwardManager {
         }
     }
 
+    // Samples the running rules and the host into their history every second.
+    async fn record_history(&self) {
+        let mut recorder = HistoryRecorder::new(self.dal.clone());
+        let mut interval = time::interval(Duration::from_secs(1));
+
+        loop {
+            interval.tick().await;
+            recorder.sample(self.running_stats().await).await;
+        }
+    }
 
     pub async fn get_rules(&self) -> Vec<Rule> {
         let lock = self.rules.read().await;
@@ -180,12 +328,17 @@ impl ForwardManager {
         let mut tasks = self.tasks.write().await;
 
         match rule.protocol {
-            RuleProtocol::Tcp => {
+            RuleProtocol::Tcp
+            | RuleProtocol::Http
+            | RuleProtocol::Proxy
+            | RuleProtocol::Tunnel
+            | RuleProtocol::Reverse => {
                 let task = tokio::spawn(start_tcp_forward(
                     rule.clone(),
                     self.config.clone(),
                     self.dal.clone(),
                     self.stats_cache.clone(),
+                    self.services.clone(),
                 ));
                 tasks.insert(rule.id.into(), task);
             }
@@ -195,6 +348,7 @@ impl ForwardManager {
                     self.config.clone(),
                     self.dal.clone(),
                     self.stats_cache.clone(),
+                    self.services.clone(),
                 ));
                 tasks.insert(rule.id.into(), task);
             }
@@ -204,12 +358,14 @@ impl ForwardManager {
                     self.config.clone(),
                     self.dal.clone(),
                     self.stats_cache.clone(),
+                    self.services.clone(),
                 ));
                 let udp_task = tokio::spawn(start_udp_forward(
                     rule.clone(),
                     self.config.clone(),
                     self.dal.clone(),
                     self.stats_cache.clone(),
+                    self.services.clone(),
                 ));
 
                 let tcp_udp_task = tokio::spawn(async move {
@@ -231,16 +387,23 @@ impl ForwardManager {
     }
 
     pub async fn stop_rule(&self, id: Uuid) -> anyhow::Result<(), anyhow::Error> {
+        let mut current_rules = self.rules.write().await;
+
+        if let Some(i) = current_rules.iter().position(|(rule_id, _)| *rule_id == id) {
+            current_rules.remove(i);
+        }
+
+        self.abort_rule(id).await
+    }
+
+    // Stop the forwarding task of a rule, callers have to keep `rules` in sync themselves.
+    async fn abort_rule(&self, id: Uuid) -> anyhow::Result<(), anyhow::Error> {
         let span = info_span!("stop_rule", id = id.to_string());
 
-        let mut current_rules = self.rules.write().await;
         let task = self.tasks.write().await.remove(&id);
 
         // it has to be done anyway so it's fine
         let _ = self.dal.rule.update_status(id, RuleStatus::Stopped).await;
-        if let Some(i) = current_rules.iter().position(|(rule_id, _)| *rule_id == id) {
-            current_rules.remove(i);
-        }
 
         match task {
             Some(task) => {
@@ -262,16 +425,39 @@ impl ForwardManager {
         Ok(())
     }
 
+    // Latency percentiles come from the histograms of the rule telemetry and the quota usage from the
+    // quota checks, the cached stats don't hold them.
     pub async fn get_stat(&self, id: Uuid) -> Option<RuleStats> {
-        self.stats_cache.get(&id).await
+        self.stats_cache.get(&id).await.map(|stat| RuleStats {
+            quota: self.quota(id),
+            latency: self.services.telemetry.latency(id),
+            ..stat
+        })
     }
 
     pub async fn get_stats(&self) -> HashMap<Uuid, RuleStats> {
+        self.running_stats()
+            .await
+            .into_iter()
+            .map(|(id, stat)| {
+                (
+                    id,
+                    RuleStats {
+                        quota: self.quota(id),
+                        latency: self.services.telemetry.latency(id),
+                        ..stat
+                    },
+                )
+            })
+            .collect()
+    }
+
+    async fn running_stats(&self) -> HashMap<Uuid, RuleStats> {
         let current_rules = self.rules.read().await;
 
         self.stats_cache
             .iter()
-            .map(|(id, stat)| (*id, stat.clone()))
+            .map(|(id, stat)| (*id, stat))
             .filter(|(id, _)| current_rules.iter().any(|(rule_id, _)| *rule_id == *id))
             .collect::<HashMap<Uuid, RuleStats>>()
     }
@@ -284,7 +470,22 @@ impl ForwardManager {
             Vec::new()
         });
 
-        let stats = self.get_stats().await;
+        let mut stats = self.get_stats().await;
+
+        // rules held over their quota don't run, their usage has to be kept all the same
+        let held = self
+            .quotas
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|id| !stats.contains_key(id))
+            .copied()
+            .collect::<Vec<_>>();
+        for id in held {
+            if let Some(stat) = self.get_stat(id).await {
+                stats.insert(id, stat);
+            }
+        }
 
         trace!(parent: &span, "function entered");
 
@@ -305,8 +506,10 @@ impl ForwardManager {
     pub async fn reset_stat(&self, id: Uuid) -> anyhow::Result<()> {
         let span = info_span!("reset_stat", id = id.to_string());
 
-        if self.stats_cache.get(&id).await.is_some() {
+        if let Some(stat) = self.stats_cache.get(&id).await {
+            self.rebase_quota(id, stat.bandwidth);
             self.stats_cache.insert(id, RuleStats::default()).await;
+            self.services.telemetry.reset(id);
             debug!(parent: &span, "rule stats reset");
             Ok(())
         } else {
@@ -314,14 +517,90 @@ impl ForwardManager {
         }
     }
 
+    // Starts capturing the traffic of a running rule, replacing an earlier capture of it.
+    pub async fn start_capture(&self, id: Uuid, params: CaptureParams) -> anyhow::Result<CaptureStatus> {
+        let span = info_span!("start_capture", id = id.to_string());
+
+        if !self.rules.read().await.iter().any(|(rule_id, _)| *rule_id == id) {
+            return Err(anyhow!("rule is not running"));
+        }
+
+        let rule = self
+            .dal
+            .rule
+            .find_by_id(id)
+            .await?
+            .ok_or_else(|| anyhow!("rule not found"))?;
+        // http rules relay requests through hyper, there is no copy loop to capture from
+        if rule.protocol == RuleProtocol::Http {
+            return Err(anyhow!("captures are not supported by http rules"));
+        }
+
+        let duration = params.duration.map_or(capture::DEFAULT_DURATION, Duration::from_secs);
+        if duration.is_zero() || duration > capture::MAX_DURATION {
+            return Err(anyhow!(
+                "capture duration must be between 1 and {} seconds",
+                capture::MAX_DURATION.as_secs()
+            ));
+        }
+        let max_size = params.max_size.unwrap_or(capture::DEFAULT_SIZE);
+        if max_size == 0 || max_size > capture::MAX_SIZE {
+            return Err(anyhow!(
+                "capture size must be between 1 and {} bytes",
+                capture::MAX_SIZE
+            ));
+        }
+
+        let capture = self.services.captures.start(id, duration, max_size);
+
+        debug!(parent: &span, "capture started for {:?}", duration);
+
+        Ok(capture.status())
+    }
+
+    // The pcapng file of the capture of a rule, which may still be recording.
+    pub fn get_capture(&self, id: Uuid) -> Option<Vec<Bytes>> {
+        self.services.captures.get(id).map(|capture| capture.file())
+    }
+
+    pub fn remove_capture(&self, id: Uuid) -> anyhow::Result<()> {
+        match self.services.captures.discard(id) {
+            Some(_) => Ok(()),
+            None => Err(anyhow!("capture not found")),
+        }
+    }
+
+    // Connections, failures and ejections of the targets of a rule.
+    pub async fn get_targets(&self, id: Uuid) -> anyhow::Result<Vec<TargetStatus>> {
+        let rule = self
+            .dal
+            .rule
+            .find_by_id(id)
+            .await?
+            .ok_or_else(|| anyhow!("rule not found"))?;
+
+        Ok(self.services.balancers.status(&rule))
+    }
+
+    // The clients and targets of a rule that moved the most bytes.
+    pub async fn get_clients(&self, id: Uuid, top: usize) -> anyhow::Result<TopTalkers> {
+        if self.dal.rule.find_by_id(id).await?.is_none() {
+            return Err(anyhow!("rule not found"));
+        }
+
+        Ok(self.services.telemetry.top_talkers(id, top))
+    }
+
     pub async fn reset_stats(&self) -> usize {
         let span = info_span!("reset_stats");
 
         let mut count = 0;
         let stats = self.get_stats().await;
 
-        for (id, _) in stats {
+        for (id, stat) in stats {
+            self.rebase_quota(id, stat.bandwidth);
             self.stats_cache.insert(id, RuleStats::default()).await;
+            self.services.telemetry.reset(id);
             count += 1;
         }
 
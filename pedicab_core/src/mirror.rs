@@ -0,0 +1,102 @@
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use pedicab_db::data::rule::Endpoint;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::mpsc,
+};
+use tracing::{Span, debug};
+
+use crate::tcp::connect_target;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// chunks queued for a slow mirror before it is given up on
+const QUEUE_SIZE: usize = 64;
+
+// Copies the client to server bytes of a connection to a mirror target. The copy is best effort:
+// once the mirror falls behind or fails it is dropped for the rest of the connection, as a gap
+// would leave it with a corrupt stream anyway.
+pub struct StreamMirror {
+    sender: Option<mpsc::Sender<Bytes>>,
+    span: Span,
+}
+
+impl StreamMirror {
+    pub fn connect(target: Endpoint, buffer_size: usize, span: Span) -> Self {
+        let (sender, mut receiver) = mpsc::channel::<Bytes>(QUEUE_SIZE);
+
+        let task_span = span.clone();
+        tokio::spawn(async move {
+            let stream =
+                match tokio::time::timeout(CONNECT_TIMEOUT, connect_target(&target, None, buffer_size, &task_span))
+                    .await
+                {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(e)) => {
+                        debug!(parent: &task_span, "failed to connect to mirror {}: {}", target, e);
+                        return;
+                    }
+                    Err(_) => {
+                        debug!(parent: &task_span, "connecting to mirror {} timed out", target);
+                        return;
+                    }
+                };
+
+            let (mut reader, mut writer) = tokio::io::split(stream);
+
+            // replies of the mirror are read and thrown away so that it never stalls on a full socket
+            let discard = tokio::spawn(async move {
+                let mut buffer = vec![0u8; 16 * 1024];
+                while let Ok(n) = reader.read(&mut buffer).await {
+                    if n == 0 {
+                        break;
+                    }
+                }
+            });
+
+            while let Some(data) = receiver.recv().await {
+                if let Err(e) = writer.write_all(&data).await {
+                    debug!(parent: &task_span, "error writing to mirror {}: {}", target, e);
+                    break;
+                }
+            }
+
+            let _ = writer.shutdown().await;
+            discard.abort();
+        });
+
+        StreamMirror {
+            sender: Some(sender),
+            span,
+        }
+    }
+
+    pub fn send(&mut self, data: &[u8]) {
+        if let Some(sender) = &self.sender
+            && sender.try_send(Bytes::copy_from_slice(data)).is_err()
+        {
+            debug!(parent: &self.span, "mirror fell behind or failed, no longer mirroring this connection");
+            self.sender = None;
+        }
+    }
+}
+
+// Socket sending copies of the datagrams of a udp session to a mirror target. It is a plain
+// non-blocking socket, so sends never wait and a datagram it can't take right away is not mirrored.
+pub fn bind_datagram_mirror(target: SocketAddr) -> io::Result<UdpSocket> {
+    let bind_addr: SocketAddr = match target {
+        SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+        SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+    };
+
+    let socket = UdpSocket::bind(bind_addr)?;
+    socket.connect(target)?;
+    socket.set_nonblocking(true)?;
+
+    Ok(socket)
+}
@@ -0,0 +1,576 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use pedicab_db::data::rule::{Endpoint, IpNetwork, RuleProxy, RuleStats, RuleStatsConnections};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{UdpSocket, lookup_host},
+    task::JoinSet,
+};
+use tracing::{Span, debug, info_span, trace};
+
+use crate::{
+    balancer::Candidate,
+    socks::{self, Address},
+    stream::{BoxedStream, Rewind},
+    tcp::{self, TcpForward},
+};
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// largest request header accepted from a client for a CONNECT request
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+// how long a udp association keeps the address of a domain, how many it keeps and how many it looks
+// up at once
+const DESTINATION_TTL: Duration = Duration::from_secs(60);
+const MAX_DESTINATIONS: usize = 256;
+const MAX_LOOKUPS: usize = 16;
+
+#[derive(Clone, Copy)]
+enum Flavor {
+    Socks5,
+    Http,
+}
+
+enum Request {
+    Connect(Flavor, Address),
+    // SOCKS5 only
+    UdpAssociate,
+}
+
+// Serves a connection of a proxy rule. The client names its destination in a SOCKS5 or HTTP CONNECT
+// handshake, told apart by the first byte, and is then relayed like a tcp rule connection.
+pub async fn handle_connection(
+    client_stream: BoxedStream, peer_addr: Option<SocketAddr>, local_addr: Option<SocketAddr>, forward: Arc<TcpForward>,
+) {
+    let span = info_span!(
+        "handle_proxy_connection",
+        rule_id = forward.rule.id.as_uuid().to_string(),
+        client_addr = peer_addr.map(|addr| addr.to_string())
+    );
+
+    let settings = forward.rule.config.proxy.clone().unwrap_or_default();
+
+    let negotiated = tokio::time::timeout(HANDSHAKE_TIMEOUT, negotiate(client_stream, &settings))
+        .await
+        .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "proxy handshake timed out")));
+
+    let (mut client_stream, request) = match negotiated {
+        Ok(negotiated) => negotiated,
+        Err(e) => {
+            debug!(parent: &span, "proxy handshake with client failed: {}", e);
+            return;
+        }
+    };
+
+    let (flavor, address) = match request {
+        Request::Connect(flavor, address) => (flavor, address),
+        Request::UdpAssociate => {
+            if let Err(e) = udp_associate(client_stream, peer_addr, local_addr, &forward, &settings, &span).await {
+                debug!(parent: &span, "udp association failed: {}", e);
+            }
+            return;
+        }
+    };
+
+    let connected = async {
+        let target_addr = Endpoint::Inet(resolve(&address, |ip| proxy_allows(&settings.allow, ip)).await?);
+        let server_stream = forward.connect(&target_addr, None, &span).await?;
+        io::Result::Ok((target_addr, server_stream))
+    };
+
+    match connected.await {
+        Ok((target_addr, server_stream)) => {
+            trace!(parent: &span, "connected to {} for {}", target_addr, address);
+
+            if let Err(e) = reply_connected(&mut client_stream, flavor).await {
+                debug!(parent: &span, "failed to reply to client: {}", e);
+                return;
+            }
+
+            tcp::relay(
+                client_stream,
+                server_stream,
+                peer_addr,
+                Candidate::fixed(target_addr).lease(),
+                None,
+                &forward,
+                &span,
+            )
+            .await;
+        }
+        Err(e) => {
+            debug!(parent: &span, "failed to connect to {}: {}", address, e);
+
+            let _ = reply_failed(&mut client_stream, flavor, &e).await;
+        }
+    }
+}
+
+async fn negotiate(mut stream: BoxedStream, settings: &RuleProxy) -> io::Result<(BoxedStream, Request)> {
+    let version = stream.read_u8().await?;
+    if version == socks::VERSION {
+        let request = socks5_negotiate(&mut stream, settings).await?;
+        return Ok((stream, request));
+    }
+
+    let mut stream: BoxedStream = Box::new(Rewind::new(vec![version], stream));
+    let request = http_negotiate(&mut stream, settings).await?;
+    Ok((stream, request))
+}
+
+// Runs the SOCKS5 method selection, authentication and request, the version byte has been read
+// already.
+async fn socks5_negotiate(stream: &mut BoxedStream, settings: &RuleProxy) -> io::Result<Request> {
+    let mut methods = vec![0u8; stream.read_u8().await? as usize];
+    stream.read_exact(&mut methods).await?;
+
+    let credentials = settings.username.as_ref().zip(settings.password.as_ref());
+    let method = match credentials {
+        Some(_) => socks::AUTH_PASSWORD,
+        None => socks::AUTH_NONE,
+    };
+    if !methods.contains(&method) {
+        stream.write_all(&[socks::VERSION, socks::AUTH_UNACCEPTABLE]).await?;
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "client offered no acceptable authentication method",
+        ));
+    }
+    stream.write_all(&[socks::VERSION, method]).await?;
+
+    if let Some((username, password)) = credentials {
+        if stream.read_u8().await? != socks::AUTH_PASSWORD_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid socks5 authentication request",
+            ));
+        }
+
+        let mut client_username = vec![0u8; stream.read_u8().await? as usize];
+        stream.read_exact(&mut client_username).await?;
+        let mut client_password = vec![0u8; stream.read_u8().await? as usize];
+        stream.read_exact(&mut client_password).await?;
+
+        let granted = client_username == username.as_bytes() && client_password == password.as_bytes();
+        stream
+            .write_all(&[socks::AUTH_PASSWORD_VERSION, if granted { 0 } else { 1 }])
+            .await?;
+        if !granted {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "client sent invalid credentials",
+            ));
+        }
+    }
+
+    let mut request = [0u8; 4];
+    stream.read_exact(&mut request).await?;
+    if request[0] != socks::VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid socks5 request"));
+    }
+
+    let address = match socks::read_addr(stream, request[3]).await {
+        Ok(address) => address,
+        Err(e) => {
+            if e.kind() == io::ErrorKind::InvalidData {
+                socks5_reply(stream, socks::REPLY_ADDRESS_NOT_SUPPORTED, socks::unspecified_addr()).await?;
+            }
+            return Err(e);
+        }
+    };
+
+    match request[1] {
+        socks::CMD_CONNECT => Ok(Request::Connect(Flavor::Socks5, address)),
+        socks::CMD_UDP_ASSOCIATE => Ok(Request::UdpAssociate),
+        command => {
+            socks5_reply(stream, socks::REPLY_COMMAND_NOT_SUPPORTED, socks::unspecified_addr()).await?;
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("unsupported socks5 command {}", command),
+            ))
+        }
+    }
+}
+
+async fn socks5_reply(stream: &mut BoxedStream, reply: u8, addr: SocketAddr) -> io::Result<()> {
+    let mut buffer = vec![socks::VERSION, reply, 0];
+    socks::write_addr(&mut buffer, addr);
+    stream.write_all(&buffer).await
+}
+
+// Reads and checks an HTTP CONNECT request. Any other method is refused, plain HTTP requests are
+// not proxied.
+async fn http_negotiate(stream: &mut BoxedStream, settings: &RuleProxy) -> io::Result<Request> {
+    // read byte by byte, clients may send right after the request and their bytes must stay in the
+    // stream
+    let mut request = Vec::with_capacity(256);
+    while !request.ends_with(b"\r\n\r\n") {
+        if request.len() >= MAX_REQUEST_SIZE {
+            http_reply(stream, "431 Request Header Fields Too Large", "").await?;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request of client is too large",
+            ));
+        }
+        request.push(stream.read_u8().await?);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut lines = request.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+
+    if request_line.next() != Some("CONNECT") {
+        http_reply(stream, "405 Method Not Allowed", "Allow: CONNECT\r\n").await?;
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "client sent an http request other than CONNECT",
+        ));
+    }
+
+    if let (Some(username), Some(password)) = (&settings.username, &settings.password) {
+        let expected = STANDARD.encode(format!("{}:{}", username, password));
+        let authorized = lines
+            .filter_map(|line| line.split_once(':'))
+            .filter(|(name, _)| name.trim().eq_ignore_ascii_case("proxy-authorization"))
+            .any(|(_, value)| {
+                let mut value = value.split_whitespace();
+                value.next().is_some_and(|scheme| scheme.eq_ignore_ascii_case("basic"))
+                    && value.next() == Some(expected.as_str())
+            });
+
+        if !authorized {
+            http_reply(
+                stream,
+                "407 Proxy Authentication Required",
+                "Proxy-Authenticate: Basic realm=\"pedicab\"\r\n",
+            )
+            .await?;
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "client sent invalid credentials",
+            ));
+        }
+    }
+
+    match request_line.next().and_then(parse_authority) {
+        Some(address) => Ok(Request::Connect(Flavor::Http, address)),
+        None => {
+            http_reply(stream, "400 Bad Request", "").await?;
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid CONNECT request target",
+            ))
+        }
+    }
+}
+
+// Parses the `host:port` target of a CONNECT request, IPv6 addresses are written in brackets.
+fn parse_authority(authority: &str) -> Option<Address> {
+    if let Ok(addr) = authority.parse::<SocketAddr>() {
+        return Some(Address::Ip(addr));
+    }
+
+    let (host, port) = authority.rsplit_once(':')?;
+    if host.is_empty() || host.contains(['[', ']']) {
+        return None;
+    }
+
+    Some(Address::Domain(host.to_owned(), port.parse().ok()?))
+}
+
+async fn http_reply(stream: &mut BoxedStream, status: &str, headers: &str) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\n{}Connection: close\r\nContent-Length: 0\r\n\r\n",
+        status, headers
+    );
+    stream.write_all(response.as_bytes()).await
+}
+
+async fn reply_connected(stream: &mut BoxedStream, flavor: Flavor) -> io::Result<()> {
+    match flavor {
+        // the bound address is of no use to clients of a tcp relay
+        Flavor::Socks5 => socks5_reply(stream, socks::REPLY_SUCCEEDED, socks::unspecified_addr()).await,
+        Flavor::Http => stream.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n").await,
+    }
+}
+
+async fn reply_failed(stream: &mut BoxedStream, flavor: Flavor, error: &io::Error) -> io::Result<()> {
+    match flavor {
+        Flavor::Socks5 => {
+            let reply = match error.kind() {
+                io::ErrorKind::PermissionDenied => socks::REPLY_NOT_ALLOWED,
+                io::ErrorKind::ConnectionRefused => socks::REPLY_CONNECTION_REFUSED,
+                io::ErrorKind::NetworkUnreachable => socks::REPLY_NETWORK_UNREACHABLE,
+                io::ErrorKind::HostUnreachable | io::ErrorKind::NotFound => socks::REPLY_HOST_UNREACHABLE,
+                _ => socks::REPLY_GENERAL_FAILURE,
+            };
+            socks5_reply(stream, reply, socks::unspecified_addr()).await
+        }
+        Flavor::Http => {
+            let status = match error.kind() {
+                io::ErrorKind::PermissionDenied => "403 Forbidden",
+                io::ErrorKind::TimedOut => "504 Gateway Timeout",
+                _ => "502 Bad Gateway",
+            };
+            http_reply(stream, status, "").await
+        }
+    }
+}
+
+// Whether an allow list lets an address through, an empty one lets anything through.
+pub fn allows(allow: &[IpNetwork], ip: IpAddr) -> bool {
+    allow.is_empty() || allow.iter().any(|network| network.contains(ip))
+}
+
+// Whether a proxy client may reach an address. Clients may be anyone, so without an allow list the
+// host itself and its link, cloud metadata services included, are still kept out of their reach.
+fn proxy_allows(allow: &[IpNetwork], ip: IpAddr) -> bool {
+    if !allow.is_empty() {
+        return allows(allow, ip);
+    }
+
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => !(ip.is_loopback() || ip.is_link_local() || ip.is_unspecified()),
+        IpAddr::V6(ip) => !(ip.is_loopback() || ip.is_unicast_link_local() || ip.is_unspecified()),
+    }
+}
+
+// Resolves the destination a client asked for to the first of its addresses that is allowed.
+pub async fn resolve(address: &Address, allowed: impl Fn(IpAddr) -> bool) -> io::Result<SocketAddr> {
+    let addrs = match address {
+        Address::Ip(addr) => vec![*addr],
+        Address::Domain(domain, port) => lookup_host((domain.as_str(), *port)).await?.collect(),
+    };
+
+    if addrs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no address found for {}", address),
+        ));
+    }
+
+    addrs.into_iter().find(|addr| allowed(addr.ip())).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("destination {} is not allowed", address),
+        )
+    })
+}
+
+// Relays the datagrams of a SOCKS5 UDP association until the client closes its control connection.
+async fn udp_associate(
+    mut control: BoxedStream, peer_addr: Option<SocketAddr>, local_addr: Option<SocketAddr>, forward: &TcpForward,
+    settings: &RuleProxy, span: &Span,
+) -> io::Result<()> {
+    // associations are not chained through upstream proxies
+    let (Some(peer_addr), Some(local_addr), None) = (peer_addr, local_addr, &forward.rule.config.upstream) else {
+        socks5_reply(
+            &mut control,
+            socks::REPLY_COMMAND_NOT_SUPPORTED,
+            socks::unspecified_addr(),
+        )
+        .await?;
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "udp associate is not available on this rule",
+        ));
+    };
+
+    // the client reaches the relay on the address it reached the rule on
+    let relay = match UdpSocket::bind(SocketAddr::new(local_addr.ip(), 0)).await {
+        Ok(relay) => relay,
+        Err(e) => {
+            socks5_reply(&mut control, socks::REPLY_GENERAL_FAILURE, socks::unspecified_addr()).await?;
+            return Err(e);
+        }
+    };
+    // a dual-stack socket reaches any destination, hosts without IPv6 are limited to IPv4 ones
+    let outbound = match UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await {
+        Ok(outbound) => outbound,
+        Err(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?,
+    };
+    let outbound_v6 = outbound.local_addr()?.is_ipv6();
+
+    socks5_reply(&mut control, socks::REPLY_SUCCEEDED, relay.local_addr()?).await?;
+
+    trace!(parent: span, "udp association relaying on {}", relay.local_addr()?);
+
+    let rule_id = forward.rule.id.as_uuid();
+    let stats_cache = &forward.stats_cache;
+
+    {
+        let prev_stats = stats_cache.get(&rule_id).await.unwrap_or_default();
+        stats_cache
+            .insert(
+                rule_id,
+                RuleStats {
+                    connections: RuleStatsConnections {
+                        udp: prev_stats.connections.udp + 1,
+                        ..prev_stats.connections
+                    },
+                    ..prev_stats
+                },
+            )
+            .await;
+    }
+
+    let transferred_bytes = Arc::new(AtomicU64::new(0));
+    let stats_updater = tcp::spawn_stats_updater(
+        rule_id,
+        transferred_bytes.clone(),
+        stats_cache.clone(),
+        Duration::from_millis(forward.config.stats_update_interval),
+    );
+
+    // datagrams of an association may go anywhere, so only the client is accounted
+    let meter = forward.telemetry.open_session(Some(peer_addr), None);
+
+    let control_closed = async {
+        let mut buffer = [0u8; 64];
+        while let Ok(n) = control.read(&mut buffer).await {
+            if n == 0 {
+                break;
+            }
+        }
+    };
+    tokio::pin!(control_closed);
+
+    // only the client holding the association may use the relay, its port is learnt from the first
+    // datagram
+    let mut client_addr: Option<SocketAddr> = None;
+    let mut client_buffer = vec![0u8; 65535];
+    let mut target_buffer = vec![0u8; 65535];
+
+    // domains are looked up off the relay loop and their addresses kept for a while, datagrams to a
+    // domain still being looked up are dropped like any the network loses
+    let allow: Arc<[IpNetwork]> = settings.allow.clone().into();
+    let mut destinations: HashMap<(String, u16), (SocketAddr, Instant)> = HashMap::new();
+    let mut pending = HashSet::new();
+    let mut lookups = JoinSet::new();
+
+    let send_to_target = async |client_addr: SocketAddr, target_addr: SocketAddr, payload: &[u8]| {
+        let send_addr = match target_addr.ip() {
+            IpAddr::V4(ip) if outbound_v6 => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), target_addr.port()),
+            _ => target_addr,
+        };
+        if outbound.send_to(payload, send_addr).await.is_ok() {
+            transferred_bytes.fetch_add(payload.len() as u64, Ordering::Relaxed);
+            meter.add(payload.len() as u64);
+
+            if let Some(capture) = forward.capture.active() {
+                capture.record_datagram(client_addr, target_addr, payload);
+            }
+        }
+    };
+
+    loop {
+        tokio::select! {
+            received = relay.recv_from(&mut client_buffer) => {
+                let Ok((size, addr)) = received else { break };
+                if addr.ip().to_canonical() != peer_addr.ip().to_canonical()
+                    || client_addr.is_some_and(|client_addr| client_addr != addr)
+                {
+                    continue;
+                }
+                client_addr = Some(addr);
+
+                let Some((address, payload)) = socks::decapsulate_datagram(&client_buffer[..size]) else {
+                    continue;
+                };
+                let resolved = match &address {
+                    Address::Ip(_) => resolve(&address, |ip| proxy_allows(&allow, ip)).await,
+                    Address::Domain(domain, port) => match destinations.get(&(domain.clone(), *port)) {
+                        Some((target_addr, resolved_at)) if resolved_at.elapsed() < DESTINATION_TTL => Ok(*target_addr),
+                        _ => {
+                            let destination = (domain.clone(), *port);
+                            if pending.len() < MAX_LOOKUPS && pending.insert(destination.clone()) {
+                                let allow = allow.clone();
+                                let payload = payload.to_vec();
+                                lookups.spawn(async move {
+                                    let resolved = resolve(&address, |ip| proxy_allows(&allow, ip)).await;
+                                    (destination, resolved, payload)
+                                });
+                            }
+                            continue;
+                        }
+                    },
+                };
+                match resolved {
+                    Ok(target_addr) => send_to_target(addr, target_addr, payload).await,
+                    Err(e) => trace!(parent: span, "dropping datagram to {}: {}", address, e),
+                }
+            }
+
+            Some(looked_up) = lookups.join_next(), if !lookups.is_empty() => {
+                let Ok((destination, resolved, payload)) = looked_up else { continue };
+                pending.remove(&destination);
+                let Some(client_addr) = client_addr else { continue };
+
+                match resolved {
+                    Ok(target_addr) => {
+                        if destinations.len() >= MAX_DESTINATIONS {
+                            destinations.retain(|_, (_, resolved_at)| resolved_at.elapsed() < DESTINATION_TTL);
+                        }
+                        if destinations.len() < MAX_DESTINATIONS {
+                            destinations.insert(destination, (target_addr, Instant::now()));
+                        }
+                        send_to_target(client_addr, target_addr, &payload).await;
+                    }
+                    Err(e) => trace!(parent: span, "dropping datagram to {}:{}: {}", destination.0, destination.1, e),
+                }
+            }
+
+            received = outbound.recv_from(&mut target_buffer) => {
+                let Ok((size, target_addr)) = received else { break };
+                let Some(client_addr) = client_addr else { continue };
+                let target_addr = SocketAddr::new(target_addr.ip().to_canonical(), target_addr.port());
+
+                let packet = socks::encapsulate_datagram(target_addr, &target_buffer[..size]);
+                if relay.send_to(&packet, client_addr).await.is_ok() {
+                    transferred_bytes.fetch_add(size as u64, Ordering::Relaxed);
+                    meter.add(size as u64);
+                    meter.answered();
+
+                    if let Some(capture) = forward.capture.active() {
+                        capture.record_datagram(target_addr, client_addr, &target_buffer[..size]);
+                    }
+                }
+            }
+
+            _ = &mut control_closed => break,
+        }
+    }
+
+    stats_updater.abort();
+
+    {
+        let prev_stats = stats_cache.get(&rule_id).await.unwrap_or_default();
+        stats_cache
+            .insert(
+                rule_id,
+                RuleStats {
+                    // bytes the updater did not get to fold in before it was stopped
+                    bandwidth: prev_stats.bandwidth + transferred_bytes.swap(0, Ordering::Relaxed),
+                    connections: RuleStatsConnections {
+                        udp: prev_stats.connections.udp - 1,
+                        ..prev_stats.connections
+                    },
+                    ..prev_stats
+                },
+            )
+            .await;
+    }
+
+    debug!(parent: span, "udp association closed");
+
+    Ok(())
+}
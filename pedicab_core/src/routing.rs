@@ -0,0 +1,296 @@
+use std::{io, time::Duration};
+
+use anyhow::Context;
+use pedicab_db::data::rule::{Endpoint, RuleAddr, RuleRouteMatcher, RuleRouting};
+use regex::bytes::Regex;
+use tokio::io::AsyncReadExt;
+
+use crate::stream::{BoxedStream, Rewind};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+// a ClientHello fits into a single TLS record
+const MAX_PEEK_SIZE: usize = 5 + (1 << 14);
+
+const HTTP_METHODS: &[&[u8]] = &[
+    b"GET ",
+    b"HEAD ",
+    b"POST ",
+    b"PUT ",
+    b"DELETE ",
+    b"CONNECT ",
+    b"OPTIONS ",
+    b"TRACE ",
+    b"PATCH ",
+    // HTTP/2 connection preface
+    b"PRI * HTTP/2.0",
+];
+
+// Picks the target of a connection from the first bytes the client sends.
+pub struct Router {
+    routes: Vec<(Matcher, RuleAddr)>,
+    reject_unmatched: bool,
+    timeout: Duration,
+}
+
+pub enum Route {
+    Matched(Endpoint),
+    // no route matched and the connection goes to the rule targets
+    Default,
+    // no route matched and the connection has to be closed
+    Rejected,
+}
+
+enum Matcher {
+    Sni(Vec<String>),
+    Ssh,
+    Tls,
+    Http,
+    Prefix(Vec<u8>),
+    Regex(Regex),
+}
+
+// ordered so that the best outcome over several candidates is the maximum
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum Matched {
+    No,
+    NeedMore,
+    Yes,
+}
+
+impl Router {
+    pub fn new(routing: &RuleRouting) -> anyhow::Result<Self> {
+        let routes = routing
+            .routes
+            .iter()
+            .map(|route| Ok((Matcher::new(&route.matcher)?, route.target.clone())))
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Router {
+            routes,
+            reject_unmatched: routing.reject_unmatched,
+            timeout: routing.timeout.map_or(DEFAULT_TIMEOUT, Duration::from_millis),
+        })
+    }
+
+    // Reads from the client until a decision can be made or the timeout passes. The returned stream
+    // replays everything that was read.
+    pub async fn route(&self, mut stream: BoxedStream, offset: usize) -> io::Result<(BoxedStream, Route)> {
+        let mut buffer = Vec::with_capacity(1024);
+
+        let target = tokio::time::timeout(self.timeout, async {
+            loop {
+                if let Some(target) = self.decide(&buffer, buffer.len() >= MAX_PEEK_SIZE) {
+                    return Ok(target);
+                }
+
+                let mut chunk = [0u8; 4096];
+                let n = stream
+                    .read(&mut chunk[..(MAX_PEEK_SIZE - buffer.len()).min(4096)])
+                    .await?;
+                if n == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "connection closed before routing",
+                    ));
+                }
+                buffer.extend_from_slice(&chunk[..n]);
+            }
+        })
+        .await;
+
+        let target = match target {
+            Ok(target) => target?,
+            // clients of server-first protocols send nothing, decide with what has been read
+            Err(_) => self.decide(&buffer, true).flatten(),
+        };
+
+        let route = match target {
+            Some(target) => Route::Matched(target.resolve(offset)),
+            None if self.reject_unmatched => Route::Rejected,
+            None => Route::Default,
+        };
+
+        Ok((Box::new(Rewind::new(buffer, stream)), route))
+    }
+
+    // Routes are checked in order, so a route waiting for more bytes holds back the ones after it.
+    // Returns `None` while more bytes are needed, unless `last` is set.
+    fn decide(&self, buffer: &[u8], last: bool) -> Option<Option<&RuleAddr>> {
+        for (matcher, target) in self.routes.iter() {
+            match matcher.matches(buffer) {
+                Matched::Yes => return Some(Some(target)),
+                Matched::NeedMore if !last => return None,
+                _ => {}
+            }
+        }
+
+        Some(None)
+    }
+}
+
+impl Matcher {
+    fn new(matcher: &RuleRouteMatcher) -> anyhow::Result<Self> {
+        Ok(match matcher {
+            RuleRouteMatcher::Sni(patterns) => {
+                Matcher::Sni(patterns.iter().map(|pattern| pattern.to_ascii_lowercase()).collect())
+            }
+            RuleRouteMatcher::Ssh => Matcher::Ssh,
+            RuleRouteMatcher::Tls => Matcher::Tls,
+            RuleRouteMatcher::Http => Matcher::Http,
+            RuleRouteMatcher::Prefix(prefix) => Matcher::Prefix(prefix.as_bytes().to_vec()),
+            RuleRouteMatcher::Regex(regex) => {
+                Matcher::Regex(Regex::new(regex).with_context(|| format!("invalid route regex {}", regex))?)
+            }
+        })
+    }
+
+    fn matches(&self, buffer: &[u8]) -> Matched {
+        match self {
+            Matcher::Sni(patterns) => match client_hello_server_name(buffer) {
+                None => Matched::NeedMore,
+                Some(Some(server_name)) => {
+                    let server_name = server_name.to_ascii_lowercase();
+                    let matched = patterns.iter().any(|pattern| match pattern.strip_prefix('*') {
+                        Some(suffix) => suffix.starts_with('.') && server_name.ends_with(suffix),
+                        None => server_name == *pattern,
+                    });
+                    if matched { Matched::Yes } else { Matched::No }
+                }
+                Some(None) => Matched::No,
+            },
+            Matcher::Ssh => starts_with(buffer, b"SSH-"),
+            // handshake record of TLS 1.x (or SSL 3.0)
+            Matcher::Tls => starts_with(buffer, &[0x16, 0x03]),
+            Matcher::Http => HTTP_METHODS
+                .iter()
+                .map(|method| starts_with(buffer, method))
+                .max()
+                .unwrap_or(Matched::No),
+            Matcher::Prefix(prefix) => starts_with(buffer, prefix),
+            // a regex can't tell whether more bytes could match, so it only looks at what has arrived
+            Matcher::Regex(_) if buffer.is_empty() => Matched::NeedMore,
+            Matcher::Regex(regex) => {
+                if regex.is_match(buffer) {
+                    Matched::Yes
+                } else {
+                    Matched::No
+                }
+            }
+        }
+    }
+}
+
+fn starts_with(buffer: &[u8], prefix: &[u8]) -> Matched {
+    if buffer.starts_with(prefix) {
+        Matched::Yes
+    } else if prefix.starts_with(buffer) {
+        Matched::NeedMore
+    } else {
+        Matched::No
+    }
+}
+
+// Returns `None` while more bytes are needed, and `Some(None)` if the bytes are not a TLS
+// ClientHello or carry no server name.
+fn client_hello_server_name(buffer: &[u8]) -> Option<Option<String>> {
+    match buffer.first() {
+        None => return None,
+        // handshake record
+        Some(0x16) => {}
+        Some(_) => return Some(None),
+    }
+
+    let mut record = Reader::new(buffer);
+    let header = record.bytes(5)?;
+    let length = u16::from_be_bytes([header[3], header[4]]) as usize;
+    let body = record.bytes(length)?;
+
+    Some(parse_client_hello(body))
+}
+
+fn parse_client_hello(body: &[u8]) -> Option<String> {
+    let mut reader = Reader::new(body);
+
+    // handshake type client_hello
+    if reader.u8()? != 0x01 {
+        return None;
+    }
+    let length = reader.u24()?;
+    let mut hello = Reader::new(reader.bytes(length)?);
+
+    // legacy version and random
+    hello.bytes(2 + 32)?;
+    let session_id = hello.u8()? as usize;
+    hello.bytes(session_id)?;
+    let cipher_suites = hello.u16()? as usize;
+    hello.bytes(cipher_suites)?;
+    let compression_methods = hello.u8()? as usize;
+    hello.bytes(compression_methods)?;
+
+    let extensions = hello.u16()? as usize;
+    let mut extensions = Reader::new(hello.bytes(extensions)?);
+
+    while !extensions.is_empty() {
+        let extension_type = extensions.u16()?;
+        let length = extensions.u16()? as usize;
+        let data = extensions.bytes(length)?;
+
+        // server_name
+        if extension_type == 0x0000 {
+            let mut list = Reader::new(data);
+            let length = list.u16()? as usize;
+            let mut names = Reader::new(list.bytes(length)?);
+
+            while !names.is_empty() {
+                let name_type = names.u8()?;
+                let length = names.u16()? as usize;
+                let name = names.bytes(length)?;
+
+                // host_name
+                if name_type == 0x00 {
+                    return String::from_utf8(name.to_vec()).ok();
+                }
+            }
+
+            return None;
+        }
+    }
+
+    None
+}
+
+struct Reader<'a> {
+    buffer: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(buffer: &'a [u8]) -> Self {
+        Reader { buffer }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.buffer.len() < n {
+            return None;
+        }
+        let (bytes, rest) = self.buffer.split_at(n);
+        self.buffer = rest;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.bytes(3)
+            .map(|bytes| ((bytes[0] as usize) << 16) | ((bytes[1] as usize) << 8) | bytes[2] as usize)
+    }
+}
@@ -0,0 +1,160 @@
+use std::{
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// SOCKS5 wire format (RFC 1928, RFC 1929), shared by upstream proxies and proxy rules.
+
+pub const VERSION: u8 = 0x05;
+
+pub const AUTH_NONE: u8 = 0x00;
+pub const AUTH_PASSWORD: u8 = 0x02;
+pub const AUTH_UNACCEPTABLE: u8 = 0xFF;
+pub const AUTH_PASSWORD_VERSION: u8 = 0x01;
+
+pub const CMD_CONNECT: u8 = 0x01;
+pub const CMD_UDP_ASSOCIATE: u8 = 0x03;
+
+pub const ATYP_IPV4: u8 = 0x01;
+pub const ATYP_DOMAIN: u8 = 0x03;
+pub const ATYP_IPV6: u8 = 0x04;
+
+pub const REPLY_SUCCEEDED: u8 = 0x00;
+pub const REPLY_GENERAL_FAILURE: u8 = 0x01;
+pub const REPLY_NOT_ALLOWED: u8 = 0x02;
+pub const REPLY_NETWORK_UNREACHABLE: u8 = 0x03;
+pub const REPLY_HOST_UNREACHABLE: u8 = 0x04;
+pub const REPLY_CONNECTION_REFUSED: u8 = 0x05;
+pub const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+pub const REPLY_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+// An address as carried in requests, replies and datagram headers.
+#[derive(Clone, Debug)]
+pub enum Address {
+    Ip(SocketAddr),
+    Domain(String, u16),
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Ip(addr) => write!(f, "{}", addr),
+            Address::Domain(domain, port) => write!(f, "{}:{}", domain, port),
+        }
+    }
+}
+
+pub fn reply_message(reply: u8) -> &'static str {
+    match reply {
+        REPLY_SUCCEEDED => "succeeded",
+        REPLY_NOT_ALLOWED => "connection not allowed by ruleset",
+        REPLY_NETWORK_UNREACHABLE => "network unreachable",
+        REPLY_HOST_UNREACHABLE => "host unreachable",
+        REPLY_CONNECTION_REFUSED => "connection refused",
+        0x06 => "ttl expired",
+        REPLY_COMMAND_NOT_SUPPORTED => "command not supported",
+        REPLY_ADDRESS_NOT_SUPPORTED => "address type not supported",
+        _ => "general failure",
+    }
+}
+
+pub fn write_addr(buffer: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buffer.push(ATYP_IPV4);
+            buffer.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buffer.push(ATYP_IPV6);
+            buffer.extend_from_slice(&ip.octets());
+        }
+    }
+    buffer.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+// Reads the address following an address type byte.
+pub async fn read_addr<R>(reader: &mut R, atyp: u8) -> io::Result<Address>
+where
+    R: AsyncRead + Unpin,
+{
+    let address = match atyp {
+        ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            reader.read_exact(&mut octets).await?;
+            Address::Ip(SocketAddr::new(IpAddr::from(octets), reader.read_u16().await?))
+        }
+        ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            reader.read_exact(&mut octets).await?;
+            Address::Ip(SocketAddr::new(IpAddr::from(octets), reader.read_u16().await?))
+        }
+        ATYP_DOMAIN => {
+            let mut domain = vec![0u8; reader.read_u8().await? as usize];
+            reader.read_exact(&mut domain).await?;
+            let domain = String::from_utf8(domain)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid socks5 domain name"))?;
+            Address::Domain(domain, reader.read_u16().await?)
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unknown socks5 address type",
+            ));
+        }
+    };
+
+    Ok(address)
+}
+
+// Prepends the UDP request header carrying the address of the remote end to a datagram.
+pub fn encapsulate_datagram(addr: SocketAddr, data: &[u8]) -> Vec<u8> {
+    // reserved and fragment number
+    let mut packet = vec![0, 0, 0];
+    write_addr(&mut packet, addr);
+    packet.extend_from_slice(data);
+    packet
+}
+
+// Splits a datagram into the address of its UDP request header and the payload. Fragments are not
+// supported and dropped, like most implementations do.
+pub fn decapsulate_datagram(packet: &[u8]) -> Option<(Address, &[u8])> {
+    if packet.len() < 4 || packet[2] != 0 {
+        return None;
+    }
+
+    let (address, payload_at) = match packet[3] {
+        ATYP_IPV4 => {
+            let octets: [u8; 4] = packet.get(4..8)?.try_into().ok()?;
+            (
+                Address::Ip(SocketAddr::new(IpAddr::from(octets), port_at(packet, 8)?)),
+                10,
+            )
+        }
+        ATYP_IPV6 => {
+            let octets: [u8; 16] = packet.get(4..20)?.try_into().ok()?;
+            (
+                Address::Ip(SocketAddr::new(IpAddr::from(octets), port_at(packet, 20)?)),
+                22,
+            )
+        }
+        ATYP_DOMAIN => {
+            let length = *packet.get(4)? as usize;
+            let domain = String::from_utf8(packet.get(5..5 + length)?.to_vec()).ok()?;
+            (Address::Domain(domain, port_at(packet, 5 + length)?), 7 + length)
+        }
+        _ => return None,
+    };
+
+    Some((address, packet.get(payload_at..)?))
+}
+
+fn port_at(packet: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(packet.get(at..at + 2)?.try_into().ok()?))
+}
+
+// The address to put in replies where none is known, clients don't look at it.
+pub fn unspecified_addr() -> SocketAddr {
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)
+}
@@ -0,0 +1,123 @@
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::telemetry::TrafficMeter;
+
+// Relayed streams are type-erased so that TCP, unix socket and wrapped streams share the same
+// relay.
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T> AsyncStream for T where T: AsyncRead + AsyncWrite + Unpin + Send {}
+
+pub type BoxedStream = Box<dyn AsyncStream>;
+
+// Replays bytes already read from a stream before reading from it again, used after peeking at the
+// start of a connection.
+pub struct Rewind {
+    prefix: Vec<u8>,
+    position: usize,
+    inner: BoxedStream,
+}
+
+impl Rewind {
+    pub fn new(prefix: Vec<u8>, inner: BoxedStream) -> Self {
+        Rewind {
+            prefix,
+            position: 0,
+            inner,
+        }
+    }
+}
+
+impl AsyncRead for Rewind {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if this.position < this.prefix.len() {
+            let remaining = &this.prefix[this.position..];
+            let n = remaining.len().min(buf.remaining());
+            buf.put_slice(&remaining[..n]);
+            this.position += n;
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Rewind {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+// Counts the bytes read from and written to a stream, for relays that don't copy the bytes
+// themselves.
+pub struct Metered {
+    inner: BoxedStream,
+    transferred_bytes: Arc<AtomicU64>,
+    meter: TrafficMeter,
+}
+
+impl Metered {
+    pub fn new(inner: BoxedStream, transferred_bytes: Arc<AtomicU64>, meter: TrafficMeter) -> Self {
+        Metered {
+            inner,
+            transferred_bytes,
+            meter,
+        }
+    }
+}
+
+impl AsyncRead for Metered {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        let n = (buf.filled().len() - filled) as u64;
+        this.transferred_bytes.fetch_add(n, Ordering::Relaxed);
+        this.meter.add(n);
+
+        result
+    }
+}
+
+impl AsyncWrite for Metered {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            this.transferred_bytes.fetch_add(n as u64, Ordering::Relaxed);
+            this.meter.add(n as u64);
+        }
+
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
@@ -1,55 +1,297 @@
+#[cfg(unix)]
+use std::path::Path;
 use std::{
     cmp::min,
+    fmt, io,
+    net::SocketAddr,
     sync::{
         Arc,
-        atomic::{AtomicU64, Ordering},
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use pedicab_cli::AgentConfig;
 use pedicab_db::{
     dal::DataAccessLayer,
-    data::rule::{RuleStats, RuleStatsConnections, RuleStatus},
+    data::rule::{
+        Endpoint, RuleOverflow, RuleProtocol, RuleStats, RuleStatsConnections, RuleStatsOverflow, RuleUdpOverTcp,
+    },
     model::rule::Rule,
 };
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::{TcpListener, TcpStream},
-    sync::Mutex,
+    sync::{Mutex, OwnedSemaphorePermit, Semaphore, watch},
+    task::{JoinHandle, JoinSet},
+};
+use tracing::{Span, debug, error, info_span, trace, warn};
+
+#[cfg(unix)]
+use crate::unix::{UnixSocketGuard, bind_unix_listener};
+use crate::{
+    balancer::{Balancer, Candidate, TargetLease},
+    capture::{Direction, RuleCapture, TcpFlow},
+    http,
+    manager::{Services, StatsCache},
+    mirror::StreamMirror,
+    proxy,
+    routing::{Route, Router},
+    stream::BoxedStream,
+    telemetry::RuleTelemetry,
+    tls::TlsLayer,
+    transparent,
+    tunnel::{self, PeerLink, Peers},
+    udp_over_tcp, upstream, utils,
 };
-use tracing::{debug, error, info_span, trace, warn};
 
-use crate::manager::StatsCache;
+// State shared by the accept loops and connections of a rule.
+pub struct TcpForward {
+    pub rule: Rule,
+    pub config: AgentConfig,
+    pub stats_cache: StatsCache,
+    connections_semaphore: Option<Arc<Semaphore>>,
+    // connections waiting for a slot under the connection limit
+    queued: AtomicUsize,
+    pub tls: Option<TlsLayer>,
+    router: Option<Router>,
+    pub capture: RuleCapture,
+    pub telemetry: Arc<RuleTelemetry>,
+    peer: Option<Arc<PeerLink>>,
+    pub peers: Peers,
+    balancer: Balancer,
+    // changes once the rule stops, its sender lives as long as the forward task of the rule
+    stopped: watch::Receiver<()>,
+}
+
+// How a connection got past the connection limit.
+enum Admission {
+    // a slot was free, or there is no limit
+    Admitted(Option<OwnedSemaphorePermit>),
+    // waits for a slot at most the timeout
+    Queued(Arc<Semaphore>, Duration),
+    // goes to the overflow target without a slot
+    Redirected(Endpoint),
+}
+
+impl TcpForward {
+    // Resolves once the rule is stopped, for work that outlives the connection it came from.
+    pub async fn stopped(&self) {
+        let mut stopped = self.stopped.clone();
+        while stopped.changed().await.is_ok() {}
+    }
+
+    // Admits a connection above the connection limit as the overflow policy says, `None` rejects it.
+    fn overflow(&self, semaphore: &Arc<Semaphore>, offset: usize) -> Option<Admission> {
+        match self.rule.config.overflow.as_ref()? {
+            RuleOverflow::Reject => None,
+            RuleOverflow::Queue(queue) => {
+                if self.queued.fetch_add(1, Ordering::Relaxed) >= queue.size as usize {
+                    self.queued.fetch_sub(1, Ordering::Relaxed);
+                    return None;
+                }
+                Some(Admission::Queued(
+                    semaphore.clone(),
+                    Duration::from_millis(queue.timeout),
+                ))
+            }
+            RuleOverflow::Redirect(target) => Some(Admission::Redirected(target.resolve(offset))),
+        }
+    }
+
+    // Waits in the queue for a slot under the connection limit, `None` once the wait times out.
+    async fn wait_in_queue(&self, semaphore: Arc<Semaphore>, timeout: Duration) -> Option<OwnedSemaphorePermit> {
+        self.update_overflow_stats(|_| {}).await;
+
+        let started = Instant::now();
+        let permit = tokio::time::timeout(timeout, semaphore.acquire_owned()).await;
+        let waited = started.elapsed().as_millis() as u64;
 
-pub async fn start_tcp_forward(rule: Rule, config: AgentConfig, dal: DataAccessLayer, stats_cache: StatsCache) {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+
+        match permit {
+            Ok(Ok(permit)) => {
+                self.update_overflow_stats(|overflow| {
+                    overflow.dequeued += 1;
+                    overflow.wait_time += waited;
+                    overflow.max_wait_time = overflow.max_wait_time.max(waited);
+                })
+                .await;
+                Some(permit)
+            }
+            _ => {
+                self.update_overflow_stats(|overflow| overflow.timed_out += 1).await;
+                None
+            }
+        }
+    }
+
+    // Applies a change to the overflow stats of the rule, along with the current queue length.
+    async fn update_overflow_stats(&self, update: impl FnOnce(&mut RuleStatsOverflow)) {
+        let rule_id = self.rule.id.as_uuid();
+        let prev_stats = self.stats_cache.get(&rule_id).await.unwrap_or_default();
+
+        let mut overflow = prev_stats.overflow.clone();
+        update(&mut overflow);
+        overflow.queued = self.queued.load(Ordering::Relaxed) as u64;
+
+        self.stats_cache
+            .insert(rule_id, RuleStats { overflow, ..prev_stats })
+            .await;
+    }
+
+    // Connects to a target, through the peer tunnel or the upstream proxy and in TLS if configured.
+    pub async fn connect(
+        &self, target: &Endpoint, source_addr: Option<SocketAddr>, span: &Span,
+    ) -> io::Result<BoxedStream> {
+        let buffer_size = (self.config.tcp_buffer_size as usize) * 1024;
+        let started = Instant::now();
+
+        let stream = match (&self.peer, &self.rule.config.upstream) {
+            (Some(peer), _) => peer.open_stream(target).await?,
+            (None, Some(upstream)) => upstream::connect(upstream, target, buffer_size, span).await?,
+            (None, None) => connect_target(target, source_addr, buffer_size, span).await?,
+        };
+
+        let stream = match &self.tls {
+            Some(tls) => tls.connect(stream, target).await?,
+            None => stream,
+        };
+
+        self.telemetry.record_connect(started.elapsed());
+        Ok(stream)
+    }
+
+    // Connects to the first of the targets that can be reached. Without retry settings every target is
+    // tried once, with them the attempts cycle through the targets, or stay on the first one, until the
+    // retries or the deadline run out.
+    pub async fn connect_any<'a>(
+        &self, targets: &'a [Candidate], source_addr: Option<SocketAddr>, span: &Span,
+    ) -> io::Result<(&'a Candidate, BoxedStream)> {
+        let no_target = || io::Error::new(io::ErrorKind::NotConnected, "no target takes new connections");
+
+        let Some(retries) = &self.rule.config.retries else {
+            let mut last_error = None;
+            for target in targets {
+                match self.connect(&target.endpoint, source_addr, span).await {
+                    Ok(stream) => return Ok((target, stream)),
+                    Err(e) => {
+                        warn!(parent: span, "failed to connect to {}: {}", target.endpoint, e);
+                        target.report_failure();
+                        last_error = Some(e);
+                    }
+                }
+            }
+            return Err(last_error.unwrap_or_else(no_target));
+        };
+
+        if targets.is_empty() {
+            return Err(no_target());
+        }
+
+        let deadline = tokio::time::Instant::now() + Duration::from_millis(retries.deadline);
+        let mut backoff = Duration::from_millis(retries.backoff);
+        let mut last_error = None;
+
+        for attempt in 0..=retries.attempts as usize {
+            let target = match retries.same_target {
+                true => &targets[0],
+                false => &targets[attempt % targets.len()],
+            };
+
+            if attempt > 0 {
+                tokio::time::sleep_until(min(tokio::time::Instant::now() + backoff, deadline)).await;
+                backoff = backoff.saturating_mul(2);
+
+                let rule_id = self.rule.id.as_uuid();
+                let prev_stats = self.stats_cache.get(&rule_id).await.unwrap_or_default();
+                self.stats_cache
+                    .insert(
+                        rule_id,
+                        RuleStats {
+                            retries: prev_stats.retries + 1,
+                            ..prev_stats
+                        },
+                    )
+                    .await;
+            }
+
+            let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+
+            let attempt_timeout = min(Duration::from_millis(retries.connect_timeout), remaining);
+            let result =
+                match tokio::time::timeout(attempt_timeout, self.connect(&target.endpoint, source_addr, span)).await {
+                    Ok(result) => result,
+                    Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "connect timed out")),
+                };
+
+            match result {
+                Ok(stream) => return Ok((target, stream)),
+                Err(e) => {
+                    warn!(parent: span, "attempt {} to connect to {} failed: {}", attempt + 1, target.endpoint, e);
+                    target.report_failure();
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "retry deadline exceeded")))
+    }
+}
+
+pub async fn start_tcp_forward(
+    rule: Rule, config: AgentConfig, dal: DataAccessLayer, stats_cache: StatsCache, services: Services,
+) {
     let span = info_span!("start_tcp_forward", rule_id = rule.id.as_uuid().to_string());
 
-    let listener = match TcpListener::bind(rule.listen).await {
-        Ok(listener) => {
-            if let Err(e) = listener.set_ttl(255) {
-                debug!(parent: &span, "failed to set TTL: {}", e);
+    let mut listeners = Vec::new();
+
+    for (offset, listen_addr) in rule.listen_addrs() {
+        match Listener::bind(&listen_addr, &rule).await {
+            Ok(listener) => {
+                listeners.push((offset, listener));
+            }
+            Err(e) => {
+                error!(parent: &span, "failed to bind to {}: {}", listen_addr, e);
+
+                // handle non-retryable errors
+                utils::fail_rule(
+                    rule.id.into(),
+                    format!("failed to bind to {}", listen_addr),
+                    &dal,
+                    &stats_cache,
+                )
+                .await;
+
+                return;
             }
-            listener
         }
+    }
+
+    let tls = match rule.config.tls.as_ref().map(TlsLayer::new).transpose() {
+        Ok(tls) => tls,
         Err(e) => {
-            error!(parent: &span, "failed to bind to {}: {}", rule.listen, e);
+            error!(parent: &span, "failed to set up tls: {:#}", e);
 
             // handle non-retryable errors
-            {
-                let updated_stats = match stats_cache.get(&rule.id.into()).await {
-                    Some(current_stats) => RuleStats {
-                        last_failed_message: format!("failed to bind to {}", rule.listen),
-                        ..current_stats
-                    },
-                    None => RuleStats {
-                        last_failed_message: format!("failed to bind to {}", rule.listen),
-                        ..Default::default()
-                    },
-                };
-                let _ = dal.rule.update_status(rule.id.into(), RuleStatus::Error).await;
-                stats_cache.insert(rule.id.into(), updated_stats).await;
-            }
+            utils::fail_rule(rule.id.into(), format!("{:#}", e), &dal, &stats_cache).await;
+
+            return;
+        }
+    };
+
+    let router = match rule.config.routing.as_ref().map(Router::new).transpose() {
+        Ok(router) => router,
+        Err(e) => {
+            error!(parent: &span, "failed to set up routing: {:#}", e);
+
+            // handle non-retryable errors
+            utils::fail_rule(rule.id.into(), format!("{:#}", e), &dal, &stats_cache).await;
 
             return;
         }
@@ -66,33 +308,175 @@ pub async fn start_tcp_forward(rule: Rule, config: AgentConfig, dal: DataAccessL
         connections_limit.map(|conn| Arc::new(tokio::sync::Semaphore::new(conn as usize)))
     };
 
-    debug!(parent: &span, "tcp forwarding started on {}",rule.listen);
+    debug!(parent: &span, "tcp forwarding started on {}", rule.listen_display());
+
+    let (_running, stopped) = watch::channel(());
+    let forward = Arc::new(TcpForward {
+        capture: services.captures.for_rule(rule.id.as_uuid()),
+        telemetry: services.telemetry.for_rule(rule.id.as_uuid()),
+        balancer: services.balancers.for_rule(&rule),
+        peer: match (&rule.config.peer, &rule.config.agent) {
+            (Some(peer), _) => Some(services.peers.get(peer)),
+            (None, Some(agent)) => Some(services.peers.agent(agent)),
+            (None, None) => None,
+        },
+        peers: services.peers,
+        rule,
+        config,
+        stats_cache,
+        connections_semaphore,
+        queued: AtomicUsize::new(0),
+        tls,
+        router,
+        stopped,
+    });
+
+    // reverse rules have no listeners, they serve the connections coming through their tunnel instead
+    if forward.rule.protocol == RuleProtocol::Reverse {
+        tunnel::serve_reverse(forward).await;
+        return;
+    }
+
+    // one accept loop per listen address and port, all of them sharing the connection limit and stats
+    // of the rule
+    let mut accept_loops = JoinSet::new();
+    for (offset, listener) in listeners {
+        accept_loops.spawn(accept_connections(listener, offset, forward.clone(), span.clone()));
+    }
+
+    while accept_loops.join_next().await.is_some() {}
+}
+
+async fn accept_connections(listener: Listener, offset: usize, forward: Arc<TcpForward>, span: Span) {
+    let rule = &forward.rule;
+    let buffer_size = (forward.config.tcp_buffer_size as usize) * 1024;
 
     loop {
-        match listener.accept().await {
-            Ok((socket, addr)) => {
-                let sem_permit = match &connections_semaphore {
-                    Some(semaphore) => match semaphore.clone().try_acquire_owned() {
-                        Ok(permit) => Some(permit),
-                        Err(_) => {
-                            warn!(parent: &span, "max connections reached, rejecting connection from {}", addr);
+        match listener.accept(buffer_size, &span).await {
+            Ok(Incoming {
+                stream: socket,
+                peer_addr,
+                local_addr,
+            }) => {
+                let addr = peer_addr.map_or_else(|| listener.to_string(), |addr| addr.to_string());
+
+                let original_addr = match (&rule.config.transparent, local_addr) {
+                    (Some(transparent), Some(local_addr)) if transparent.original_destination => {
+                        if listener.is_bound_to(local_addr) {
+                            warn!(parent: &span, "rejecting connection from {} not redirected by tproxy", addr);
                             continue;
                         }
+                        Some(Endpoint::Inet(local_addr))
+                    }
+                    _ => None,
+                };
+                let source_addr = match &rule.config.transparent {
+                    Some(transparent) if transparent.spoof_source => peer_addr,
+                    _ => None,
+                };
+
+                let admission = match &forward.connections_semaphore {
+                    Some(semaphore) => match semaphore.clone().try_acquire_owned() {
+                        Ok(permit) => Admission::Admitted(Some(permit)),
+                        Err(_) => match forward.overflow(semaphore, offset) {
+                            Some(admission) => admission,
+                            None => {
+                                warn!(parent: &span, "max connections reached, rejecting connection from {}", addr);
+                                forward.update_overflow_stats(|overflow| overflow.rejected += 1).await;
+                                continue;
+                            }
+                        },
                     },
-                    None => None,
+                    None => Admission::Admitted(None),
                 };
 
                 trace!(parent: &span, "new connection from {}", addr);
 
-                let target_addr = rule.target.addrs[0];
-                let rule_id = rule.id;
-                let config = config.clone();
-                let stats_cache = stats_cache.clone();
+                let forward = forward.clone();
+
+                let span = span.clone();
 
                 tokio::spawn(async move {
-                    let _permit = sem_permit;
+                    let (_permit, redirect_addr) = match admission {
+                        Admission::Admitted(permit) => (permit, None),
+                        Admission::Queued(semaphore, timeout) => {
+                            match forward.wait_in_queue(semaphore, timeout).await {
+                                Some(permit) => (Some(permit), None),
+                                None => {
+                                    debug!(parent: &span, "queued connection from {} timed out, rejecting", addr);
+                                    return;
+                                }
+                            }
+                        }
+                        Admission::Redirected(redirect_addr) => {
+                            debug!(parent: &span, "max connections reached, redirecting connection from {}", addr);
+                            forward.update_overflow_stats(|overflow| overflow.redirected += 1).await;
+                            (None, Some(redirect_addr))
+                        }
+                    };
+
+                    // only proxy and tunnel rules have no targets, their clients pick the destination of each
+                    // connection
+                    let redirected = redirect_addr.is_some();
+                    let targets = match redirect_addr.or(original_addr) {
+                        Some(target_addr) => vec![Candidate::fixed(target_addr)],
+                        None if forward.rule.target.addrs.is_empty() => {
+                            match forward.rule.protocol {
+                                RuleProtocol::Tunnel => tunnel::handle_connection(socket, peer_addr, forward).await,
+                                _ => proxy::handle_connection(socket, peer_addr, local_addr, forward).await,
+                            }
+                            return;
+                        }
+                        None => forward.balancer.candidates(offset, peer_addr),
+                    };
+
+                    // redirected connections skip the routes, they all go to the overflow target
+                    let router = forward.router.as_ref().filter(|_| !redirected);
+                    let (socket, targets) = match router {
+                        Some(router) => match router.route(socket, offset).await {
+                            Ok((socket, Route::Matched(target_addr))) => (socket, vec![Candidate::fixed(target_addr)]),
+                            Ok((socket, Route::Default)) => (socket, targets),
+                            Ok((_, Route::Rejected)) => {
+                                debug!(parent: &span, "no route matched connection from {}, rejecting", addr);
+
+                                let rule_id = forward.rule.id.as_uuid();
+                                let prev_stats = forward.stats_cache.get(&rule_id).await.unwrap_or_default();
+                                forward
+                                    .stats_cache
+                                    .insert(
+                                        rule_id,
+                                        RuleStats {
+                                            unmatched_connections: prev_stats.unmatched_connections + 1,
+                                            ..prev_stats
+                                        },
+                                    )
+                                    .await;
+
+                                return;
+                            }
+                            Err(e) => {
+                                debug!(parent: &span, "failed to route connection from {}: {}", addr, e);
+                                return;
+                            }
+                        },
+                        None => (socket, targets),
+                    };
 
-                    handle_connection(socket, target_addr, rule_id.as_uuid(), config, stats_cache).await;
+                    if targets.is_empty() {
+                        warn!(parent: &span, "no target takes new connections, rejecting connection from {}", addr);
+                        return;
+                    }
+
+                    match forward.rule.protocol {
+                        RuleProtocol::Http => {
+                            http::handle_connection(socket, peer_addr, offset, targets, source_addr, forward).await;
+                        }
+                        _ => {
+                            let mirror_addr = forward.rule.config.mirror.as_ref().map(|mirror| mirror.resolve(offset));
+
+                            handle_connection(socket, peer_addr, targets, source_addr, mirror_addr, forward).await;
+                        }
+                    }
                 });
             }
             Err(e) => {
@@ -103,14 +487,63 @@ pub async fn start_tcp_forward(rule: Rule, config: AgentConfig, dal: DataAccessL
 }
 
 async fn handle_connection(
-    mut client_stream: TcpStream, target_addr: std::net::SocketAddr, rule_id: uuid::Uuid, config: AgentConfig,
-    stats_cache: StatsCache,
+    client_stream: BoxedStream, peer_addr: Option<SocketAddr>, targets: Vec<Candidate>,
+    source_addr: Option<SocketAddr>, mirror_addr: Option<Endpoint>, forward: Arc<TcpForward>,
 ) {
-    let span = info_span!(
-        "handle_tcp_connection",
-        rule_id = rule_id.to_string(),
-        target_addr = target_addr.to_string()
-    );
+    let rule_id = forward.rule.id.as_uuid();
+
+    let span = info_span!("handle_tcp_connection", rule_id = rule_id.to_string());
+
+    let client_stream = match &forward.tls {
+        Some(tls) => match tls.accept(client_stream).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!(parent: &span, "tls handshake with client failed: {}", e);
+                return;
+            }
+        },
+        None => client_stream,
+    };
+
+    if forward.rule.config.udp_over_tcp == Some(RuleUdpOverTcp::Decapsulate) {
+        // datagrams can't tell whether a target is up, so there is nothing to fail over on
+        let target = targets.into_iter().next().unwrap().lease();
+        if let Err(e) = udp_over_tcp::decapsulate(client_stream, peer_addr, target, source_addr, &forward, &span).await
+        {
+            error!(parent: &span, "failed to set up udp socket to target: {}", e);
+        }
+        return;
+    }
+
+    match forward.connect_any(&targets, source_addr, &span).await {
+        Ok((target, server_stream)) => {
+            trace!(parent: &span, "connected to {}", target.endpoint);
+
+            relay(
+                client_stream,
+                server_stream,
+                peer_addr,
+                target.clone().lease(),
+                mirror_addr,
+                &forward,
+                &span,
+            )
+            .await;
+        }
+        Err(e) => {
+            error!(parent: &span, "failed to connect to target: {}", e);
+        }
+    }
+}
+
+// Copies bytes both ways between a client and its connected target until either side is done.
+pub async fn relay(
+    client_stream: BoxedStream, server_stream: BoxedStream, peer_addr: Option<SocketAddr>, target: TargetLease,
+    mirror_addr: Option<Endpoint>, forward: &TcpForward, span: &Span,
+) {
+    let rule_id = forward.rule.id.as_uuid();
+    let config = &forward.config;
+    let stats_cache = &forward.stats_cache;
 
     let buffer_size = (config.tcp_buffer_size as usize) * 1024;
 
@@ -130,235 +563,409 @@ async fn handle_connection(
             .await;
     }
 
-    match TcpStream::connect(target_addr).await {
-        Ok(mut server_stream) => {
-            trace!(parent: &span, "connected to target");
+    let (mut client_reader, mut client_writer) = tokio::io::split(client_stream);
+    let (mut server_reader, mut server_writer) = tokio::io::split(server_stream);
 
-            {
-                let client_sock_ref = socket2::SockRef::from(&client_stream);
-                let server_sock_ref = socket2::SockRef::from(&server_stream);
+    let transferred_bytes = Arc::new(AtomicU64::new(0));
+    let stats_updater = spawn_stats_updater(
+        rule_id,
+        transferred_bytes.clone(),
+        stats_cache.clone(),
+        Duration::from_millis(config.stats_update_interval),
+    );
 
-                // Set TCP keepalive
-                {
-                    let mut ka = socket2::TcpKeepalive::new();
-                    ka = ka.with_time(Duration::from_secs(20));
-                    ka = ka.with_interval(Duration::from_secs(20));
+    let mut mirror = mirror_addr.map(|mirror_addr| StreamMirror::connect(mirror_addr, buffer_size, span.clone()));
 
-                    if let Err(e) = client_sock_ref.set_tcp_keepalive(&ka) {
-                        debug!(parent: &span, "failed to set tcp keepalive for client stream: {}", e);
-                    }
-                    if let Err(e) = server_sock_ref.set_tcp_keepalive(&ka) {
-                        debug!(parent: &span, "failed to set tcp keepalive for server stream: {}", e);
-                    }
-                }
+    let flow = TcpFlow::new(peer_addr, target.endpoint());
+    let meter = forward.telemetry.open_connection(peer_addr, Some(target.endpoint()));
+
+    // resets and a target that times out without answering count against it
+    let target_failed = AtomicBool::new(false);
+    let target_answered = AtomicBool::new(false);
+
+    let client_to_server = async {
+        let mut buffer = vec![0u8; buffer_size];
+        let mut last_flush_time = tokio::time::Instant::now();
 
-                // Set TCP window size
-                {
-                    if let Err(e) = client_sock_ref.set_recv_buffer_size(buffer_size * 4) {
-                        debug!(parent: &span, "failed to set client recv buffer: {}", e);
+        loop {
+            match tokio::time::timeout(Duration::from_secs(60), client_reader.read(&mut buffer)).await {
+                Ok(Ok(0)) => break,
+                Ok(Ok(n)) => {
+                    if let Err(e) = server_writer.write_all(&buffer[..n]).await {
+                        warn!(parent: span, "error writing to server: {}", e);
+                        target_failed.store(true, Ordering::Relaxed);
+                        break;
                     }
-                    if let Err(e) = server_sock_ref.set_send_buffer_size(buffer_size * 4) {
-                        debug!(parent: &span, "failed to set server send buffer: {}", e);
+
+                    if let Some(mirror) = &mut mirror {
+                        mirror.send(&buffer[..n]);
                     }
-                }
 
-                // Set TCP no delay
-                {
-                    if let Err(e) = client_sock_ref.set_tcp_nodelay(true) {
-                        debug!(parent: &span, "failed to set nodelay for client stream: {}", e);
+                    if let Some(capture) = forward.capture.active() {
+                        flow.record(&capture, Direction::ToTarget, &buffer[..n]);
                     }
-                    if let Err(e) = server_sock_ref.set_tcp_nodelay(true) {
-                        debug!(parent: &span, "failed to set nodelay for server stream: {}", e);
+
+                    // Update transferred byte count
+                    transferred_bytes.fetch_add(n as u64, Ordering::Relaxed);
+                    meter.add(n as u64);
+
+                    // Try to refresh the buffer, but with a little latency
+                    if (n == buffer_size
+                        || tokio::time::Instant::now().duration_since(last_flush_time) > Duration::from_millis(50))
+                        && let Err(e) = server_writer.flush().await
+                    {
+                        warn!(parent: span, "error flushing server writer: {}", e);
+                        break;
                     }
+
+                    last_flush_time = tokio::time::Instant::now();
+                }
+                Ok(Err(e)) => {
+                    warn!(parent: span, "error reading from client: {}", e);
+                    break;
+                }
+                Err(_) => {
+                    warn!(parent: span, "client read timeout");
+                    break;
                 }
             }
+        }
 
-            let (mut client_reader, mut client_writer) = client_stream.split();
-            let (mut server_reader, mut server_writer) = server_stream.split();
-
-            let transferred_bytes = Arc::new(AtomicU64::new(0));
-            let last_update_time = Arc::new(Mutex::new(tokio::time::Instant::now()));
+        let _ = server_writer.shutdown().await;
+        debug!(parent: span, "client_to_server stream closed");
+    };
 
-            let stats_update_interval = Duration::from_millis(config.stats_update_interval);
-            let stats_updater = {
-                let transferred_bytes = transferred_bytes.clone();
-                let last_update_time = last_update_time.clone();
-                let stats_cache = stats_cache.clone();
+    let server_to_client = async {
+        let mut buffer = vec![0u8; buffer_size];
+        let mut last_flush_time = tokio::time::Instant::now();
 
-                tokio::spawn(async move {
-                    let mut interval = tokio::time::interval(stats_update_interval);
-
-                    loop {
-                        interval.tick().await;
-
-                        let prev_stats = stats_cache.get(&rule_id).await.unwrap_or_default();
-
-                        // Get the number of bytes transferred since the last update
-                        let bytes = transferred_bytes.swap(0, Ordering::Relaxed);
-
-                        // Calculate total bandwidth
-                        let bandwidth = prev_stats.bandwidth + bytes;
-
-                        // Calculation speed (bytes/second)
-                        let mut speed = prev_stats.speed;
-
-                        let mut last_time = last_update_time.lock().await;
-                        let elapsed = tokio::time::Instant::now().duration_since(*last_time);
-                        if elapsed.as_millis() > 0 && bytes > 0 {
-                            speed = (bytes * 1000) / elapsed.as_millis() as u64;
-                        } else if bytes == 0 {
-                            // If there is no new data transmission, gradually reduce the speed to reflect the actual
-                            // situation.
-                            speed = match speed {
-                                speed if speed > 1_000_000 => speed.saturating_mul(40).saturating_div(100), /* Reduce by 60% each time */
-                                speed if speed > 500_000 => speed.saturating_mul(30).saturating_div(100), /* Reduce by 70% each time */
-                                speed if speed > 25_000 => speed.saturating_mul(20).saturating_div(100), /* Reduce by 80% each time */
-                                speed if speed > 1_000 => speed.saturating_mul(10).saturating_div(100), /* Reduce by 90% each time */
-                                _ => speed.saturating_mul(5).saturating_div(100), // Reduce by 95% each time
-                            };
-                        }
+        loop {
+            match tokio::time::timeout(Duration::from_secs(300), server_reader.read(&mut buffer)).await {
+                Ok(Ok(0)) => break,
+                Ok(Ok(n)) => {
+                    target_answered.store(true, Ordering::Relaxed);
+                    meter.answered();
 
-                        *last_time = tokio::time::Instant::now();
-
-                        stats_cache
-                            .insert(
-                                rule_id,
-                                RuleStats {
-                                    bandwidth,
-                                    speed,
-                                    ..prev_stats
-                                },
-                            )
-                            .await;
+                    if let Err(e) = client_writer.write_all(&buffer[..n]).await {
+                        warn!(parent: span, "error writing to client: {}", e);
+                        break;
                     }
-                })
-            };
 
-            let client_to_server = async {
-                let mut buffer = vec![0u8; buffer_size];
-                let mut last_flush_time = tokio::time::Instant::now();
-
-                loop {
-                    match tokio::time::timeout(Duration::from_secs(60), client_reader.read(&mut buffer)).await {
-                        Ok(Ok(0)) => break,
-                        Ok(Ok(n)) => {
-                            if let Err(e) = server_writer.write_all(&buffer[..n]).await {
-                                warn!(parent: &span, "error writing to server: {}", e);
-                                break;
-                            }
+                    if let Some(capture) = forward.capture.active() {
+                        flow.record(&capture, Direction::ToClient, &buffer[..n]);
+                    }
 
-                            // Update transferred byte count
-                            transferred_bytes.fetch_add(n as u64, Ordering::Relaxed);
-
-                            // Try to refresh the buffer, but with a little latency
-                            if (n == buffer_size
-                                || tokio::time::Instant::now().duration_since(last_flush_time)
-                                    > Duration::from_millis(50))
-                                && let Err(e) = server_writer.flush().await
-                            {
-                                warn!(parent: &span, "error flushing server writer: {}", e);
-                                break;
-                            }
+                    // Update transferred byte count
+                    transferred_bytes.fetch_add(n as u64, Ordering::Relaxed);
+                    meter.add(n as u64);
+
+                    // Try to refresh the buffer, but with a little latency
+                    if (n == buffer_size
+                        || tokio::time::Instant::now().duration_since(last_flush_time) > Duration::from_millis(50))
+                        && let Err(e) = client_writer.flush().await
+                    {
+                        warn!(parent: span, "error flushing client writer: {}", e);
+                        break;
+                    }
 
-                            last_flush_time = tokio::time::Instant::now();
-                        }
-                        Ok(Err(e)) => {
-                            warn!(parent: &span, "error reading from client: {}", e);
-                            break;
-                        }
-                        Err(_) => {
-                            warn!(parent: &span, "client read timeout");
-                            break;
-                        }
+                    last_flush_time = tokio::time::Instant::now();
+                }
+                Ok(Err(e)) => {
+                    warn!(parent: span, "error reading from server: {}", e);
+                    target_failed.store(true, Ordering::Relaxed);
+                    break;
+                }
+                Err(_) => {
+                    warn!(parent: span, "server read timeout");
+                    if !target_answered.load(Ordering::Relaxed) {
+                        target_failed.store(true, Ordering::Relaxed);
                     }
+                    break;
                 }
+            }
+        }
 
-                let _ = server_writer.shutdown().await;
-                debug!(parent: &span, "client_to_server stream closed");
-            };
-
-            let server_to_client = async {
-                let mut buffer = vec![0u8; buffer_size];
-                let mut last_flush_time = tokio::time::Instant::now();
-
-                loop {
-                    match tokio::time::timeout(Duration::from_secs(300), server_reader.read(&mut buffer)).await {
-                        Ok(Ok(0)) => break,
-                        Ok(Ok(n)) => {
-                            if let Err(e) = client_writer.write_all(&buffer[..n]).await {
-                                warn!(parent: &span, "error writing to client: {}", e);
-                                break;
-                            }
+        let _ = client_writer.shutdown().await;
+        debug!(parent: span, "server_to_client stream closed");
+    };
 
-                            // Update transferred byte count
-                            transferred_bytes.fetch_add(n as u64, Ordering::Relaxed);
-
-                            // Try to refresh the buffer, but with a little latency
-                            if (n == buffer_size
-                                || tokio::time::Instant::now().duration_since(last_flush_time)
-                                    > Duration::from_millis(50))
-                                && let Err(e) = client_writer.flush().await
-                            {
-                                warn!(parent: &span, "error flushing client writer: {}", e);
-                                break;
-                            }
+    let handle = tokio::time::timeout(
+        Duration::from_secs(60 * 5), // over five minutes
+        async {
+            tokio::join!(client_to_server, server_to_client);
+        },
+    );
 
-                            last_flush_time = tokio::time::Instant::now();
-                        }
-                        Ok(Err(e)) => {
-                            warn!(parent: &span, "error reading from server: {}", e);
-                            break;
-                        }
-                        Err(_) => {
-                            warn!(parent: &span, "server read timeout");
-                            break;
-                        }
-                    }
+    tokio::select! {
+        _ = async {
+            match handle.await {
+                Ok(_) => {},
+                Err(_) => {
+                    debug!(parent: span, "connection timeout");
                 }
+            }
+        } => {},
+        _ = stats_updater => {
+            debug!(parent: span, "stats updater finished unexpectedly");
+        }
+        _ = target.closed() => {
+            debug!(parent: span, "target disabled, closing connection");
+        }
+    }
 
-                let _ = client_writer.shutdown().await;
-                debug!(parent: &span, "server_to_client stream closed");
-            };
+    if target_failed.load(Ordering::Relaxed) {
+        target.report_failure();
+    } else {
+        target.report_success();
+    }
 
-            let handle = tokio::time::timeout(
-                Duration::from_secs(60 * 5), // over five minutes
-                async {
-                    tokio::join!(client_to_server, server_to_client);
+    if let Some(capture) = forward.capture.active() {
+        flow.close(&capture);
+    }
+
+    {
+        let prev_stats = stats_cache.get(&rule_id).await.unwrap_or_default();
+        stats_cache
+            .insert(
+                rule_id,
+                RuleStats {
+                    connections: RuleStatsConnections {
+                        tcp: prev_stats.connections.tcp - 1,
+                        ..prev_stats.connections
+                    },
+                    ..prev_stats
                 },
-            );
-
-            tokio::select! {
-                _ = async {
-                    match handle.await {
-                        Ok(_) => {},
-                        Err(_) => {
-                            debug!(parent: &span, "connection timeout");
-                        }
-                    }
-                } => {},
-                _ = stats_updater => {
-                    debug!(parent: &span, "stats updater finished unexpectedly");
+            )
+            .await;
+    }
+}
+
+// Folds the bytes counted by a connection into the rule bandwidth and speed.
+pub fn spawn_stats_updater(
+    rule_id: uuid::Uuid, transferred_bytes: Arc<AtomicU64>, stats_cache: StatsCache, stats_update_interval: Duration,
+) -> JoinHandle<()> {
+    let last_update_time = Arc::new(Mutex::new(tokio::time::Instant::now()));
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(stats_update_interval);
+
+        loop {
+            interval.tick().await;
+
+            let prev_stats = stats_cache.get(&rule_id).await.unwrap_or_default();
+
+            // Get the number of bytes transferred since the last update
+            let bytes = transferred_bytes.swap(0, Ordering::Relaxed);
+
+            // Calculate total bandwidth
+            let bandwidth = prev_stats.bandwidth + bytes;
+
+            // Calculation speed (bytes/second)
+            let mut speed = prev_stats.speed;
+
+            let mut last_time = last_update_time.lock().await;
+            let elapsed = tokio::time::Instant::now().duration_since(*last_time);
+            if elapsed.as_millis() > 0 && bytes > 0 {
+                speed = (bytes * 1000) / elapsed.as_millis() as u64;
+            } else if bytes == 0 {
+                // If there is no new data transmission, gradually reduce the speed to reflect the actual
+                // situation.
+                speed = decay_speed(speed);
+            }
+
+            *last_time = tokio::time::Instant::now();
+
+            stats_cache
+                .insert(
+                    rule_id,
+                    RuleStats {
+                        bandwidth,
+                        speed,
+                        ..prev_stats
+                    },
+                )
+                .await;
+        }
+    })
+}
+
+// Brings the speed of a rule down gradually while no bytes go through it.
+#[rustfmt::skip]
+fn decay_speed(speed: u64) -> u64 {
+    match speed {
+        speed if speed > 1_000_000 => speed.saturating_mul(40).saturating_div(100), /* Reduce by 60% each time */
+        speed if speed > 500_000 => speed.saturating_mul(30).saturating_div(100), /* Reduce by 70% each time */
+        speed if speed > 25_000 => speed.saturating_mul(20).saturating_div(100), /* Reduce by 80% each time */
+        speed if speed > 1_000 => speed.saturating_mul(10).saturating_div(100), /* Reduce by 90% each time */
+        _ => speed.saturating_mul(5).saturating_div(100), // Reduce by 95% each time
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix {
+        listener: UnixListener,
+        _guard: UnixSocketGuard,
+    },
+}
+
+impl Listener {
+    async fn bind(endpoint: &Endpoint, rule: &Rule) -> io::Result<Self> {
+        match endpoint {
+            Endpoint::Inet(addr) => {
+                let listener = match rule.config.transparent {
+                    Some(_) => transparent::bind_tcp_listener(*addr)?,
+                    None => TcpListener::bind(addr).await?,
+                };
+                if let Err(e) = listener.set_ttl(255) {
+                    debug!("failed to set TTL: {}", e);
                 }
+                Ok(Listener::Tcp(listener))
+            }
+            #[cfg(unix)]
+            Endpoint::Unix(path) => {
+                let (listener, guard) = bind_unix_listener(path, rule.config.unix_socket_mode).await?;
+                Ok(Listener::Unix {
+                    listener,
+                    _guard: guard,
+                })
             }
+            #[cfg(not(unix))]
+            Endpoint::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "unix sockets are not supported on this platform",
+            )),
+        }
+    }
 
-            {
-                let prev_stats = stats_cache.get(&rule_id).await.unwrap_or_default();
-                stats_cache
-                    .insert(
-                        rule_id,
-                        RuleStats {
-                            connections: RuleStatsConnections {
-                                tcp: prev_stats.connections.tcp - 1,
-                                ..prev_stats.connections
-                            },
-                            ..prev_stats
-                        },
-                    )
-                    .await;
+    async fn accept(&self, buffer_size: usize, span: &Span) -> io::Result<Incoming> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                tune_tcp_stream(&stream, StreamSide::Client, buffer_size, span);
+                Ok(Incoming {
+                    local_addr: stream.local_addr().ok(),
+                    peer_addr: Some(addr),
+                    stream: Box::new(stream),
+                })
+            }
+            #[cfg(unix)]
+            Listener::Unix { listener, .. } => {
+                let (stream, _) = listener.accept().await?;
+                Ok(Incoming {
+                    stream: Box::new(stream),
+                    peer_addr: None,
+                    local_addr: None,
+                })
             }
         }
-        Err(e) => {
-            error!(parent: &span, "failed to connect to target: {}", e);
+    }
+
+    fn is_bound_to(&self, addr: SocketAddr) -> bool {
+        match self {
+            Listener::Tcp(listener) => listener
+                .local_addr()
+                .is_ok_and(|local_addr| transparent::is_bound_to(local_addr, addr)),
+            #[cfg(unix)]
+            Listener::Unix { .. } => false,
+        }
+    }
+}
+
+struct Incoming {
+    stream: BoxedStream,
+    peer_addr: Option<SocketAddr>,
+    // with transparent mode this is the original destination of the connection
+    local_addr: Option<SocketAddr>,
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{}", addr),
+                Err(_) => write!(f, "tcp listener"),
+            },
+            #[cfg(unix)]
+            Listener::Unix { listener, .. } => match listener
+                .local_addr()
+                .ok()
+                .and_then(|addr| addr.as_pathname().map(Path::to_path_buf))
+            {
+                Some(path) => write!(f, "unix:{}", path.display()),
+                None => write!(f, "unix listener"),
+            },
+        }
+    }
+}
+
+pub async fn connect_target(
+    endpoint: &Endpoint, source_addr: Option<SocketAddr>, buffer_size: usize, span: &Span,
+) -> io::Result<BoxedStream> {
+    match endpoint {
+        Endpoint::Inet(addr) => {
+            let stream = match source_addr {
+                Some(source_addr) => transparent::connect_tcp(*addr, source_addr).await?,
+                None => TcpStream::connect(addr).await?,
+            };
+            tune_tcp_stream(&stream, StreamSide::Server, buffer_size, span);
+            Ok(Box::new(stream))
+        }
+        #[cfg(unix)]
+        Endpoint::Unix(path) => Ok(Box::new(UnixStream::connect(path).await?)),
+        #[cfg(not(unix))]
+        Endpoint::Unix(_) => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "unix sockets are not supported on this platform",
+        )),
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum StreamSide {
+    Client,
+    Server,
+}
+
+impl fmt::Display for StreamSide {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StreamSide::Client => write!(f, "client"),
+            StreamSide::Server => write!(f, "server"),
+        }
+    }
+}
+
+fn tune_tcp_stream(stream: &TcpStream, side: StreamSide, buffer_size: usize, span: &Span) {
+    let sock_ref = socket2::SockRef::from(stream);
+
+    // Set TCP keepalive
+    {
+        let mut ka = socket2::TcpKeepalive::new();
+        ka = ka.with_time(Duration::from_secs(20));
+        ka = ka.with_interval(Duration::from_secs(20));
+
+        if let Err(e) = sock_ref.set_tcp_keepalive(&ka) {
+            debug!(parent: span, "failed to set tcp keepalive for {} stream: {}", side, e);
+        }
+    }
+
+    // Set TCP window size
+    {
+        let result = match side {
+            StreamSide::Client => sock_ref.set_recv_buffer_size(buffer_size * 4),
+            StreamSide::Server => sock_ref.set_send_buffer_size(buffer_size * 4),
+        };
+        if let Err(e) = result {
+            debug!(parent: span, "failed to set {} buffer: {}", side, e);
+        }
+    }
+
+    // Set TCP no delay
+    {
+        if let Err(e) = sock_ref.set_tcp_nodelay(true) {
+            debug!(parent: span, "failed to set nodelay for {} stream: {}", side, e);
         }
     }
 }
@@ -0,0 +1,371 @@
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    hash::Hash,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use pedicab_db::data::rule::{Endpoint, RuleStatsLatency, RuleStatsPercentiles};
+use serde::Serialize;
+use uuid::Uuid;
+
+// Clients and targets kept per rule, further ones take the place of the lightest.
+const TALLY_CAPACITY: usize = 1024;
+
+// Histogram buckets, values below 8 get one each and every power of two above is split in 8.
+const SUB_BUCKETS: u64 = 8;
+const HISTOGRAM_BUCKETS: usize = 8 * 62;
+
+// The traffic of every rule broken down by client address and by target, kept across restarts of
+// a rule until it is disabled.
+#[derive(Clone, Default)]
+pub struct Telemetry(Arc<RwLock<HashMap<Uuid, Arc<RuleTelemetry>>>>);
+
+impl Telemetry {
+    pub fn for_rule(&self, rule_id: Uuid) -> Arc<RuleTelemetry> {
+        self.0.write().unwrap().entry(rule_id).or_default().clone()
+    }
+
+    pub fn remove(&self, rule_id: Uuid) {
+        self.0.write().unwrap().remove(&rule_id);
+    }
+
+    pub fn reset(&self, rule_id: Uuid) {
+        if let Some(telemetry) = self.0.read().unwrap().get(&rule_id) {
+            telemetry.reset();
+        }
+    }
+
+    // The clients and targets of a rule that moved the most bytes, heaviest first.
+    pub fn top_talkers(&self, rule_id: Uuid, top: usize) -> TopTalkers {
+        match self.0.read().unwrap().get(&rule_id) {
+            Some(telemetry) => TopTalkers {
+                clients: telemetry.clients.lock().unwrap().top(top),
+                targets: telemetry.targets.lock().unwrap().top(top),
+            },
+            None => TopTalkers::default(),
+        }
+    }
+
+    pub fn latency(&self, rule_id: Uuid) -> RuleStatsLatency {
+        match self.0.read().unwrap().get(&rule_id) {
+            Some(telemetry) => telemetry.latency.percentiles(),
+            None => RuleStatsLatency::default(),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct RuleTelemetry {
+    clients: Mutex<HeavyHitters<IpAddr>>,
+    targets: Mutex<HeavyHitters<Endpoint>>,
+    latency: Arc<Latency>,
+}
+
+impl RuleTelemetry {
+    pub fn record_connect(&self, elapsed: Duration) {
+        self.latency.connect.record(elapsed);
+    }
+
+    // Counts how long a target took to answer a request, for targets answering many per connection.
+    pub fn record_first_byte(&self, elapsed: Duration) {
+        self.latency.first_byte.record(elapsed);
+    }
+
+    // Counts a new connection of a client to a target, its bytes go through the returned meter.
+    pub fn open_connection(&self, client_addr: Option<SocketAddr>, target: Option<&Endpoint>) -> TrafficMeter {
+        self.open(client_addr, target, |tally| &tally.connections)
+    }
+
+    // Counts a new datagram session of a client to a target, its bytes go through the returned meter.
+    pub fn open_session(&self, client_addr: Option<SocketAddr>, target: Option<&Endpoint>) -> TrafficMeter {
+        self.open(client_addr, target, |tally| &tally.sessions)
+    }
+
+    fn open(
+        &self, client_addr: Option<SocketAddr>, target: Option<&Endpoint>, counter: fn(&Tally) -> &AtomicU64,
+    ) -> TrafficMeter {
+        let tallies = Tallies {
+            client: client_addr.map(|addr| self.clients.lock().unwrap().tally(addr.ip().to_canonical())),
+            target: target.map(|target| self.targets.lock().unwrap().tally(target.clone())),
+            latency: self.latency.clone(),
+            opened: Instant::now(),
+            answered: AtomicBool::new(false),
+        };
+
+        for tally in tallies.iter() {
+            counter(tally).fetch_add(1, Ordering::Relaxed);
+            tally.active.fetch_add(1, Ordering::Relaxed);
+        }
+
+        TrafficMeter(Arc::new(tallies))
+    }
+
+    fn reset(&self) {
+        self.clients.lock().unwrap().reset();
+        self.targets.lock().unwrap().reset();
+        self.latency.reset();
+    }
+}
+
+#[derive(Default)]
+struct Latency {
+    connect: Histogram,
+    first_byte: Histogram,
+    duration: Histogram,
+}
+
+impl Latency {
+    fn percentiles(&self) -> RuleStatsLatency {
+        RuleStatsLatency {
+            connect: self.connect.percentiles(),
+            first_byte: self.first_byte.percentiles(),
+            duration: self.duration.percentiles(),
+        }
+    }
+
+    fn reset(&self) {
+        self.connect.reset();
+        self.first_byte.reset();
+        self.duration.reset();
+    }
+}
+
+// Counts of measurements in microseconds by log-linear bucket, so percentiles come out within an
+// eighth of the measured value in fixed memory.
+struct Histogram {
+    buckets: Box<[AtomicU64]>,
+    count: AtomicU64,
+    max: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            buckets: (0..HISTOGRAM_BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            max: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    fn record(&self, elapsed: Duration) {
+        let value = elapsed.as_micros().min(u64::MAX as u128) as u64;
+
+        self.buckets[Self::bucket(value)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.max.fetch_max(value, Ordering::Relaxed);
+    }
+
+    fn bucket(value: u64) -> usize {
+        if value < SUB_BUCKETS {
+            return value as usize;
+        }
+        let exponent = value.ilog2() as u64;
+        (SUB_BUCKETS * (exponent - 2) + ((value >> (exponent - 3)) & (SUB_BUCKETS - 1))) as usize
+    }
+
+    // The highest value that falls into a bucket.
+    fn bucket_bound(bucket: usize) -> u64 {
+        let bucket = bucket as u64;
+        if bucket < SUB_BUCKETS {
+            return bucket;
+        }
+        let width = 1u64 << (bucket / SUB_BUCKETS - 1);
+        (SUB_BUCKETS + bucket % SUB_BUCKETS) * width + (width - 1)
+    }
+
+    fn percentiles(&self) -> RuleStatsPercentiles {
+        let count = self.count.load(Ordering::Relaxed);
+        let max = self.max.load(Ordering::Relaxed);
+
+        let percentile = |percent: u64| {
+            let rank = (count * percent).div_ceil(100).max(1);
+            let mut seen = 0;
+            for (bucket, counter) in self.buckets.iter().enumerate() {
+                seen += counter.load(Ordering::Relaxed);
+                if seen >= rank {
+                    return Self::bucket_bound(bucket).min(max);
+                }
+            }
+            max
+        };
+
+        match count {
+            0 => RuleStatsPercentiles::default(),
+            _ => RuleStatsPercentiles {
+                count,
+                p50: percentile(50),
+                p90: percentile(90),
+                p99: percentile(99),
+                max,
+            },
+        }
+    }
+
+    fn reset(&self) {
+        for counter in self.buckets.iter() {
+            counter.store(0, Ordering::Relaxed);
+        }
+        self.count.store(0, Ordering::Relaxed);
+        self.max.store(0, Ordering::Relaxed);
+    }
+}
+
+// The counters of the heaviest keys in bounded memory, after the Space-Saving algorithm: once full,
+// a new key replaces the lightest idle one and inherits its bytes as a possible overcount, so a
+// heavy key is never pushed out by many light ones.
+struct HeavyHitters<K> {
+    tallies: HashMap<K, Arc<Tally>>,
+}
+
+impl<K> Default for HeavyHitters<K> {
+    fn default() -> Self {
+        HeavyHitters {
+            tallies: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone + ToString> HeavyHitters<K> {
+    fn tally(&mut self, key: K) -> Arc<Tally> {
+        if let Some(tally) = self.tallies.get(&key) {
+            return tally.clone();
+        }
+
+        let tally = Tally::default();
+
+        if self.tallies.len() >= TALLY_CAPACITY {
+            let lightest = self
+                .tallies
+                .iter()
+                .min_by_key(|(_, tally)| {
+                    (
+                        tally.active.load(Ordering::Relaxed) > 0,
+                        tally.bytes.load(Ordering::Relaxed),
+                    )
+                })
+                .map(|(key, _)| key.clone());
+
+            // connections of a key pushed out keep counting, just not here anymore
+            if let Some(lightest) = lightest.and_then(|lightest| self.tallies.remove(&lightest)) {
+                let bytes = lightest.bytes.load(Ordering::Relaxed);
+                tally.bytes.store(bytes, Ordering::Relaxed);
+                tally.overcount.store(bytes, Ordering::Relaxed);
+            }
+        }
+
+        let tally = Arc::new(tally);
+        self.tallies.insert(key, tally.clone());
+        tally
+    }
+
+    // Forgets the idle keys and starts the others over.
+    fn reset(&mut self) {
+        self.tallies.retain(|_, tally| tally.active.load(Ordering::Relaxed) > 0);
+
+        for tally in self.tallies.values() {
+            tally.bytes.store(0, Ordering::Relaxed);
+            tally.overcount.store(0, Ordering::Relaxed);
+            tally.connections.store(0, Ordering::Relaxed);
+            tally.sessions.store(0, Ordering::Relaxed);
+        }
+    }
+
+    fn top(&self, top: usize) -> Vec<TalkerStatus> {
+        let mut talkers = self
+            .tallies
+            .iter()
+            .map(|(key, tally)| TalkerStatus {
+                addr: key.to_string(),
+                bytes: tally.bytes.load(Ordering::Relaxed),
+                overcount: tally.overcount.load(Ordering::Relaxed),
+                connections: tally.connections.load(Ordering::Relaxed),
+                sessions: tally.sessions.load(Ordering::Relaxed),
+                active: tally.active.load(Ordering::Relaxed),
+            })
+            .collect::<Vec<_>>();
+
+        talkers.sort_unstable_by_key(|talker| Reverse(talker.bytes));
+        talkers.truncate(top);
+        talkers
+    }
+}
+
+#[derive(Default)]
+struct Tally {
+    bytes: AtomicU64,
+    overcount: AtomicU64,
+    connections: AtomicU64,
+    sessions: AtomicU64,
+    active: AtomicU64,
+}
+
+struct Tallies {
+    client: Option<Arc<Tally>>,
+    target: Option<Arc<Tally>>,
+    latency: Arc<Latency>,
+    opened: Instant,
+    answered: AtomicBool,
+}
+
+impl Tallies {
+    fn iter(&self) -> impl Iterator<Item = &Arc<Tally>> {
+        self.client.iter().chain(self.target.iter())
+    }
+}
+
+impl Drop for Tallies {
+    fn drop(&mut self) {
+        for tally in self.iter() {
+            tally.active.fetch_sub(1, Ordering::Relaxed);
+        }
+        self.latency.duration.record(self.opened.elapsed());
+    }
+}
+
+// Counts the bytes of a connection or session for its client and target, which count it as active
+// until the last clone of the meter is dropped. That is when its duration is measured too.
+#[derive(Clone)]
+pub struct TrafficMeter(Arc<Tallies>);
+
+impl TrafficMeter {
+    pub fn add(&self, bytes: u64) {
+        for tally in self.0.iter() {
+            tally.bytes.fetch_add(bytes, Ordering::Relaxed);
+        }
+    }
+
+    // Measures the time to the first byte from the target, only the first call counts.
+    pub fn answered(&self) {
+        if !self.0.answered.load(Ordering::Relaxed) && !self.0.answered.swap(true, Ordering::Relaxed) {
+            self.0.latency.first_byte.record(self.0.opened.elapsed());
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TopTalkers {
+    pub clients: Vec<TalkerStatus>,
+    pub targets: Vec<TalkerStatus>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TalkerStatus {
+    // client ip address or target
+    pub addr: String,
+    pub bytes: u64,
+    // bytes inherited from the lighter client or target this one took the place of, `bytes` is too
+    // high by at most this much
+    pub overcount: u64,
+    pub connections: u64,
+    pub sessions: u64,
+    // connections and sessions open right now
+    pub active: u64,
+}
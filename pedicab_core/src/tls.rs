@@ -0,0 +1,160 @@
+use std::{io, sync::Arc, time::Duration};
+
+use anyhow::{Context, anyhow};
+use pedicab_db::data::rule::{Endpoint, RuleTls, RuleTlsClient, RuleTlsServer};
+use rustls::{
+    ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{CryptoProvider, ring, verify_tls12_signature, verify_tls13_signature},
+};
+use rustls_pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime, pem::PemObject};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::stream::BoxedStream;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// TLS settings of a rule, built once when the rule starts.
+pub struct TlsLayer {
+    acceptor: Option<TlsAcceptor>,
+    connector: Option<(TlsConnector, Option<ServerName<'static>>)>,
+}
+
+impl TlsLayer {
+    pub fn new(tls: &RuleTls) -> anyhow::Result<Self> {
+        let provider = Arc::new(ring::default_provider());
+
+        let acceptor = match &tls.server {
+            Some(server) => Some(build_acceptor(server, provider.clone()).context("invalid tls server settings")?),
+            None => None,
+        };
+        let connector = match &tls.client {
+            Some(client) => Some(build_connector(client, provider).context("invalid tls client settings")?),
+            None => None,
+        };
+
+        Ok(TlsLayer { acceptor, connector })
+    }
+
+    // whether client connections are TLS terminated
+    pub fn terminates(&self) -> bool {
+        self.acceptor.is_some()
+    }
+
+    // terminates TLS from the client if configured, otherwise hands the stream back as is
+    pub async fn accept(&self, stream: BoxedStream) -> io::Result<BoxedStream> {
+        let Some(acceptor) = &self.acceptor else {
+            return Ok(stream);
+        };
+
+        let stream = with_timeout(acceptor.accept(stream)).await?;
+        Ok(Box::new(stream))
+    }
+
+    // wraps the connection to the target in TLS if configured
+    pub async fn connect(&self, stream: BoxedStream, target: &Endpoint) -> io::Result<BoxedStream> {
+        let Some((connector, sni)) = &self.connector else {
+            return Ok(stream);
+        };
+
+        let server_name = match (sni, target) {
+            (Some(sni), _) => sni.clone(),
+            (None, Endpoint::Inet(addr)) => ServerName::IpAddress(addr.ip().into()),
+            (None, Endpoint::Unix(_)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "sni is required for unix socket targets",
+                ));
+            }
+        };
+
+        let stream = with_timeout(connector.connect(server_name, stream)).await?;
+        Ok(Box::new(stream))
+    }
+}
+
+async fn with_timeout<T>(handshake: impl Future<Output = io::Result<T>>) -> io::Result<T> {
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "tls handshake timed out"))?
+}
+
+fn build_acceptor(server: &RuleTlsServer, provider: Arc<CryptoProvider>) -> anyhow::Result<TlsAcceptor> {
+    let certificates = CertificateDer::pem_slice_iter(server.certificate.as_bytes())
+        .collect::<Result<Vec<_>, _>>()
+        .context("failed to parse certificate")?;
+    if certificates.is_empty() {
+        return Err(anyhow!("no certificate found"));
+    }
+    let key = PrivateKeyDer::from_pem_slice(server.key.as_bytes()).context("failed to parse private key")?;
+
+    let config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certificates, key)?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn build_connector(
+    client: &RuleTlsClient, provider: Arc<CryptoProvider>,
+) -> anyhow::Result<(TlsConnector, Option<ServerName<'static>>)> {
+    let sni = match &client.sni {
+        Some(sni) => Some(ServerName::try_from(sni.clone()).context("invalid sni")?),
+        None => None,
+    };
+
+    let builder = ClientConfig::builder_with_provider(provider.clone()).with_safe_default_protocol_versions()?;
+
+    let config = if client.insecure {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerification(provider)))
+            .with_no_client_auth()
+    } else {
+        let mut roots = RootCertStore::empty();
+        match &client.ca {
+            Some(ca) => {
+                for certificate in CertificateDer::pem_slice_iter(ca.as_bytes()) {
+                    roots.add(certificate.context("failed to parse ca certificate")?)?;
+                }
+                if roots.is_empty() {
+                    return Err(anyhow!("no ca certificate found"));
+                }
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+        builder.with_root_certificates(roots).with_no_client_auth()
+    };
+
+    Ok((TlsConnector::from(Arc::new(config)), sni))
+}
+
+// Accepts any target certificate while still checking handshake signatures, used with `insecure`.
+#[derive(Debug)]
+struct NoVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self, _end_entity: &CertificateDer<'_>, _intermediates: &[CertificateDer<'_>], _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8], _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
@@ -0,0 +1,205 @@
+use std::net::SocketAddr;
+
+#[cfg(target_os = "linux")]
+pub use linux::*;
+#[cfg(not(target_os = "linux"))]
+pub use unsupported::*;
+
+// Whether `addr` is the address a socket bound to `bound_addr` listens on. Such traffic was not
+// redirected, so forwarding it to its original destination would loop back to the listener.
+pub fn is_bound_to(bound_addr: SocketAddr, addr: SocketAddr) -> bool {
+    bound_addr == addr || (bound_addr.ip().is_unspecified() && bound_addr.port() == addr.port())
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::{
+        io::{self, IoSliceMut},
+        net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+        os::fd::AsRawFd,
+    };
+
+    use nix::sys::socket::{ControlMessageOwned, MsgFlags, SockaddrLike, SockaddrStorage, recvmsg};
+    use socket2::{Domain, Protocol, Socket, Type};
+    use tokio::{
+        io::Interest,
+        net::{TcpListener, TcpSocket, TcpStream, UdpSocket},
+    };
+
+    // Transparent sockets may bind non-local addresses, which is what TPROXY redirected traffic and
+    // source address spoofing need. Requires CAP_NET_ADMIN.
+    fn new_transparent_socket(addr: &SocketAddr, ty: Type, protocol: Protocol) -> io::Result<Socket> {
+        let socket = Socket::new(Domain::for_address(*addr), ty, Some(protocol))?;
+
+        match addr {
+            SocketAddr::V4(_) => socket.set_ip_transparent_v4(true)?,
+            SocketAddr::V6(_) => set_bool_option(&socket, libc::SOL_IPV6, libc::IPV6_TRANSPARENT)?,
+        }
+
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+
+        Ok(socket)
+    }
+
+    fn set_bool_option(socket: &Socket, level: libc::c_int, name: libc::c_int) -> io::Result<()> {
+        let value: libc::c_int = 1;
+
+        // SAFETY: `value` outlives the call and its exact size is passed along
+        let ret = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                &value as *const libc::c_int as *const libc::c_void,
+                size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+
+        if ret == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn bind_tcp_listener(addr: SocketAddr) -> io::Result<TcpListener> {
+        let socket = new_transparent_socket(&addr, Type::STREAM, Protocol::TCP)?;
+
+        socket.bind(&addr.into())?;
+        socket.listen(1024)?;
+
+        TcpListener::from_std(socket.into())
+    }
+
+    pub async fn connect_tcp(target: SocketAddr, source: SocketAddr) -> io::Result<TcpStream> {
+        let source = SocketAddr::new(source.ip().to_canonical(), 0);
+        if source.is_ipv4() != target.is_ipv4() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "client and target address families differ",
+            ));
+        }
+
+        let socket = new_transparent_socket(&source, Type::STREAM, Protocol::TCP)?;
+        socket.bind(&source.into())?;
+
+        TcpSocket::from_std_stream(socket.into()).connect(target).await
+    }
+
+    // Listener socket reporting the original destination of every datagram, see `recv_from_original`.
+    pub fn bind_udp_listener(addr: SocketAddr) -> io::Result<UdpSocket> {
+        let socket = new_transparent_socket(&addr, Type::DGRAM, Protocol::UDP)?;
+
+        set_bool_option(&socket, libc::SOL_IP, libc::IP_RECVORIGDSTADDR)?;
+        if addr.is_ipv6() {
+            set_bool_option(&socket, libc::SOL_IPV6, libc::IPV6_RECVORIGDSTADDR)?;
+        }
+
+        socket.bind(&addr.into())?;
+
+        UdpSocket::from_std(socket.into())
+    }
+
+    // Socket bound to a possibly non-local address, used to answer clients from the address they sent
+    // to and to reach targets from the client address.
+    pub fn bind_udp_socket(addr: SocketAddr) -> io::Result<UdpSocket> {
+        let socket = new_transparent_socket(&addr, Type::DGRAM, Protocol::UDP)?;
+
+        socket.bind(&addr.into())?;
+
+        UdpSocket::from_std(socket.into())
+    }
+
+    // Receive a datagram along with its source and original destination address.
+    pub async fn recv_from_original(
+        socket: &UdpSocket, buf: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr, Option<SocketAddr>)> {
+        socket
+            .async_io(Interest::READABLE, || {
+                let mut iov = [IoSliceMut::new(buf)];
+                let mut cmsg_buffer = nix::cmsg_space!(libc::sockaddr_in6);
+
+                let msg = recvmsg::<SockaddrStorage>(
+                    socket.as_raw_fd(),
+                    &mut iov,
+                    Some(&mut cmsg_buffer),
+                    MsgFlags::empty(),
+                )?;
+
+                let source = msg
+                    .address
+                    .as_ref()
+                    .and_then(to_socket_addr)
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "datagram without source address"))?;
+
+                let mut destination = None;
+                for cmsg in msg.cmsgs()? {
+                    match cmsg {
+                        ControlMessageOwned::Ipv4OrigDstAddr(addr) => {
+                            destination = Some(SocketAddr::V4(SocketAddrV4::new(
+                                Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                                u16::from_be(addr.sin_port),
+                            )));
+                        }
+                        ControlMessageOwned::Ipv6OrigDstAddr(addr) => {
+                            destination = Some(SocketAddr::V6(SocketAddrV6::new(
+                                Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                                u16::from_be(addr.sin6_port),
+                                addr.sin6_flowinfo,
+                                addr.sin6_scope_id,
+                            )));
+                        }
+                        _ => {}
+                    }
+                }
+
+                Ok((msg.bytes, source, destination))
+            })
+            .await
+    }
+
+    fn to_socket_addr(addr: &SockaddrStorage) -> Option<SocketAddr> {
+        match addr.family()? {
+            nix::sys::socket::AddressFamily::Inet => addr.as_sockaddr_in().map(|addr| SocketAddr::from(*addr)),
+            nix::sys::socket::AddressFamily::Inet6 => addr.as_sockaddr_in6().map(|addr| SocketAddr::from(*addr)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod unsupported {
+    use std::{io, net::SocketAddr};
+
+    use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+    fn unsupported() -> io::Error {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            "transparent mode is only supported on linux",
+        )
+    }
+
+    pub fn bind_tcp_listener(_addr: SocketAddr) -> io::Result<TcpListener> {
+        Err(unsupported())
+    }
+
+    pub async fn connect_tcp(_target: SocketAddr, _source: SocketAddr) -> io::Result<TcpStream> {
+        Err(unsupported())
+    }
+
+    pub fn bind_udp_listener(_addr: SocketAddr) -> io::Result<UdpSocket> {
+        Err(unsupported())
+    }
+
+    pub fn bind_udp_socket(_addr: SocketAddr) -> io::Result<UdpSocket> {
+        Err(unsupported())
+    }
+
+    pub async fn recv_from_original(
+        _socket: &UdpSocket, _buf: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr, Option<SocketAddr>)> {
+        Err(unsupported())
+    }
+}
@@ -0,0 +1,1322 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{
+        Arc, Mutex, Weak,
+        atomic::{AtomicU32, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use bytes::Bytes;
+use pedicab_db::data::rule::{
+    Endpoint, IpNetwork, RuleAddr, RulePeer, RuleStats, RuleStatsConnections, RuleTargetState, SocketAddrRange,
+};
+use ring::{
+    aead::{Aad, CHACHA20_POLY1305, LessSafeKey, Nonce, UnboundKey},
+    agreement::{self, EphemeralPrivateKey, UnparsedPublicKey, X25519},
+    hkdf::{HKDF_SHA256, Salt},
+    rand::SystemRandom,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf},
+    net::{TcpStream, UdpSocket},
+    sync::{Semaphore, mpsc, oneshot},
+    task::JoinHandle,
+};
+use tracing::{Span, debug, info_span, trace, warn};
+
+use crate::{
+    balancer::Candidate,
+    proxy,
+    socks::{self, Address},
+    stream::BoxedStream,
+    tcp::{self, TcpForward},
+};
+
+// Encrypted tunnels between pedicab instances. The sending side opens a single connection per peer,
+// authenticated by a shared key, and multiplexes the tcp connections and udp sessions of its rules
+// over it as streams. The receiving side, a tunnel rule, connects each stream to the destination
+// the sender asked for.
+//
+// Both ends exchange ephemeral X25519 keys and derive one ChaCha20-Poly1305 key per direction from
+// the shared secret and the shared key, so only a holder of the key can complete the handshake.
+// Records are a big endian u16 length followed by the sealed frame. A frame is a type byte, a big
+// endian u32 stream id and a body depending on the type.
+//
+// Reverse tunnels turn the roles around for targets behind NAT: a reverse rule dials out to the
+// tunnel rule of a public pedicab and registers there under a name, along with the targets it
+// exposes. Rules of the public pedicab naming that agent open their streams over the registered
+// connection, and the reverse rule connects them to its targets.
+
+const MAGIC: &[u8; 4] = b"PDCT";
+const VERSION: u8 = 1;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// body is the registration of an agent, empty for everyone else
+const FRAME_HELLO: u8 = 0;
+// body is the stream kind followed by the destination in SOCKS5 address format
+const FRAME_OPEN: u8 = 1;
+const FRAME_OPENED: u8 = 2;
+const FRAME_DATA: u8 = 3;
+// body is a big endian u32 of bytes the receiver consumed
+const FRAME_WINDOW: u8 = 4;
+// no more data from the sender, tcp streams only
+const FRAME_CLOSE: u8 = 5;
+const FRAME_RESET: u8 = 6;
+
+const KIND_TCP: u8 = 1;
+const KIND_UDP: u8 = 2;
+
+// bytes a tcp stream may send ahead of the receiver consuming them
+const STREAM_WINDOW: usize = 256 * 1024;
+const MAX_CHUNK_SIZE: usize = 16 * 1024;
+const FRAME_HEADER_SIZE: usize = 5;
+const TAG_SIZE: usize = 16;
+// largest body a frame may carry, sealed frames have to fit the u16 record length
+const MAX_FRAME_BODY_SIZE: usize = u16::MAX as usize - FRAME_HEADER_SIZE - TAG_SIZE;
+// events queued for a stream, udp datagrams beyond it are dropped
+const STREAM_QUEUE_SIZE: usize = 1024;
+// udp sessions without traffic are closed after this long, like on udp rules
+const DATAGRAM_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+// delays between attempts of a reverse rule to reach the public pedicab
+const REDIAL_DELAY_MIN: Duration = Duration::from_secs(1);
+const REDIAL_DELAY_MAX: Duration = Duration::from_secs(30);
+
+struct Frame {
+    kind: u8,
+    id: u32,
+    body: Bytes,
+}
+
+impl Frame {
+    fn new(kind: u8, id: u32, body: impl Into<Bytes>) -> Self {
+        Frame {
+            kind,
+            id,
+            body: body.into(),
+        }
+    }
+
+    fn empty(kind: u8, id: u32) -> Self {
+        Frame::new(kind, id, Bytes::new())
+    }
+}
+
+enum Event {
+    Data(Bytes),
+    Close,
+    Reset,
+}
+
+struct Slot {
+    events: mpsc::Sender<Event>,
+    // credit for sending data, refilled by window frames of the receiver
+    window: Arc<Semaphore>,
+    datagrams: bool,
+    opened: Option<oneshot::Sender<bool>>,
+}
+
+type Slots = Arc<Mutex<HashMap<u32, Slot>>>;
+
+// A stream the peer asked to open, to be accepted or reset by the rule serving the connection.
+struct OpenRequest {
+    id: u32,
+    kind: u8,
+    address: Address,
+}
+
+// One direction of the record layer.
+struct RecordKey {
+    key: LessSafeKey,
+    counter: u64,
+}
+
+impl RecordKey {
+    fn next_nonce(&mut self) -> Nonce {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&self.counter.to_be_bytes());
+        self.counter += 1;
+        Nonce::assume_unique_for_key(nonce)
+    }
+}
+
+async fn write_record<W>(writer: &mut W, key: &mut RecordKey, frame: &Frame) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    // checked before a nonce is taken, the peer would not be able to open the next records otherwise
+    if frame.body.len() > MAX_FRAME_BODY_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "tunnel frame is too large"));
+    }
+
+    let mut record = Vec::with_capacity(2 + FRAME_HEADER_SIZE + frame.body.len() + TAG_SIZE);
+    record.extend_from_slice(&[0, 0, frame.kind]);
+    record.extend_from_slice(&frame.id.to_be_bytes());
+    record.extend_from_slice(&frame.body);
+
+    let mut sealed = record.split_off(2);
+    let nonce = key.next_nonce();
+    key.key
+        .seal_in_place_append_tag(nonce, Aad::empty(), &mut sealed)
+        .map_err(|_| io::Error::other("failed to seal tunnel record"))?;
+
+    record[..2].copy_from_slice(&(sealed.len() as u16).to_be_bytes());
+    record.extend_from_slice(&sealed);
+
+    writer.write_all(&record).await
+}
+
+async fn read_record<R>(reader: &mut R, key: &mut RecordKey) -> io::Result<Frame>
+where
+    R: AsyncRead + Unpin,
+{
+    let length = reader.read_u16().await? as usize;
+    let mut record = vec![0u8; length];
+    reader.read_exact(&mut record).await?;
+
+    let nonce = key.next_nonce();
+    let frame = key
+        .key
+        .open_in_place(nonce, Aad::empty(), &mut record)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "failed to open tunnel record"))?;
+    if frame.len() < FRAME_HEADER_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "tunnel frame is too short"));
+    }
+
+    Ok(Frame {
+        kind: frame[0],
+        id: u32::from_be_bytes([frame[1], frame[2], frame[3], frame[4]]),
+        body: Bytes::copy_from_slice(&frame[FRAME_HEADER_SIZE..]),
+    })
+}
+
+// Exchanges ephemeral keys and derives the record keys of both directions, the first one for the
+// records sent by this side.
+async fn handshake(stream: &mut BoxedStream, key: &str, initiator: bool) -> io::Result<(RecordKey, RecordKey)> {
+    let rng = SystemRandom::new();
+    let private_key = EphemeralPrivateKey::generate(&X25519, &rng)
+        .map_err(|_| io::Error::other("failed to generate tunnel key pair"))?;
+    let public_key = private_key
+        .compute_public_key()
+        .map_err(|_| io::Error::other("failed to generate tunnel key pair"))?;
+
+    let mut peer_public_key = [0u8; 32];
+    if initiator {
+        let mut hello = MAGIC.to_vec();
+        hello.push(VERSION);
+        hello.extend_from_slice(public_key.as_ref());
+        stream.write_all(&hello).await?;
+        stream.read_exact(&mut peer_public_key).await?;
+    } else {
+        let mut hello = [0u8; 5];
+        stream.read_exact(&mut hello).await?;
+        if &hello[..4] != MAGIC || hello[4] != VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "peer does not speak the tunnel protocol",
+            ));
+        }
+        stream.read_exact(&mut peer_public_key).await?;
+        stream.write_all(public_key.as_ref()).await?;
+    }
+
+    let (initiator_key, responder_key) = match initiator {
+        true => (public_key.as_ref(), &peer_public_key[..]),
+        false => (&peer_public_key[..], public_key.as_ref()),
+    };
+    let derive = |secret: &[u8], label: &[u8]| {
+        let info = [label, initiator_key, responder_key];
+        let prk = Salt::new(HKDF_SHA256, key.as_bytes()).extract(secret);
+        let okm = prk.expand(&info, &CHACHA20_POLY1305).map_err(|_| ())?;
+        Ok::<_, ()>(RecordKey {
+            key: LessSafeKey::new(UnboundKey::from(okm)),
+            counter: 0,
+        })
+    };
+
+    let (initiator_record_key, responder_record_key) = agreement::agree_ephemeral(
+        private_key,
+        &UnparsedPublicKey::new(&X25519, peer_public_key),
+        |secret| {
+            Ok::<_, ()>((
+                derive(secret, b"pedicab tunnel initiator")?,
+                derive(secret, b"pedicab tunnel responder")?,
+            ))
+        },
+    )
+    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid tunnel public key"))?
+    .map_err(|_| io::Error::other("failed to derive tunnel keys"))?;
+
+    Ok(match initiator {
+        true => (initiator_record_key, responder_record_key),
+        false => (responder_record_key, initiator_record_key),
+    })
+}
+
+// Proves to the peer that this side holds the shared key, and checks that the peer does: records
+// sealed with a key derived from another shared key fail to open. Resolves to the HELLO body of the
+// peer.
+async fn confirm(
+    reader: &mut ReadHalf<BoxedStream>, writer: &mut WriteHalf<BoxedStream>, sealing_key: &mut RecordKey,
+    opening_key: &mut RecordKey, hello: Bytes,
+) -> io::Result<Bytes> {
+    write_record(writer, sealing_key, &Frame::new(FRAME_HELLO, 0, hello)).await?;
+
+    match read_record(reader, opening_key).await {
+        Ok(frame) if frame.kind == FRAME_HELLO => Ok(frame.body),
+        Ok(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected tunnel frame")),
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "peer uses another tunnel key",
+        )),
+        Err(e) => Err(e),
+    }
+}
+
+// A multiplexed tunnel connection, closed once dropped.
+struct Connection {
+    frames: mpsc::Sender<Frame>,
+    slots: Slots,
+    // initiators use odd stream ids and responders even ones, so both sides can open streams
+    next_id: AtomicU32,
+    task: JoinHandle<()>,
+}
+
+// A connection along with the streams the peer opens on it and the HELLO body the peer sent.
+struct Established {
+    connection: Arc<Connection>,
+    opens: mpsc::Receiver<OpenRequest>,
+    hello: Bytes,
+}
+
+impl Connection {
+    // Sets up an authenticated connection and starts relaying its frames. Streams opened by the peer
+    // are handed out through the returned receiver, and reset once it is dropped.
+    async fn establish(
+        mut stream: BoxedStream, key: &str, initiator: bool, hello: Bytes, span: Span,
+    ) -> io::Result<Established> {
+        let (reader, writer, sealing_key, opening_key, peer_hello) = tokio::time::timeout(HANDSHAKE_TIMEOUT, async {
+            let (mut sealing_key, mut opening_key) = handshake(&mut stream, key, initiator).await?;
+            let (mut reader, mut writer) = tokio::io::split(stream);
+            let peer_hello = confirm(&mut reader, &mut writer, &mut sealing_key, &mut opening_key, hello).await?;
+            io::Result::Ok((reader, writer, sealing_key, opening_key, peer_hello))
+        })
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "tunnel handshake timed out"))??;
+
+        let (frames_tx, frames_rx) = mpsc::channel(256);
+        let (opens_tx, opens_rx) = mpsc::channel(256);
+        let slots: Slots = Arc::default();
+
+        let task = tokio::spawn({
+            let frames = frames_tx.clone();
+            let slots = slots.clone();
+
+            async move {
+                tokio::select! {
+                    result = write_frames(writer, sealing_key, frames_rx) => {
+                        if let Err(e) = result {
+                            debug!(parent: &span, "failed to write to tunnel: {}", e);
+                        }
+                    }
+                    result = read_frames(reader, opening_key, &slots, &frames, opens_tx) => {
+                        if let Err(e) = result {
+                            debug!(parent: &span, "failed to read from tunnel: {}", e);
+                        }
+                    }
+                }
+
+                // streams still open see the tunnel going away as a reset
+                let slots = std::mem::take(&mut *slots.lock().unwrap());
+                for (_, slot) in slots {
+                    slot.window.close();
+                    let _ = slot.events.try_send(Event::Reset);
+                }
+
+                debug!(parent: &span, "tunnel connection closed");
+            }
+        });
+
+        let connection = Connection {
+            frames: frames_tx,
+            slots,
+            next_id: AtomicU32::new(if initiator { 1 } else { 2 }),
+            task,
+        };
+
+        Ok(Established {
+            connection: Arc::new(connection),
+            opens: opens_rx,
+            hello: peer_hello,
+        })
+    }
+
+    fn is_closed(&self) -> bool {
+        self.task.is_finished()
+    }
+
+    fn register(&self, id: u32, datagrams: bool) -> (mpsc::Receiver<Event>, Arc<Semaphore>, oneshot::Receiver<bool>) {
+        let (events_tx, events_rx) = mpsc::channel(STREAM_QUEUE_SIZE);
+        let (opened_tx, opened_rx) = oneshot::channel();
+        let window = Arc::new(Semaphore::new(STREAM_WINDOW));
+
+        self.slots.lock().unwrap().insert(
+            id,
+            Slot {
+                events: events_tx,
+                window: window.clone(),
+                datagrams,
+                opened: Some(opened_tx),
+            },
+        );
+
+        (events_rx, window, opened_rx)
+    }
+
+    // Asks the peer to open a stream to a destination and waits for it to be connected.
+    async fn open(&self, kind: u8, target: SocketAddr) -> io::Result<(u32, mpsc::Receiver<Event>, Arc<Semaphore>)> {
+        let id = self.next_id.fetch_add(2, Ordering::Relaxed);
+        let (events, window, opened) = self.register(id, kind == KIND_UDP);
+
+        let mut body = vec![kind];
+        socks::write_addr(&mut body, target);
+        if self.frames.send(Frame::new(FRAME_OPEN, id, body)).await.is_err() {
+            self.slots.lock().unwrap().remove(&id);
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "tunnel connection closed"));
+        }
+
+        match opened.await {
+            Ok(true) => Ok((id, events, window)),
+            _ => {
+                self.slots.lock().unwrap().remove(&id);
+                Err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    format!("peer failed to connect to {}", target),
+                ))
+            }
+        }
+    }
+
+    // Accepts a stream opened by the peer once its destination is connected.
+    async fn accept(&self, id: u32, datagrams: bool) -> io::Result<(mpsc::Receiver<Event>, Arc<Semaphore>)> {
+        let (events, window, _) = self.register(id, datagrams);
+
+        if self.frames.send(Frame::empty(FRAME_OPENED, id)).await.is_err() {
+            self.slots.lock().unwrap().remove(&id);
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "tunnel connection closed"));
+        }
+
+        Ok((events, window))
+    }
+
+    async fn reject(&self, id: u32) {
+        let _ = self.frames.send(Frame::empty(FRAME_RESET, id)).await;
+    }
+
+    // Exposes a tcp stream as a regular stream, relayed to and from the tunnel by two tasks.
+    fn stream(&self, id: u32, mut events: mpsc::Receiver<Event>, window: Arc<Semaphore>) -> BoxedStream {
+        let (local, remote) = tokio::io::duplex(STREAM_WINDOW);
+        let (mut remote_reader, mut remote_writer) = tokio::io::split(remote);
+
+        // a stream failing on this side is reset, which the peer doesn't acknowledge, so its slot goes
+        // right away
+        let reset = {
+            let frames = self.frames.clone();
+            let slots = self.slots.clone();
+
+            move || {
+                if let Some(slot) = slots.lock().unwrap().remove(&id) {
+                    slot.window.close();
+                    let _ = frames.try_send(Frame::empty(FRAME_RESET, id));
+                }
+            }
+        };
+
+        let outbound = {
+            let frames = self.frames.clone();
+            let window = window.clone();
+            let reset = reset.clone();
+
+            async move {
+                let mut buffer = vec![0u8; MAX_CHUNK_SIZE];
+
+                loop {
+                    let n = match remote_reader.read(&mut buffer).await {
+                        Ok(0) => {
+                            let _ = frames.send(Frame::empty(FRAME_CLOSE, id)).await;
+                            break;
+                        }
+                        Ok(n) => n,
+                        Err(_) => {
+                            reset();
+                            break;
+                        }
+                    };
+
+                    // a closed window means the stream was reset
+                    match window.acquire_many(n as u32).await {
+                        Ok(permits) => permits.forget(),
+                        Err(_) => break,
+                    }
+
+                    if frames
+                        .send(Frame::new(FRAME_DATA, id, Bytes::copy_from_slice(&buffer[..n])))
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+            }
+        };
+
+        let inbound = {
+            let frames = self.frames.clone();
+
+            async move {
+                while let Some(event) = events.recv().await {
+                    match event {
+                        Event::Data(data) => {
+                            if remote_writer.write_all(&data).await.is_err() {
+                                reset();
+                                break;
+                            }
+
+                            let consumed = (data.len() as u32).to_be_bytes();
+                            let _ = frames.send(Frame::new(FRAME_WINDOW, id, consumed.to_vec())).await;
+                        }
+                        Event::Close => break,
+                        Event::Reset => {
+                            window.close();
+                            break;
+                        }
+                    }
+                }
+
+                let _ = remote_writer.shutdown().await;
+            }
+        };
+
+        let slots = self.slots.clone();
+        tokio::spawn(async move {
+            tokio::join!(outbound, inbound);
+            slots.lock().unwrap().remove(&id);
+        });
+
+        Box::new(local)
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn write_frames(
+    mut writer: WriteHalf<BoxedStream>, mut key: RecordKey, mut frames: mpsc::Receiver<Frame>,
+) -> io::Result<()> {
+    while let Some(frame) = frames.recv().await {
+        // a frame that can't be sealed is dropped rather than taking down every stream of the tunnel
+        match write_record(&mut writer, &mut key, &frame).await {
+            Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+                debug!("dropping tunnel frame for stream {}: {}", frame.id, e);
+            }
+            result => result?,
+        }
+    }
+
+    Ok(())
+}
+
+async fn read_frames(
+    mut reader: ReadHalf<BoxedStream>, mut key: RecordKey, slots: &Slots, frames: &mpsc::Sender<Frame>,
+    opens: mpsc::Sender<OpenRequest>,
+) -> io::Result<()> {
+    loop {
+        let frame = read_record(&mut reader, &mut key).await?;
+
+        if frame.kind == FRAME_OPEN {
+            let request = match frame.body.len() > 1 {
+                true => socks::read_addr(&mut &frame.body[2..], frame.body[1])
+                    .await
+                    .ok()
+                    .map(|address| OpenRequest {
+                        id: frame.id,
+                        kind: frame.body[0],
+                        address,
+                    }),
+                false => None,
+            };
+
+            // nobody takes the streams of a side that doesn't serve any
+            let accepted = match request {
+                Some(request) => opens.send(request).await.is_ok(),
+                None => false,
+            };
+            if !accepted {
+                let _ = frames.send(Frame::empty(FRAME_RESET, frame.id)).await;
+            }
+            continue;
+        }
+
+        // events are delivered outside of the lock, tcp ones may have to wait for room in the queue
+        let (events, event) = {
+            let mut slots = slots.lock().unwrap();
+            let Some(slot) = slots.get_mut(&frame.id) else {
+                continue;
+            };
+
+            match frame.kind {
+                FRAME_OPENED => {
+                    if let Some(opened) = slot.opened.take() {
+                        let _ = opened.send(true);
+                    }
+                    continue;
+                }
+                FRAME_WINDOW => {
+                    if let Ok(consumed) = <[u8; 4]>::try_from(&frame.body[..]) {
+                        slot.window.add_permits(u32::from_be_bytes(consumed) as usize);
+                    }
+                    continue;
+                }
+                FRAME_DATA if slot.datagrams => {
+                    let _ = slot.events.try_send(Event::Data(frame.body));
+                    continue;
+                }
+                FRAME_DATA => (slot.events.clone(), Event::Data(frame.body)),
+                FRAME_CLOSE => (slot.events.clone(), Event::Close),
+                FRAME_RESET => {
+                    let slot = slots.remove(&frame.id).unwrap();
+                    if let Some(opened) = slot.opened {
+                        let _ = opened.send(false);
+                    }
+                    slot.window.close();
+                    (slot.events, Event::Reset)
+                }
+                _ => continue,
+            }
+        };
+
+        let _ = events.send(event).await;
+    }
+}
+
+// A udp session carried through a tunnel, each frame holding one datagram.
+pub struct DatagramStream {
+    id: u32,
+    frames: mpsc::Sender<Frame>,
+    events: mpsc::Receiver<Event>,
+    slots: Slots,
+}
+
+impl DatagramStream {
+    // Datagrams too large for a frame are refused with `InvalidInput`, the session stays usable.
+    pub async fn send(&self, data: &[u8]) -> io::Result<()> {
+        if data.len() > MAX_FRAME_BODY_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "datagram is too large for the tunnel",
+            ));
+        }
+
+        self.frames
+            .send(Frame::new(FRAME_DATA, self.id, Bytes::copy_from_slice(data)))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "tunnel connection closed"))
+    }
+
+    // Resolves to `None` once the session is closed by the peer or the tunnel is gone.
+    pub async fn recv(&mut self) -> Option<Bytes> {
+        match self.events.recv().await? {
+            Event::Data(data) => Some(data),
+            Event::Close | Event::Reset => None,
+        }
+    }
+}
+
+impl Drop for DatagramStream {
+    fn drop(&mut self) {
+        if self.slots.lock().unwrap().remove(&self.id).is_some() {
+            let _ = self.frames.try_send(Frame::empty(FRAME_RESET, self.id));
+        }
+    }
+}
+
+type Agents = Arc<Mutex<HashMap<String, Agent>>>;
+
+// A reverse tunnel an agent registered with a tunnel rule of this instance.
+struct Agent {
+    connection: Weak<Connection>,
+    exposed: Vec<SocketAddrRange>,
+}
+
+// Tunnels to peers, shared by every rule carried to the same peer with the same key, and the
+// reverse tunnels of the agents registered with this instance. A tunnel to a peer is dropped along
+// with the last rule using it.
+#[derive(Clone, Default)]
+pub struct Peers {
+    links: Arc<Mutex<HashMap<RulePeer, Weak<PeerLink>>>>,
+    agents: Agents,
+}
+
+impl Peers {
+    pub fn get(&self, peer: &RulePeer) -> Arc<PeerLink> {
+        let mut links = self.links.lock().unwrap();
+        links.retain(|_, link| link.strong_count() > 0);
+
+        if let Some(link) = links.get(peer).and_then(Weak::upgrade) {
+            return link;
+        }
+
+        let link = Arc::new(PeerLink {
+            route: LinkRoute::Peer {
+                peer: peer.clone(),
+                connection: tokio::sync::Mutex::new(None),
+            },
+        });
+        links.insert(peer.clone(), Arc::downgrade(&link));
+        link
+    }
+
+    // The link back to an agent, usable whenever the agent is registered.
+    pub fn agent(&self, name: &str) -> Arc<PeerLink> {
+        Arc::new(PeerLink {
+            route: LinkRoute::Agent {
+                name: name.to_owned(),
+                agents: self.agents.clone(),
+            },
+        })
+    }
+
+    // A reconnecting agent takes its name over from the connection it lost.
+    fn register(&self, name: &str, connection: &Arc<Connection>, exposed: Vec<SocketAddrRange>) {
+        self.agents.lock().unwrap().insert(
+            name.to_owned(),
+            Agent {
+                connection: Arc::downgrade(connection),
+                exposed,
+            },
+        );
+    }
+
+    fn unregister(&self, name: &str, connection: &Arc<Connection>) {
+        let mut agents = self.agents.lock().unwrap();
+        if agents
+            .get(name)
+            .is_some_and(|agent| std::ptr::eq(agent.connection.as_ptr(), Arc::as_ptr(connection)))
+        {
+            agents.remove(name);
+        }
+    }
+}
+
+// The sending side of a tunnel, either to a peer or back to an agent.
+pub struct PeerLink {
+    route: LinkRoute,
+}
+
+enum LinkRoute {
+    // connected on first use and reconnected when it was lost
+    Peer {
+        peer: RulePeer,
+        connection: tokio::sync::Mutex<Option<Arc<Connection>>>,
+    },
+    // over the connection the agent registered
+    Agent {
+        name: String,
+        agents: Agents,
+    },
+}
+
+impl PeerLink {
+    async fn connection(&self, target: SocketAddr) -> io::Result<Arc<Connection>> {
+        let (peer, connection) = match &self.route {
+            LinkRoute::Peer { peer, connection } => (peer, connection),
+            LinkRoute::Agent { name, agents } => {
+                let agents = agents.lock().unwrap();
+                let registered = agents.get(name).and_then(|agent| {
+                    let connection = agent
+                        .connection
+                        .upgrade()
+                        .filter(|connection| !connection.is_closed())?;
+                    Some((agent, connection))
+                });
+                let Some((agent, connection)) = registered else {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotConnected,
+                        format!("agent {} is not connected", name),
+                    ));
+                };
+
+                // the agent refuses anything else as well, this only spares the round trip
+                if !agent.exposed.iter().any(|range| range.contains(target)) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        format!("agent {} does not expose {}", name, target),
+                    ));
+                }
+
+                return Ok(connection);
+            }
+        };
+
+        let mut connection = connection.lock().await;
+
+        if let Some(connection) = connection.as_ref().filter(|connection| !connection.is_closed()) {
+            return Ok(connection.clone());
+        }
+
+        let span = info_span!("peer_tunnel", peer_addr = peer.addr.to_string());
+
+        let established = dial(peer.addr, &peer.key, Bytes::new(), &span).await?.connection;
+        debug!(parent: &span, "tunnel connection established");
+
+        *connection = Some(established.clone());
+        Ok(established)
+    }
+
+    pub async fn open_stream(&self, target: &Endpoint) -> io::Result<BoxedStream> {
+        let Endpoint::Inet(target) = target else {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "unix sockets can't be reached through a tunnel",
+            ));
+        };
+
+        let connection = self.connection(*target).await?;
+        let (id, events, window) = connection.open(KIND_TCP, *target).await?;
+        Ok(connection.stream(id, events, window))
+    }
+
+    pub async fn open_datagrams(&self, target: SocketAddr) -> io::Result<DatagramStream> {
+        let connection = self.connection(target).await?;
+        let (id, events, _) = connection.open(KIND_UDP, target).await?;
+
+        Ok(DatagramStream {
+            id,
+            frames: connection.frames.clone(),
+            events,
+            slots: connection.slots.clone(),
+        })
+    }
+}
+
+// Connects to a tunnel rule as the initiator. Streams of the rule wait on the dial, so a peer
+// dropping packets has no longer to answer than to complete the handshake.
+async fn dial(addr: SocketAddr, key: &str, hello: Bytes, span: &Span) -> io::Result<Established> {
+    let stream = tokio::time::timeout(HANDSHAKE_TIMEOUT, TcpStream::connect(addr))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connecting to the tunnel peer timed out"))??;
+    if let Err(e) = stream.set_nodelay(true) {
+        debug!(parent: span, "failed to set nodelay for tunnel stream: {}", e);
+    }
+    let keepalive = socket2::TcpKeepalive::new()
+        .with_time(Duration::from_secs(20))
+        .with_interval(Duration::from_secs(20));
+    if let Err(e) = socket2::SockRef::from(&stream).set_tcp_keepalive(&keepalive) {
+        debug!(parent: span, "failed to set tcp keepalive for tunnel stream: {}", e);
+    }
+
+    Connection::establish(Box::new(stream), key, true, hello, span.clone()).await
+}
+
+// The HELLO body of an agent: its name with a one byte length, then every exposed address range as
+// a SOCKS5 address followed by the big endian last port of the range.
+fn encode_registration(name: &str, exposed: &[SocketAddrRange]) -> Bytes {
+    let mut body = vec![name.len() as u8];
+    body.extend_from_slice(name.as_bytes());
+
+    for range in exposed {
+        socks::write_addr(&mut body, range.addr);
+        body.extend_from_slice(&range.port_end.to_be_bytes());
+    }
+
+    body.into()
+}
+
+async fn decode_registration(body: &[u8]) -> io::Result<(String, Vec<SocketAddrRange>)> {
+    let mut reader = body;
+
+    let mut name = vec![0u8; reader.read_u8().await? as usize];
+    reader.read_exact(&mut name).await?;
+    let name = String::from_utf8(name).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid agent name"))?;
+
+    let mut exposed = Vec::new();
+    while !reader.is_empty() {
+        let atyp = reader.read_u8().await?;
+        let Address::Ip(addr) = socks::read_addr(&mut reader, atyp).await? else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid exposed address"));
+        };
+        let port_end = reader.read_u16().await?;
+
+        exposed.push(SocketAddrRange { addr, port_end });
+    }
+
+    Ok((name, exposed))
+}
+
+// Destinations the peer of a connection may open streams to.
+enum Destinations {
+    // the networks allowed by a tunnel rule, anything if empty
+    Allowed(Vec<IpNetwork>),
+    // the targets of a reverse rule
+    Exposed(Vec<SocketAddrRange>),
+}
+
+impl Destinations {
+    async fn resolve(&self, address: &Address) -> io::Result<SocketAddr> {
+        match (self, address) {
+            (Destinations::Allowed(allow), _) => proxy::resolve(address, |ip| proxy::allows(allow, ip)).await,
+            (Destinations::Exposed(exposed), Address::Ip(addr))
+                if exposed.iter().any(|range| range.contains(*addr)) =>
+            {
+                Ok(*addr)
+            }
+            (Destinations::Exposed(_), _) => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "destination is not exposed",
+            )),
+        }
+    }
+}
+
+// Serves a tunnel connection accepted by a tunnel rule, connecting the streams the peer opens to
+// the destinations it asks for. Agents connecting with a registration are available to the rules of
+// this instance for as long as the connection lasts.
+pub async fn handle_connection(stream: BoxedStream, peer_addr: Option<SocketAddr>, forward: Arc<TcpForward>) {
+    let span = info_span!(
+        "handle_tunnel_connection",
+        rule_id = forward.rule.id.as_uuid().to_string(),
+        peer_addr = peer_addr.map(|addr| addr.to_string())
+    );
+
+    let Some(settings) = forward.rule.config.tunnel.clone() else {
+        return;
+    };
+
+    let Established {
+        connection,
+        opens,
+        hello,
+    } = match Connection::establish(stream, &settings.key, false, Bytes::new(), span.clone()).await {
+        Ok(established) => established,
+        Err(e) => {
+            warn!(parent: &span, "tunnel handshake with peer failed: {}", e);
+            return;
+        }
+    };
+
+    let agent = match hello.is_empty() {
+        true => None,
+        false => match decode_registration(&hello).await {
+            Ok((name, exposed)) => {
+                forward.peers.register(&name, &connection, exposed);
+                Some(name)
+            }
+            Err(e) => {
+                warn!(parent: &span, "invalid registration from agent: {}", e);
+                return;
+            }
+        },
+    };
+
+    match &agent {
+        Some(name) => debug!(parent: &span, "agent {} registered", name),
+        None => debug!(parent: &span, "tunnel connection accepted"),
+    }
+
+    let destinations = Arc::new(Destinations::Allowed(settings.allow));
+    serve_streams(&connection, opens, destinations, peer_addr, &forward, &span).await;
+
+    if let Some(name) = agent {
+        forward.peers.unregister(&name, &connection);
+        debug!(parent: &span, "agent {} disconnected", name);
+    }
+}
+
+// Keeps the reverse tunnel of a reverse rule registered with the public pedicab, connecting the
+// streams its rules open to the targets of the rule.
+pub async fn serve_reverse(forward: Arc<TcpForward>) {
+    let Some(settings) = forward.rule.config.reverse.clone() else {
+        return;
+    };
+
+    let span = info_span!(
+        "reverse_tunnel",
+        rule_id = forward.rule.id.as_uuid().to_string(),
+        public_addr = settings.addr.to_string()
+    );
+
+    let exposed: Vec<SocketAddrRange> = forward
+        .rule
+        .target
+        .addrs
+        .iter()
+        // draining and disabled targets are not offered to new connections
+        .filter(|target| target.state == RuleTargetState::Active)
+        .filter_map(|target| match &target.addr {
+            RuleAddr::Inet(range) => Some(*range),
+            RuleAddr::Unix(_) => None,
+        })
+        .collect();
+    let hello = encode_registration(&settings.name, &exposed);
+    let destinations = Arc::new(Destinations::Exposed(exposed));
+
+    let mut delay = REDIAL_DELAY_MIN;
+
+    loop {
+        match dial(settings.addr, &settings.key, hello.clone(), &span).await {
+            Ok(Established { connection, opens, .. }) => {
+                debug!(parent: &span, "registered as agent {}", settings.name);
+                delay = REDIAL_DELAY_MIN;
+
+                serve_streams(
+                    &connection,
+                    opens,
+                    destinations.clone(),
+                    Some(settings.addr),
+                    &forward,
+                    &span,
+                )
+                .await;
+                warn!(parent: &span, "lost the reverse tunnel, reconnecting");
+            }
+            Err(e) => {
+                warn!(parent: &span, "failed to reach the public pedicab: {}", e);
+            }
+        }
+
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(REDIAL_DELAY_MAX);
+    }
+}
+
+// Connects the streams the peer opens to their destinations, until the connection is gone.
+async fn serve_streams(
+    connection: &Arc<Connection>, mut opens: mpsc::Receiver<OpenRequest>, destinations: Arc<Destinations>,
+    peer_addr: Option<SocketAddr>, forward: &Arc<TcpForward>, span: &Span,
+) {
+    while let Some(OpenRequest { id, kind, address }) = opens.recv().await {
+        let connection = connection.clone();
+        let destinations = destinations.clone();
+        let forward = forward.clone();
+        let span = span.clone();
+
+        tokio::spawn(async move {
+            let target_addr = match destinations.resolve(&address).await {
+                Ok(target_addr) => target_addr,
+                Err(e) => {
+                    debug!(parent: &span, "refusing stream to {}: {}", address, e);
+                    connection.reject(id).await;
+                    return;
+                }
+            };
+
+            match kind {
+                KIND_TCP => accept_stream(&connection, id, target_addr, peer_addr, &forward, &span).await,
+                KIND_UDP => accept_datagrams(&connection, id, target_addr, peer_addr, &forward, &span).await,
+                _ => connection.reject(id).await,
+            }
+        });
+    }
+}
+
+async fn accept_stream(
+    connection: &Connection, id: u32, target_addr: SocketAddr, peer_addr: Option<SocketAddr>, forward: &TcpForward,
+    span: &Span,
+) {
+    let target_addr = Endpoint::Inet(target_addr);
+
+    let server_stream = match forward.connect(&target_addr, None, span).await {
+        Ok(server_stream) => server_stream,
+        Err(e) => {
+            debug!(parent: span, "failed to connect to {}: {}", target_addr, e);
+            connection.reject(id).await;
+            return;
+        }
+    };
+
+    let Ok((events, window)) = connection.accept(id, false).await else {
+        return;
+    };
+
+    trace!(parent: span, "tunnel stream connected to {}", target_addr);
+
+    let client_stream = connection.stream(id, events, window);
+    tcp::relay(
+        client_stream,
+        server_stream,
+        peer_addr,
+        Candidate::fixed(target_addr).lease(),
+        None,
+        forward,
+        span,
+    )
+    .await;
+}
+
+async fn accept_datagrams(
+    connection: &Connection, id: u32, target_addr: SocketAddr, peer_addr: Option<SocketAddr>, forward: &TcpForward,
+    span: &Span,
+) {
+    let bind_addr: SocketAddr = match target_addr {
+        SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+        SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+    };
+    let socket = match UdpSocket::bind(bind_addr).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            debug!(parent: span, "failed to bind udp socket for {}: {}", target_addr, e);
+            connection.reject(id).await;
+            return;
+        }
+    };
+    if let Err(e) = socket.connect(target_addr).await {
+        debug!(parent: span, "failed to connect to {}: {}", target_addr, e);
+        connection.reject(id).await;
+        return;
+    }
+
+    let Ok((events, _)) = connection.accept(id, true).await else {
+        return;
+    };
+    let mut datagrams = DatagramStream {
+        id,
+        frames: connection.frames.clone(),
+        events,
+        slots: connection.slots.clone(),
+    };
+
+    trace!(parent: span, "tunnel udp session connected to {}", target_addr);
+
+    let rule_id = forward.rule.id.as_uuid();
+    let stats_cache = &forward.stats_cache;
+
+    {
+        let prev_stats = stats_cache.get(&rule_id).await.unwrap_or_default();
+        stats_cache
+            .insert(
+                rule_id,
+                RuleStats {
+                    connections: RuleStatsConnections {
+                        udp: prev_stats.connections.udp + 1,
+                        ..prev_stats.connections
+                    },
+                    ..prev_stats
+                },
+            )
+            .await;
+    }
+
+    let transferred_bytes = Arc::new(AtomicU64::new(0));
+    let stats_updater = tcp::spawn_stats_updater(
+        rule_id,
+        transferred_bytes.clone(),
+        stats_cache.clone(),
+        Duration::from_millis(forward.config.stats_update_interval),
+    );
+    let meter = forward
+        .telemetry
+        .open_session(peer_addr, Some(&Endpoint::Inet(target_addr)));
+
+    let mut buffer = vec![0u8; 65535];
+
+    loop {
+        tokio::select! {
+            data = datagrams.recv() => {
+                let Some(data) = data else { break };
+                if socket.send(&data).await.is_ok() {
+                    transferred_bytes.fetch_add(data.len() as u64, Ordering::Relaxed);
+                    meter.add(data.len() as u64);
+                }
+            }
+
+            received = socket.recv(&mut buffer) => {
+                let Ok(size) = received else { break };
+                if size > MAX_FRAME_BODY_SIZE {
+                    trace!(parent: span, "dropping datagram from {}, too large for the tunnel", target_addr);
+                    continue;
+                }
+                if datagrams.send(&buffer[..size]).await.is_err() {
+                    break;
+                }
+                transferred_bytes.fetch_add(size as u64, Ordering::Relaxed);
+                meter.add(size as u64);
+                meter.answered();
+            }
+
+            _ = tokio::time::sleep(DATAGRAM_IDLE_TIMEOUT) => {
+                trace!(parent: span, "tunnel udp session to {} timed out", target_addr);
+                break;
+            }
+        }
+    }
+
+    stats_updater.abort();
+
+    {
+        let prev_stats = stats_cache.get(&rule_id).await.unwrap_or_default();
+        stats_cache
+            .insert(
+                rule_id,
+                RuleStats {
+                    // bytes the updater did not get to fold in before it was stopped
+                    bandwidth: prev_stats.bandwidth + transferred_bytes.swap(0, Ordering::Relaxed),
+                    connections: RuleStatsConnections {
+                        udp: prev_stats.connections.udp - 1,
+                        ..prev_stats.connections
+                    },
+                    ..prev_stats
+                },
+            )
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs the handshake of both ends over an in-memory pipe, resolving to their streams and record
+    // keys.
+    async fn handshake_pair(
+        initiator_key: &str, responder_key: &str,
+    ) -> ((BoxedStream, RecordKey, RecordKey), (BoxedStream, RecordKey, RecordKey)) {
+        let (initiator, responder) = tokio::io::duplex(4096);
+        let mut initiator: BoxedStream = Box::new(initiator);
+        let mut responder: BoxedStream = Box::new(responder);
+
+        let (initiator_keys, responder_keys) = tokio::join!(
+            handshake(&mut initiator, initiator_key, true),
+            handshake(&mut responder, responder_key, false)
+        );
+        let (initiator_sealing, initiator_opening) = initiator_keys.unwrap();
+        let (responder_sealing, responder_opening) = responder_keys.unwrap();
+
+        (
+            (initiator, initiator_sealing, initiator_opening),
+            (responder, responder_sealing, responder_opening),
+        )
+    }
+
+    #[tokio::test]
+    async fn records_round_trip_in_both_directions() {
+        let ((_, mut initiator_sealing, mut initiator_opening), (_, mut responder_sealing, mut responder_opening)) =
+            handshake_pair("shared key", "shared key").await;
+
+        let mut records = Vec::new();
+        write_record(
+            &mut records,
+            &mut initiator_sealing,
+            &Frame::new(FRAME_DATA, 7, &b"hello"[..]),
+        )
+        .await
+        .unwrap();
+        write_record(&mut records, &mut initiator_sealing, &Frame::empty(FRAME_CLOSE, 7))
+            .await
+            .unwrap();
+
+        let mut reader = &records[..];
+        let frame = read_record(&mut reader, &mut responder_opening).await.unwrap();
+        assert_eq!((frame.kind, frame.id, &frame.body[..]), (FRAME_DATA, 7, &b"hello"[..]));
+        let frame = read_record(&mut reader, &mut responder_opening).await.unwrap();
+        assert_eq!((frame.kind, frame.id, frame.body.len()), (FRAME_CLOSE, 7, 0));
+        assert!(reader.is_empty());
+
+        let mut records = Vec::new();
+        write_record(
+            &mut records,
+            &mut responder_sealing,
+            &Frame::new(FRAME_WINDOW, 9, &b"back"[..]),
+        )
+        .await
+        .unwrap();
+        let frame = read_record(&mut &records[..], &mut initiator_opening).await.unwrap();
+        assert_eq!((frame.kind, frame.id, &frame.body[..]), (FRAME_WINDOW, 9, &b"back"[..]));
+    }
+
+    #[tokio::test]
+    async fn nonce_counter_advances_once_per_record() {
+        let ((_, mut sealing, _), (_, _, mut opening)) = handshake_pair("shared key", "shared key").await;
+
+        let mut first = Vec::new();
+        write_record(&mut first, &mut sealing, &Frame::new(FRAME_DATA, 1, &b"first"[..]))
+            .await
+            .unwrap();
+        let mut second = Vec::new();
+        write_record(&mut second, &mut sealing, &Frame::new(FRAME_DATA, 1, &b"second"[..]))
+            .await
+            .unwrap();
+        assert_eq!(sealing.counter, 2);
+
+        // a record only opens with the nonce it was sealed with, so skipped or replayed ones fail
+        assert!(read_record(&mut &second[..], &mut opening).await.is_err());
+        assert_eq!(opening.counter, 1);
+        assert!(read_record(&mut &first[..], &mut opening).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn oversized_frames_are_refused_without_taking_a_nonce() {
+        let ((_, mut sealing, _), (_, _, mut opening)) = handshake_pair("shared key", "shared key").await;
+
+        let mut records = Vec::new();
+        let oversized = Frame::new(FRAME_DATA, 1, vec![0u8; MAX_FRAME_BODY_SIZE + 1]);
+        let e = write_record(&mut records, &mut sealing, &oversized).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(records.is_empty());
+        assert_eq!(sealing.counter, 0);
+
+        let largest = Frame::new(FRAME_DATA, 1, vec![1u8; MAX_FRAME_BODY_SIZE]);
+        write_record(&mut records, &mut sealing, &largest).await.unwrap();
+        let frame = read_record(&mut &records[..], &mut opening).await.unwrap();
+        assert_eq!(frame.body.len(), MAX_FRAME_BODY_SIZE);
+    }
+
+    #[tokio::test]
+    async fn peers_with_another_key_are_rejected() {
+        let (
+            (initiator, mut initiator_sealing, mut initiator_opening),
+            (responder, mut responder_sealing, mut responder_opening),
+        ) = handshake_pair("shared key", "another key").await;
+
+        let (mut initiator_reader, mut initiator_writer) = tokio::io::split(initiator);
+        let (mut responder_reader, mut responder_writer) = tokio::io::split(responder);
+        let (initiator_confirmed, responder_confirmed) = tokio::join!(
+            confirm(
+                &mut initiator_reader,
+                &mut initiator_writer,
+                &mut initiator_sealing,
+                &mut initiator_opening,
+                Bytes::new()
+            ),
+            confirm(
+                &mut responder_reader,
+                &mut responder_writer,
+                &mut responder_sealing,
+                &mut responder_opening,
+                Bytes::new()
+            )
+        );
+
+        assert_eq!(initiator_confirmed.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(responder_confirmed.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn tampered_records_are_rejected() {
+        let ((_, mut sealing, _), (_, _, mut opening)) = handshake_pair("shared key", "shared key").await;
+
+        let mut records = Vec::new();
+        write_record(&mut records, &mut sealing, &Frame::new(FRAME_DATA, 1, &b"payload"[..]))
+            .await
+            .unwrap();
+        let last = records.len() - 1;
+        records[last] ^= 0x01;
+
+        let opened = read_record(&mut &records[..], &mut opening).await;
+        assert!(matches!(opened, Err(e) if e.kind() == io::ErrorKind::InvalidData));
+    }
+}
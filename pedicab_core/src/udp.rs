@@ -1,5 +1,6 @@
 use std::{
     collections::HashMap,
+    io,
     net::SocketAddr,
     sync::{
         Arc,
@@ -11,63 +12,156 @@ use std::{
 use pedicab_cli::AgentConfig;
 use pedicab_db::{
     dal::DataAccessLayer,
-    data::rule::{RuleStats, RuleStatsConnections, RuleStatus},
+    data::rule::{Endpoint, RuleAddr, RuleStats, RuleStatsConnections, RuleTransparent, RuleUdpOverTcp, RuleUpstream},
     model::rule::Rule,
 };
-use tokio::{net::UdpSocket, sync::Mutex, time::Instant};
-use tracing::{debug, error, info_span, trace, warn};
-
-use crate::manager::StatsCache;
+use tokio::{
+    io::AsyncReadExt,
+    net::{TcpStream, UdpSocket},
+    sync::Mutex,
+    task::JoinSet,
+    time::Instant,
+};
+use tracing::{Span, debug, error, info_span, trace, warn};
+
+use crate::{
+    balancer::{Balancer, Candidate, TargetLease},
+    capture::RuleCapture,
+    manager::{Services, StatsCache},
+    mirror, socks, tcp,
+    telemetry::{RuleTelemetry, TrafficMeter},
+    transparent,
+    tunnel::{DatagramStream, PeerLink},
+    udp_over_tcp, upstream, utils,
+};
 
 struct UdpClient {
     sender: tokio::sync::mpsc::Sender<Vec<u8>>,
+    target_addr: SocketAddr,
     last_active: Instant,
+    mirror: Option<std::net::UdpSocket>,
+    meter: TrafficMeter,
+}
+
+struct UdpListener {
+    socket: Arc<UdpSocket>,
+    index: usize,
+    offset: usize,
+    mirror_addr: Option<SocketAddr>,
+}
+
+struct UdpSession {
+    // socket replies are sent to the client from
+    listener: Arc<UdpSocket>,
+    client_addr: SocketAddr,
+    target_addr: SocketAddr,
+    // also reports how the target did
+    target: Arc<TargetLease>,
+    source_addr: Option<SocketAddr>,
+    route: TargetRoute,
+    // counts the replies for the client and target
+    meter: TrafficMeter,
+}
+
+// How the sessions of a rule reach their targets.
+#[derive(Clone)]
+struct TargetRoute {
+    balancer: Arc<Balancer>,
+    via: TargetVia,
+    // carry the datagrams over a tcp connection with this buffer size instead, see `udp_over_tcp`
+    over_tcp: Option<usize>,
 }
 
-pub async fn start_udp_forward(rule: Rule, config: AgentConfig, dal: DataAccessLayer, stats_cache: StatsCache) {
+#[derive(Clone)]
+enum TargetVia {
+    Direct,
+    // through the UDP ASSOCIATE relay of an upstream socks5 proxy, or its CONNECT over tcp
+    Upstream(RuleUpstream),
+    // through the tunnel to a peer pedicab or back to an agent
+    Peer(Arc<PeerLink>),
+}
+
+// sessions are keyed by listener index, client address and, in transparent mode, original
+// destination
+type Clients = Arc<Mutex<HashMap<(usize, SocketAddr, Option<SocketAddr>), UdpClient>>>;
+
+// State shared by the receive loops and sessions of a rule.
+struct UdpForward {
+    transparent: Option<RuleTransparent>,
+    route: TargetRoute,
+    clients: Clients,
+    transferred_bytes: Arc<AtomicU64>,
+    capture: RuleCapture,
+    telemetry: Arc<RuleTelemetry>,
+}
+
+pub async fn start_udp_forward(
+    rule: Rule, config: AgentConfig, dal: DataAccessLayer, stats_cache: StatsCache, services: Services,
+) {
     let span = info_span!("start_udp_forward", rule_id = rule.id.as_uuid().to_string());
 
     let rule_id = rule.id.as_uuid();
-    let target_addr = rule.target.addrs[0];
-
-    let socket = match UdpSocket::bind(rule.listen).await {
-        Ok(socket) => socket,
-        Err(e) => {
-            error!(parent: &span, "failed to bind to {}: {}", rule.listen, e);
-
-            // handle non-retryable errors
-            {
-                let updated_stats = match stats_cache.get(&rule.id.into()).await {
-                    Some(current_stats) => RuleStats {
-                        last_failed_message: format!("failed to bind to {}", rule.listen),
-                        ..current_stats
-                    },
-                    None => RuleStats {
-                        last_failed_message: format!("failed to bind to {}", rule.listen),
-                        ..Default::default()
-                    },
-                };
-                let _ = dal.rule.update_status(rule.id.into(), RuleStatus::Error).await;
-                stats_cache.insert(rule.id.into(), updated_stats).await;
+
+    let mut listeners = Vec::new();
+
+    for (offset, listen_addr) in rule.listen_addrs() {
+        // unix sockets are rejected when rules are saved, this only guards against stale records
+        let inet_targets = rule
+            .target
+            .addrs
+            .iter()
+            .all(|target| matches!(target.addr, RuleAddr::Inet(_)));
+        let bound = match &listen_addr {
+            Endpoint::Inet(addr) if inet_targets => match rule.config.transparent {
+                Some(_) => transparent::bind_udp_listener(*addr),
+                None => UdpSocket::bind(addr).await,
+            },
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "unix sockets are not supported by udp rules",
+            )),
+        };
+
+        let socket = match bound {
+            Ok(socket) => socket,
+            Err(e) => {
+                error!(parent: &span, "failed to bind to {}: {}", listen_addr, e);
+
+                // handle non-retryable errors
+                utils::fail_rule(
+                    rule.id.into(),
+                    format!("failed to bind to {}", listen_addr),
+                    &dal,
+                    &stats_cache,
+                )
+                .await;
+
+                return;
             }
+        };
 
-            return;
+        let socket_ref = socket2::SockRef::from(&socket);
+        if let Err(e) = socket_ref.set_send_buffer_size(65535 * 2) {
+            warn!(parent: &span, "failed to set send buffer size: {}", e);
+        }
+        if let Err(e) = socket_ref.set_recv_buffer_size(65535 * 2) {
+            warn!(parent: &span, "failed to set receive buffer size: {}", e);
         }
-    };
 
-    let socket_ref = socket2::SockRef::from(&socket);
-    if let Err(e) = socket_ref.set_send_buffer_size(65535 * 2) {
-        warn!(parent: &span, "failed to set send buffer size: {}", e);
+        listeners.push(UdpListener {
+            socket: Arc::new(socket),
+            index: listeners.len(),
+            offset,
+            mirror_addr: match rule.config.mirror.as_ref().map(|mirror| mirror.resolve(offset)) {
+                Some(Endpoint::Inet(mirror_addr)) => Some(mirror_addr),
+                _ => None,
+            },
+        });
     }
-    if let Err(e) = socket_ref.set_recv_buffer_size(65535 * 2) {
-        warn!(parent: &span, "failed to set receive buffer size: {}", e);
-    }
-
-    let listener = Arc::new(socket);
 
-    debug!(parent: &span, "udp forwarding started on {}", rule.listen);
+    debug!(parent: &span, "udp forwarding started on {}", rule.listen_display());
 
-    let clients: Arc<Mutex<HashMap<SocketAddr, UdpClient>>> = Arc::new(Mutex::new(HashMap::new()));
+    let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
 
     let transferred_bytes = Arc::new(AtomicU64::new(0));
     let last_update_time = Arc::new(Mutex::new(Instant::now()));
@@ -151,13 +245,68 @@ pub async fn start_udp_forward(rule: Rule, config: AgentConfig, dal: DataAccessL
         })
     };
 
+    let route = TargetRoute {
+        balancer: Arc::new(services.balancers.for_rule(&rule)),
+        via: match (&rule.config.peer, &rule.config.agent, &rule.config.upstream) {
+            (Some(peer), _, _) => TargetVia::Peer(services.peers.get(peer)),
+            (None, Some(agent), _) => TargetVia::Peer(services.peers.agent(agent)),
+            (None, None, Some(upstream)) => TargetVia::Upstream(upstream.clone()),
+            (None, None, None) => TargetVia::Direct,
+        },
+        over_tcp: (rule.config.udp_over_tcp == Some(RuleUdpOverTcp::Encapsulate))
+            .then_some((config.tcp_buffer_size as usize) * 1024),
+    };
+
+    let forward = Arc::new(UdpForward {
+        transparent: rule.config.transparent.clone(),
+        route,
+        clients,
+        transferred_bytes,
+        capture: services.captures.for_rule(rule_id),
+        telemetry: services.telemetry.for_rule(rule_id),
+    });
+
+    // one receive loop per listen address and port
+    let mut receive_loops = JoinSet::new();
+
+    for listener in listeners {
+        receive_loops.spawn(receive_datagrams(listener, forward.clone(), span.clone()));
+    }
+
+    while receive_loops.join_next().await.is_some() {}
+}
+
+async fn receive_datagrams(listener: UdpListener, forward: Arc<UdpForward>, span: Span) {
+    let UdpListener {
+        socket: listener,
+        index,
+        offset,
+        mirror_addr,
+    } = listener;
+    let UdpForward {
+        transparent,
+        route,
+        clients,
+        transferred_bytes,
+        capture,
+        telemetry,
+    } = &*forward;
+
     let mut buf = [0; 65535];
 
     loop {
         let span = span.clone();
 
-        match listener.recv_from(&mut buf).await {
-            Ok((size, client_addr)) => {
+        let received = match transparent {
+            Some(_) => transparent::recv_from_original(&listener, &mut buf).await,
+            None => listener
+                .recv_from(&mut buf)
+                .await
+                .map(|(size, client_addr)| (size, client_addr, None)),
+        };
+
+        match received {
+            Ok((size, client_addr, original_addr)) => {
                 trace!(parent: &span, "received {} bytes from {}", size, client_addr);
 
                 let data = buf[..size].to_vec();
@@ -166,44 +315,123 @@ pub async fn start_udp_forward(rule: Rule, config: AgentConfig, dal: DataAccessL
 
                 let mut clients_lock = clients.lock().await;
 
-                if let Some(client) = clients_lock.get_mut(&client_addr) {
+                if let Some(client) = clients_lock.get_mut(&(index, client_addr, original_addr)) {
                     client.last_active = Instant::now();
+                    client.meter.add(size as u64);
+
+                    if let Some(mirror) = &client.mirror {
+                        let _ = mirror.send(&data);
+                    }
+
+                    if let Some(capture) = capture.active() {
+                        capture.record_datagram(client_addr, client.target_addr, &data);
+                    }
 
                     if let Err(e) = client.sender.send(data).await {
                         error!(parent: &span, "failed to send data to client handler for {}: {}", client_addr, e);
-                        clients_lock.remove(&client_addr);
+                        clients_lock.remove(&(index, client_addr, original_addr));
                     }
                 } else {
-                    let listener_clone = listener.clone();
+                    let intercepted_addr = original_addr.filter(|original_addr| {
+                        listener
+                            .local_addr()
+                            .is_ok_and(|local_addr| !transparent::is_bound_to(local_addr, *original_addr))
+                    });
+
+                    // intercepted clients expect replies from the address they sent to
+                    let listener_clone = match intercepted_addr {
+                        Some(original_addr) => match transparent::bind_udp_socket(original_addr) {
+                            Ok(socket) => Arc::new(socket),
+                            Err(e) => {
+                                warn!(parent: &span, "failed to bind reply socket to {}: {}", original_addr, e);
+                                continue;
+                            }
+                        },
+                        None => listener.clone(),
+                    };
+                    // datagrams can't tell whether a target is up, so sessions just go to the target picked
+                    // first
+                    let target = match (transparent, intercepted_addr) {
+                        (Some(transparent), Some(original_addr)) if transparent.original_destination => {
+                            Some(Candidate::fixed(Endpoint::Inet(original_addr)))
+                        }
+                        (Some(transparent), None) if transparent.original_destination => {
+                            warn!(parent: &span, "dropping datagram from {} not redirected by tproxy", client_addr);
+                            continue;
+                        }
+                        _ => route.balancer.candidates(offset, Some(client_addr)).into_iter().next(),
+                    };
+                    let Some(target) = target else {
+                        warn!(parent: &span, "no target takes new sessions, dropping datagram from {}", client_addr);
+                        continue;
+                    };
+                    let Endpoint::Inet(session_target_addr) = target.endpoint else {
+                        continue;
+                    };
+                    let source_addr = match transparent {
+                        Some(transparent) if transparent.spoof_source => Some(client_addr),
+                        _ => None,
+                    };
+
+                    let mirror = mirror_addr.and_then(|mirror_addr| match mirror::bind_datagram_mirror(mirror_addr) {
+                        Ok(mirror) => {
+                            let _ = mirror.send(&data);
+                            Some(mirror)
+                        }
+                        Err(e) => {
+                            debug!(parent: &span, "failed to set up mirror {} for {}: {}", mirror_addr, client_addr, e);
+                            None
+                        }
+                    });
+
+                    if let Some(capture) = capture.active() {
+                        capture.record_datagram(client_addr, session_target_addr, &data);
+                    }
+
                     let (tx, rx) = tokio::sync::mpsc::channel(100);
                     let client_data = data.clone();
                     let client_transferred_bytes = transferred_bytes.clone();
+                    let target = Arc::new(target.lease());
+                    let meter = telemetry.open_session(Some(client_addr), Some(target.endpoint()));
+                    meter.add(size as u64);
+                    let session = UdpSession {
+                        listener: listener_clone,
+                        client_addr,
+                        target_addr: session_target_addr,
+                        target: target.clone(),
+                        source_addr,
+                        route: route.clone(),
+                        meter: meter.clone(),
+                    };
+                    let session_capture = capture.clone();
 
                     tokio::spawn(async move {
-                        match create_target_session(
-                            listener_clone,
-                            client_addr,
-                            target_addr,
-                            client_data,
-                            rx,
-                            client_transferred_bytes,
-                        )
-                        .await
-                        {
-                            Ok(_) => {
-                                trace!(parent: &span, "udp session ended for client {}", client_addr);
+                        tokio::select! {
+                            result = create_target_session(session, client_data, rx, client_transferred_bytes, session_capture) => {
+                                match result {
+                                    Ok(_) => {
+                                        trace!(parent: &span, "udp session ended for client {}", client_addr);
+                                    }
+                                    Err(e) => {
+                                        warn!(parent: &span, "failed to create udp session for client {}: {}", client_addr, e);
+                                        target.report_failure();
+                                    }
+                                }
                             }
-                            Err(e) => {
-                                warn!(parent: &span, "failed to create udp session for client {}: {}", client_addr, e);
+                            _ = target.closed() => {
+                                debug!(parent: &span, "target disabled, closing udp session for client {}", client_addr);
                             }
                         }
                     });
 
                     clients_lock.insert(
-                        client_addr,
+                        (index, client_addr, original_addr),
                         UdpClient {
                             sender: tx,
+                            target_addr: session_target_addr,
                             last_active: Instant::now(),
+                            mirror,
+                            meter,
                         },
                     );
                 }
@@ -216,16 +444,48 @@ pub async fn start_udp_forward(rule: Rule, config: AgentConfig, dal: DataAccessL
 }
 
 async fn create_target_session(
-    listener: Arc<UdpSocket>, client_addr: SocketAddr, target_addr: SocketAddr, initial_data: Vec<u8>,
-    mut client_rx: tokio::sync::mpsc::Receiver<Vec<u8>>, transferred_bytes: Arc<AtomicU64>,
+    session: UdpSession, initial_data: Vec<u8>, mut client_rx: tokio::sync::mpsc::Receiver<Vec<u8>>,
+    transferred_bytes: Arc<AtomicU64>, capture: RuleCapture,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if let Some(buffer_size) = session.route.over_tcp {
+        relay_over_tcp(
+            session,
+            buffer_size,
+            initial_data,
+            client_rx,
+            transferred_bytes,
+            capture,
+        )
+        .await?;
+        return Ok(());
+    }
+
+    if let TargetVia::Peer(peer) = &session.route.via {
+        let datagrams = peer.open_datagrams(session.target_addr).await?;
+        relay_through_peer(datagrams, session, initial_data, client_rx, transferred_bytes, capture).await;
+        return Ok(());
+    }
+
+    let UdpSession {
+        listener,
+        client_addr,
+        target_addr,
+        target,
+        source_addr,
+        route,
+        meter,
+    } = session;
+
     let span = info_span!(
         "udp_target_session",
         client_addr = client_addr.to_string(),
         target_addr = target_addr.to_string()
     );
 
-    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    let socket = match source_addr {
+        Some(source_addr) => transparent::bind_udp_socket(source_addr)?,
+        None => UdpSocket::bind("0.0.0.0:0").await?,
+    };
 
     let socket_ref = socket2::SockRef::from(&socket);
     if let Err(e) = socket_ref.set_send_buffer_size(65535 * 2) {
@@ -237,9 +497,27 @@ async fn create_target_session(
 
     let target_socket = Arc::new(socket);
 
-    target_socket.as_ref().connect(target_addr).await?;
+    // with an upstream proxy datagrams go to its relay, which is told the target in a header
+    let (mut control, relayed) = match &route.via {
+        TargetVia::Upstream(upstream) => {
+            let association = upstream::associate_udp(upstream).await?;
+            target_socket.as_ref().connect(association.relay).await?;
+            (Some(association.control), true)
+        }
+        _ => {
+            target_socket.as_ref().connect(target_addr).await?;
+            (None, false)
+        }
+    };
+    let encapsulate = |data: Vec<u8>| {
+        if relayed {
+            socks::encapsulate_datagram(target_addr, &data)
+        } else {
+            data
+        }
+    };
 
-    target_socket.as_ref().send(&initial_data).await?;
+    target_socket.as_ref().send(&encapsulate(initial_data)).await?;
 
     let (resp_tx, mut resp_rx) = tokio::sync::mpsc::channel(100);
 
@@ -247,8 +525,30 @@ async fn create_target_session(
     let target_receiver = {
         tokio::spawn(async move {
             let mut buf = [0; 65535];
-            while let Ok(size) = target_socket_clone.as_ref().recv(&mut buf).await {
-                let response = buf[..size].to_vec();
+            let mut answered = false;
+            loop {
+                let size = match target_socket_clone.as_ref().recv(&mut buf).await {
+                    Ok(size) => size,
+                    // nobody listens on the target
+                    Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                        target.report_failure();
+                        break;
+                    }
+                    Err(_) => break,
+                };
+                if !answered {
+                    answered = true;
+                    target.report_success();
+                }
+
+                let response = if relayed {
+                    match socks::decapsulate_datagram(&buf[..size]) {
+                        Some((_, payload)) => payload.to_vec(),
+                        None => continue,
+                    }
+                } else {
+                    buf[..size].to_vec()
+                };
                 if resp_tx.send(response).await.is_err() {
                     break;
                 }
@@ -259,6 +559,7 @@ async fn create_target_session(
     loop {
         tokio::select! {
             Some(data) = client_rx.recv() => {
+                let data = encapsulate(data);
                 if data.len() > 16000 {
                     trace!(parent: &span, "sending large udp packet ({} bytes) to target", data.len());
 
@@ -298,6 +599,17 @@ async fn create_target_session(
                     break;
                 }
                 transferred_bytes.fetch_add(response.len() as u64, Ordering::Relaxed);
+                meter.add(response.len() as u64);
+                meter.answered();
+
+                if let Some(capture) = capture.active() {
+                    capture.record_datagram(target_addr, client_addr, &response);
+                }
+            }
+
+            _ = association_closed(&mut control), if relayed => {
+                debug!(parent: &span, "upstream proxy closed the udp association");
+                break;
             }
 
             else => break,
@@ -308,3 +620,156 @@ async fn create_target_session(
 
     Ok(())
 }
+
+// Relays a session through the tunnel to a peer pedicab, which sends the datagrams on to the
+// target.
+async fn relay_through_peer(
+    mut datagrams: DatagramStream, session: UdpSession, initial_data: Vec<u8>,
+    mut client_rx: tokio::sync::mpsc::Receiver<Vec<u8>>, transferred_bytes: Arc<AtomicU64>, capture: RuleCapture,
+) {
+    let UdpSession {
+        listener,
+        client_addr,
+        target_addr,
+        target,
+        meter,
+        ..
+    } = session;
+
+    // datagrams too large for the tunnel are dropped, the session goes on
+    if let Err(e) = datagrams.send(&initial_data).await
+        && e.kind() != io::ErrorKind::InvalidInput
+    {
+        return;
+    }
+
+    let mut answered = false;
+
+    loop {
+        tokio::select! {
+            data = client_rx.recv() => {
+                let Some(data) = data else { break };
+                if let Err(e) = datagrams.send(&data).await
+                    && e.kind() != io::ErrorKind::InvalidInput
+                {
+                    break;
+                }
+            }
+
+            response = datagrams.recv() => {
+                let Some(response) = response else { break };
+                if !answered {
+                    answered = true;
+                    target.report_success();
+                }
+                if listener.send_to(&response, client_addr).await.is_err() {
+                    break;
+                }
+                transferred_bytes.fetch_add(response.len() as u64, Ordering::Relaxed);
+                meter.add(response.len() as u64);
+                meter.answered();
+
+                if let Some(capture) = capture.active() {
+                    capture.record_datagram(target_addr, client_addr, &response);
+                }
+            }
+        }
+    }
+}
+
+// Relays a session over a tcp connection to the target, through the upstream proxy or tunnel of the
+// rule if any, with the datagrams framed as in `udp_over_tcp`.
+async fn relay_over_tcp(
+    session: UdpSession, buffer_size: usize, initial_data: Vec<u8>,
+    mut client_rx: tokio::sync::mpsc::Receiver<Vec<u8>>, transferred_bytes: Arc<AtomicU64>, capture: RuleCapture,
+) -> io::Result<()> {
+    let UdpSession {
+        listener,
+        client_addr,
+        target_addr,
+        target,
+        source_addr,
+        route,
+        meter,
+    } = session;
+
+    let span = info_span!(
+        "udp_over_tcp_session",
+        client_addr = client_addr.to_string(),
+        target_addr = target_addr.to_string()
+    );
+
+    let endpoint = Endpoint::Inet(target_addr);
+    let stream = match &route.via {
+        TargetVia::Direct => tcp::connect_target(&endpoint, source_addr, buffer_size, &span).await?,
+        TargetVia::Upstream(upstream) => upstream::connect(upstream, &endpoint, buffer_size, &span).await?,
+        TargetVia::Peer(peer) => peer.open_stream(&endpoint).await?,
+    };
+    let (mut reader, mut writer) = tokio::io::split(stream);
+
+    udp_over_tcp::write_datagram(&mut writer, &initial_data).await?;
+
+    let client_to_target = async {
+        while let Some(data) = client_rx.recv().await {
+            if let Err(e) = udp_over_tcp::write_datagram(&mut writer, &data).await {
+                debug!(parent: &span, "failed to send datagram to target: {}", e);
+                break;
+            }
+        }
+    };
+
+    let target_to_client = async {
+        let mut buffer = vec![0u8; u16::MAX as usize];
+        let mut answered = false;
+
+        loop {
+            let size = match udp_over_tcp::read_datagram(&mut reader, &mut buffer).await {
+                Ok(Some(size)) => size,
+                Ok(None) => break,
+                Err(e) => {
+                    debug!(parent: &span, "failed to receive datagram from target: {}", e);
+                    target.report_failure();
+                    break;
+                }
+            };
+            if !answered {
+                answered = true;
+                target.report_success();
+            }
+
+            if listener.send_to(&buffer[..size], client_addr).await.is_err() {
+                break;
+            }
+            transferred_bytes.fetch_add(size as u64, Ordering::Relaxed);
+            meter.add(size as u64);
+            meter.answered();
+
+            if let Some(capture) = capture.active() {
+                capture.record_datagram(target_addr, client_addr, &buffer[..size]);
+            }
+        }
+    };
+
+    // the session ends with either direction, each a whole loop as reading a datagram is not cancel
+    // safe
+    tokio::select! {
+        _ = client_to_target => {}
+        _ = target_to_client => {}
+    }
+
+    Ok(())
+}
+
+// Resolves once the control connection of a udp association is gone.
+async fn association_closed(control: &mut Option<TcpStream>) {
+    let Some(control) = control else {
+        return std::future::pending().await;
+    };
+
+    let mut buffer = [0u8; 64];
+    while let Ok(n) = control.read(&mut buffer).await {
+        if n == 0 {
+            break;
+        }
+    }
+}
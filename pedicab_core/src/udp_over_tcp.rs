@@ -0,0 +1,217 @@
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use pedicab_db::data::rule::{Endpoint, RuleStats, RuleStatsConnections};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::UdpSocket,
+};
+use tracing::{Span, debug, trace};
+
+use crate::{
+    balancer::TargetLease,
+    stream::BoxedStream,
+    tcp::{self, TcpForward},
+    transparent,
+};
+
+// UDP over TCP carries every datagram as a big endian u16 length followed by its bytes, the framing
+// DNS uses over TCP, so encapsulating rules can also talk to DNS servers directly.
+
+// connections without any datagram from the client are closed after this long
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+pub async fn write_datagram<W>(writer: &mut W, data: &[u8]) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let length =
+        u16::try_from(data.len()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "datagram is too large"))?;
+
+    let mut frame = Vec::with_capacity(2 + data.len());
+    frame.extend_from_slice(&length.to_be_bytes());
+    frame.extend_from_slice(data);
+
+    writer.write_all(&frame).await?;
+    writer.flush().await
+}
+
+// Reads the next datagram into `buffer`, resolving to `None` once the stream ends between
+// datagrams. Not cancel safe, a datagram may be left half read.
+pub async fn read_datagram<R>(reader: &mut R, buffer: &mut [u8]) -> io::Result<Option<usize>>
+where
+    R: AsyncRead + Unpin,
+{
+    let length = match reader.read_u16().await {
+        Ok(length) => length as usize,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    reader.read_exact(&mut buffer[..length]).await?;
+    Ok(Some(length))
+}
+
+// Sends the datagrams carried by a connection of a decapsulating rule to the target over udp, and
+// the replies back the same way.
+pub async fn decapsulate(
+    client_stream: BoxedStream, peer_addr: Option<SocketAddr>, target: TargetLease, source_addr: Option<SocketAddr>,
+    forward: &TcpForward, span: &Span,
+) -> io::Result<()> {
+    let Endpoint::Inet(target_addr) = *target.endpoint() else {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "unix sockets can't receive datagrams",
+        ));
+    };
+
+    let socket = match source_addr {
+        Some(source_addr) => transparent::bind_udp_socket(source_addr)?,
+        None => {
+            let bind_addr: SocketAddr = match target_addr {
+                SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+                SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+            };
+            UdpSocket::bind(bind_addr).await?
+        }
+    };
+    socket.connect(target_addr).await?;
+
+    trace!(parent: span, "decapsulating datagrams to {}", target_addr);
+
+    let rule_id = forward.rule.id.as_uuid();
+    let stats_cache = &forward.stats_cache;
+
+    {
+        let prev_stats = stats_cache.get(&rule_id).await.unwrap_or_default();
+        stats_cache
+            .insert(
+                rule_id,
+                RuleStats {
+                    connections: RuleStatsConnections {
+                        tcp: prev_stats.connections.tcp + 1,
+                        ..prev_stats.connections
+                    },
+                    ..prev_stats
+                },
+            )
+            .await;
+    }
+
+    let transferred_bytes = Arc::new(AtomicU64::new(0));
+    let stats_updater = tcp::spawn_stats_updater(
+        rule_id,
+        transferred_bytes.clone(),
+        stats_cache.clone(),
+        Duration::from_millis(forward.config.stats_update_interval),
+    );
+
+    let meter = forward.telemetry.open_connection(peer_addr, Some(target.endpoint()));
+
+    let (mut client_reader, mut client_writer) = tokio::io::split(client_stream);
+
+    let client_to_target = async {
+        let mut buffer = vec![0u8; u16::MAX as usize];
+
+        loop {
+            let size = match tokio::time::timeout(IDLE_TIMEOUT, read_datagram(&mut client_reader, &mut buffer)).await {
+                Ok(Ok(Some(size))) => size,
+                Ok(Ok(None)) => break,
+                Ok(Err(e)) => {
+                    debug!(parent: span, "error reading datagram from client: {}", e);
+                    break;
+                }
+                Err(_) => {
+                    trace!(parent: span, "client idle, closing");
+                    break;
+                }
+            };
+
+            if let Err(e) = socket.send(&buffer[..size]).await {
+                debug!(parent: span, "failed to send datagram to target: {}", e);
+                continue;
+            }
+            transferred_bytes.fetch_add(size as u64, Ordering::Relaxed);
+            meter.add(size as u64);
+
+            if let (Some(capture), Some(peer_addr)) = (forward.capture.active(), peer_addr) {
+                capture.record_datagram(peer_addr, target_addr, &buffer[..size]);
+            }
+        }
+    };
+
+    let target_to_client = async {
+        let mut buffer = vec![0u8; u16::MAX as usize];
+        let mut answered = false;
+
+        loop {
+            let size = match socket.recv(&mut buffer).await {
+                Ok(size) => size,
+                // an earlier datagram found nobody listening, the target may still come up
+                Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                    target.report_failure();
+                    continue;
+                }
+                Err(e) => {
+                    debug!(parent: span, "failed to receive datagram from target: {}", e);
+                    break;
+                }
+            };
+
+            if !answered {
+                answered = true;
+                target.report_success();
+                meter.answered();
+            }
+
+            if let Err(e) = write_datagram(&mut client_writer, &buffer[..size]).await {
+                debug!(parent: span, "error writing datagram to client: {}", e);
+                break;
+            }
+            transferred_bytes.fetch_add(size as u64, Ordering::Relaxed);
+            meter.add(size as u64);
+
+            if let (Some(capture), Some(peer_addr)) = (forward.capture.active(), peer_addr) {
+                capture.record_datagram(target_addr, peer_addr, &buffer[..size]);
+            }
+        }
+    };
+
+    // the connection ends along with either direction
+    tokio::select! {
+        _ = client_to_target => {}
+        _ = target_to_client => {}
+        _ = target.closed() => {
+            debug!(parent: span, "target disabled, closing connection");
+        }
+    }
+
+    stats_updater.abort();
+
+    {
+        let prev_stats = stats_cache.get(&rule_id).await.unwrap_or_default();
+        stats_cache
+            .insert(
+                rule_id,
+                RuleStats {
+                    // bytes the updater did not get to fold in before it was stopped
+                    bandwidth: prev_stats.bandwidth + transferred_bytes.swap(0, Ordering::Relaxed),
+                    connections: RuleStatsConnections {
+                        tcp: prev_stats.connections.tcp - 1,
+                        ..prev_stats.connections
+                    },
+                    ..prev_stats
+                },
+            )
+            .await;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,52 @@
+use std::{
+    fs, io,
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use tokio::net::{UnixListener, UnixStream};
+
+// Removes the socket file of a unix listener once the rule stops.
+pub struct UnixSocketGuard {
+    path: PathBuf,
+}
+
+impl Drop for UnixSocketGuard {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+pub async fn bind_unix_listener(path: &Path, mode: Option<u32>) -> io::Result<(UnixListener, UnixSocketGuard)> {
+    // a socket file left behind by a crashed process would make binding fail, but a socket somebody is
+    // still accepting on, or any other kind of file, must not be touched
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+
+        match UnixStream::connect(path).await {
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{} is in use", path.display()),
+                ));
+            }
+            Err(_) => fs::remove_file(path)?,
+        }
+    }
+
+    let listener = UnixListener::bind(path)?;
+    let guard = UnixSocketGuard {
+        path: path.to_path_buf(),
+    };
+
+    if let Some(mode) = mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+
+    Ok((listener, guard))
+}
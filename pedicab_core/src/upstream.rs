@@ -0,0 +1,218 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use pedicab_db::data::rule::{Endpoint, RuleUpstream, RuleUpstreamProtocol};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+use tracing::Span;
+
+use crate::{
+    socks::{self, Address},
+    stream::BoxedStream,
+    tcp::connect_target,
+};
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// largest response header accepted from a proxy for a CONNECT request
+const MAX_RESPONSE_SIZE: usize = 8 * 1024;
+
+// Opens a tunnel to the target through the upstream proxy of a rule.
+pub async fn connect(
+    upstream: &RuleUpstream, target: &Endpoint, buffer_size: usize, span: &Span,
+) -> io::Result<BoxedStream> {
+    let Endpoint::Inet(target) = target else {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "unix socket targets can't be reached through an upstream proxy",
+        ));
+    };
+
+    let mut stream = connect_target(&Endpoint::Inet(upstream.addr), None, buffer_size, span).await?;
+
+    with_timeout(async {
+        match upstream.protocol {
+            RuleUpstreamProtocol::Socks5 => {
+                socks5_authenticate(&mut stream, upstream).await?;
+                socks5_request(&mut stream, socks::CMD_CONNECT, *target).await?;
+            }
+            RuleUpstreamProtocol::Http => http_connect(&mut stream, upstream, *target).await?,
+        }
+        Ok(())
+    })
+    .await?;
+
+    Ok(stream)
+}
+
+// A SOCKS5 UDP association, the proxy keeps relaying datagrams as long as the control connection
+// stays open.
+pub struct UdpAssociation {
+    pub control: TcpStream,
+    pub relay: SocketAddr,
+}
+
+pub async fn associate_udp(upstream: &RuleUpstream) -> io::Result<UdpAssociation> {
+    if upstream.protocol != RuleUpstreamProtocol::Socks5 {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "udp can only be relayed through socks5 proxies",
+        ));
+    }
+
+    with_timeout(async {
+        let mut control = TcpStream::connect(upstream.addr).await?;
+        socks5_authenticate(&mut control, upstream).await?;
+
+        // the address datagrams will be sent from isn't known yet
+        let unspecified = match upstream.addr {
+            SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+            SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+        };
+        let bound = socks5_request(&mut control, socks::CMD_UDP_ASSOCIATE, unspecified).await?;
+
+        // an unspecified relay address stands for the address of the proxy itself
+        let relay = if bound.ip().is_unspecified() {
+            SocketAddr::new(upstream.addr.ip(), bound.port())
+        } else {
+            bound
+        };
+
+        Ok(UdpAssociation { control, relay })
+    })
+    .await
+}
+
+async fn with_timeout<T>(handshake: impl Future<Output = io::Result<T>>) -> io::Result<T> {
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "upstream proxy handshake timed out"))?
+}
+
+async fn socks5_authenticate<S>(stream: &mut S, upstream: &RuleUpstream) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let credentials = upstream.username.as_ref().zip(upstream.password.as_ref());
+
+    match credentials {
+        Some(_) => {
+            stream
+                .write_all(&[socks::VERSION, 2, socks::AUTH_NONE, socks::AUTH_PASSWORD])
+                .await?
+        }
+        None => stream.write_all(&[socks::VERSION, 1, socks::AUTH_NONE]).await?,
+    }
+
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != socks::VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "upstream proxy does not speak socks5",
+        ));
+    }
+
+    match (reply[1], credentials) {
+        (socks::AUTH_NONE, _) => Ok(()),
+        (socks::AUTH_PASSWORD, Some((username, password))) => {
+            let mut request = vec![socks::AUTH_PASSWORD_VERSION, username.len() as u8];
+            request.extend_from_slice(username.as_bytes());
+            request.push(password.len() as u8);
+            request.extend_from_slice(password.as_bytes());
+            stream.write_all(&request).await?;
+
+            let mut reply = [0u8; 2];
+            stream.read_exact(&mut reply).await?;
+            if reply[1] != 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "upstream proxy rejected the credentials",
+                ));
+            }
+
+            Ok(())
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "upstream proxy accepts none of the offered authentication methods",
+        )),
+    }
+}
+
+// Sends a request and returns the address bound by the proxy for it.
+async fn socks5_request<S>(stream: &mut S, command: u8, addr: SocketAddr) -> io::Result<SocketAddr>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut request = vec![socks::VERSION, command, 0];
+    socks::write_addr(&mut request, addr);
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != socks::REPLY_SUCCEEDED {
+        return Err(io::Error::other(format!(
+            "upstream proxy replied: {}",
+            socks::reply_message(reply[1])
+        )));
+    }
+
+    match socks::read_addr(stream, reply[3]).await? {
+        Address::Ip(addr) => Ok(addr),
+        // a host name can't be used as a relay address
+        Address::Domain(_, port) => Ok(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port)),
+    }
+}
+
+async fn http_connect<S>(stream: &mut S, upstream: &RuleUpstream, target: SocketAddr) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target);
+    if let (Some(username), Some(password)) = (&upstream.username, &upstream.password) {
+        let credentials = STANDARD.encode(format!("{}:{}", username, password));
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", credentials));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // read byte by byte, targets speaking first may send right after the tunnel is up and their bytes
+    // must stay in the stream
+    let mut response = Vec::with_capacity(256);
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_RESPONSE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "response of upstream proxy is too large",
+            ));
+        }
+        response.push(stream.read_u8().await?);
+    }
+
+    let status = std::str::from_utf8(&response)
+        .ok()
+        .and_then(|response| response.split_whitespace().nth(1))
+        .and_then(|status| status.parse::<u16>().ok());
+
+    match status {
+        Some(200..=299) => Ok(()),
+        Some(407) => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "upstream proxy requires authentication",
+        )),
+        Some(status) => Err(io::Error::other(format!(
+            "upstream proxy refused the tunnel with status {}",
+            status
+        ))),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid response from upstream proxy",
+        )),
+    }
+}
@@ -1,3 +1,27 @@
+use pedicab_db::{
+    dal::DataAccessLayer,
+    data::rule::{RuleStats, RuleStatus},
+};
+use uuid::Uuid;
+
+use crate::manager::StatsCache;
+
+// Puts a rule into error status for failures retrying won't fix, e.g. an address in use.
+pub async fn fail_rule(rule_id: Uuid, message: String, dal: &DataAccessLayer, stats_cache: &StatsCache) {
+    let updated_stats = match stats_cache.get(&rule_id).await {
+        Some(current_stats) => RuleStats {
+            last_failed_message: message,
+            ..current_stats
+        },
+        None => RuleStats {
+            last_failed_message: message,
+            ..Default::default()
+        },
+    };
+    let _ = dal.rule.update_status(rule_id, RuleStatus::Error).await;
+    stats_cache.insert(rule_id, updated_stats).await;
+}
+
 #[cfg(unix)]
 pub mod unix_limits {
     use std::{cmp, io};
@@ -0,0 +1,184 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use sled::Db;
+use uuid::Uuid;
+
+use crate::data::history::*;
+
+// The series of the host, rule ids are never nil.
+const HOST_SERIES: Uuid = Uuid::nil();
+
+// Points a query returns at most.
+const MAX_POINTS: u64 = 10_000;
+
+// Time series of the traffic of every rule and the load of the host. Each sample is merged into a
+// point of every resolution, and points older than the retention of their resolution are dropped as
+// newer ones come in. Unlike rules history is not flushed on every write, the database flush
+// interval is good enough for it.
+#[derive(Debug, Clone)]
+pub struct HistoryDataAccessLayer {
+    db: Db,
+}
+
+impl HistoryDataAccessLayer {
+    pub fn new(db: Db) -> Self {
+        Self { db }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Database error: {0}")]
+    Db(#[from] sled::Error),
+
+    #[error("Logics error: {0}")]
+    Logics(String),
+
+    #[error("Decode error: {0}")]
+    DecodeError(#[from] bincode::error::DecodeError),
+
+    #[error("Encode error: {0}")]
+    EncodeError(#[from] bincode::error::EncodeError),
+}
+
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+pub struct FindHistoryParams {
+    // bounds in seconds since the unix epoch, the last hour by default
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    // seconds per returned point, the finest resolution still kept for `from` by default
+    pub step: Option<u64>,
+}
+
+impl HistoryDataAccessLayer {
+    // Series keys are the series id followed by the big endian timestamp, so a series sorts by time.
+    fn key(series: &Uuid, timestamp: u64) -> Vec<u8> {
+        let mut key = series.as_bytes().to_vec();
+        key.extend_from_slice(&timestamp.to_be_bytes());
+        key
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs())
+    }
+
+    fn record<P: HistoryPoint>(&self, series: &Uuid, sample: &P) -> Result<(), Error> {
+        for resolution in HistoryResolution::ALL {
+            let tree = self.db.open_tree(resolution.tree())?;
+
+            let timestamp = sample.timestamp() - sample.timestamp() % resolution.seconds();
+            let key = Self::key(series, timestamp);
+
+            let mut point = match tree.get(&key)? {
+                Some(data) => bincode::decode_from_slice::<P, _>(&data, bincode::config::standard())?.0,
+                None => {
+                    let mut point = P::default();
+                    point.set_timestamp(timestamp);
+                    point
+                }
+            };
+            point.merge(sample);
+            tree.insert(key, bincode::encode_to_vec(&point, bincode::config::standard())?)?;
+
+            let expired = Self::key(series, 0)..Self::key(series, timestamp.saturating_sub(resolution.retention()));
+            for key in tree.range(expired).keys() {
+                tree.remove(key?)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn find<P: HistoryPoint>(&self, series: &Uuid, params: FindHistoryParams) -> Result<Vec<P>, Error> {
+        let now = Self::now();
+        let to = params.to.unwrap_or(now);
+        let from = params.from.unwrap_or(to.saturating_sub(60 * 60));
+
+        if from >= to {
+            return Err(Error::Logics(String::from("history has to start before it ends")));
+        }
+        if params.step == Some(0) {
+            return Err(Error::Logics(String::from("history step has to be at least a second")));
+        }
+
+        // the resolutions still kept for `from`, finest first, and the coarsest of them the step can
+        // be made of
+        let kept = HistoryResolution::ALL
+            .into_iter()
+            .filter(|resolution| from >= now.saturating_sub(resolution.retention()))
+            .collect::<Vec<_>>();
+        let finest = kept.first().copied().unwrap_or(HistoryResolution::Hour);
+        let resolution = match params.step {
+            Some(step) => kept
+                .iter()
+                .rev()
+                .find(|resolution| step.is_multiple_of(resolution.seconds()))
+                .copied()
+                .unwrap_or(finest),
+            None => finest,
+        };
+
+        let step = params
+            .step
+            .unwrap_or(resolution.seconds())
+            .next_multiple_of(resolution.seconds());
+        if (to - from) / step > MAX_POINTS {
+            return Err(Error::Logics(format!(
+                "history would have more than {} points, raise the step",
+                MAX_POINTS
+            )));
+        }
+
+        let tree = self.db.open_tree(resolution.tree())?;
+        let range = Self::key(series, from - from % resolution.seconds())..Self::key(series, to);
+
+        let mut points: Vec<P> = Vec::new();
+        for entry in tree.range(range).values() {
+            let point = bincode::decode_from_slice::<P, _>(&entry?, bincode::config::standard())?.0;
+            let timestamp = point.timestamp() - point.timestamp() % step;
+
+            match points.last_mut() {
+                Some(last) if last.timestamp() == timestamp => last.merge(&point),
+                _ => {
+                    let mut merged = P::default();
+                    merged.set_timestamp(timestamp);
+                    merged.merge(&point);
+                    points.push(merged);
+                }
+            }
+        }
+
+        Ok(points)
+    }
+}
+
+impl HistoryDataAccessLayer {
+    pub async fn record_rule(&self, rule_id: Uuid, sample: RuleHistoryPoint) -> Result<(), Error> {
+        self.record(&rule_id, &sample)
+    }
+
+    pub async fn record_host(&self, sample: HostHistoryPoint) -> Result<(), Error> {
+        self.record(&HOST_SERIES, &sample)
+    }
+
+    pub async fn find_rule(&self, rule_id: Uuid, params: FindHistoryParams) -> Result<Vec<RuleHistoryPoint>, Error> {
+        self.find(&rule_id, params)
+    }
+
+    pub async fn find_host(&self, params: FindHistoryParams) -> Result<Vec<HostHistoryPoint>, Error> {
+        self.find(&HOST_SERIES, params)
+    }
+
+    pub async fn delete_rule(&self, rule_id: Uuid) -> Result<(), Error> {
+        for resolution in HistoryResolution::ALL {
+            let tree = self.db.open_tree(resolution.tree())?;
+            for key in tree.scan_prefix(rule_id.as_bytes()).keys() {
+                tree.remove(key?)?;
+            }
+        }
+
+        Ok(())
+    }
+}
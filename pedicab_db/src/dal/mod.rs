@@ -1,16 +1,19 @@
 use sled::Db;
 
+pub mod history;
 pub mod rule;
 
 #[derive(Clone)]
 pub struct DataAccessLayer {
     pub rule: rule::RuleDataAccessLayer,
+    pub history: history::HistoryDataAccessLayer,
 }
 
 impl DataAccessLayer {
     pub fn new(db: Db) -> Self {
         DataAccessLayer {
-            rule: rule::RuleDataAccessLayer::new(db),
+            rule: rule::RuleDataAccessLayer::new(db.clone()),
+            history: history::HistoryDataAccessLayer::new(db),
         }
     }
 }
@@ -1,12 +1,13 @@
 #![allow(unused)]
 
-use std::net::SocketAddr;
-
 use sled::Db;
 use uuid::{NoContext, Timestamp, Uuid};
 
 use crate::{
-    data::{generic::CompactUuid, rule::*},
+    data::{
+        generic::{CompactUuid, OneOrMany},
+        rule::*,
+    },
     model,
     model::rule::Rule,
 };
@@ -44,7 +45,7 @@ pub enum Error {
 #[derive(Debug, serde::Deserialize)]
 pub struct CreateRuleParams {
     pub name: String,
-    pub listen: SocketAddr,
+    pub listen: OneOrMany<RuleAddr>,
     pub target: RuleTarget,
     pub protocol: RuleProtocol,
     pub config: Option<RuleConfig>,
@@ -56,7 +57,7 @@ pub struct CreateRuleParams {
 #[derive(Debug, serde::Deserialize)]
 pub struct UpdateRuleParams {
     pub name: Option<String>,
-    pub listen: Option<SocketAddr>,
+    pub listen: Option<OneOrMany<RuleAddr>>,
     pub target: Option<RuleTarget>,
     pub protocol: Option<RuleProtocol>,
     pub config: Option<RuleConfig>,
@@ -107,6 +108,509 @@ impl RuleDataAccessLayer {
         self.update_rule_index(&ids).await?;
         Ok(())
     }
+
+    fn validate(rule: &Rule) -> Result<(), Error> {
+        // clients of proxy rules and peers of tunnel rules pick the destinations themselves
+        if rule.target.addrs.is_empty() && !matches!(rule.protocol, RuleProtocol::Proxy | RuleProtocol::Tunnel) {
+            return Err(Error::Logics(String::from("rule has no target")));
+        }
+
+        if rule.target.addrs.iter().any(|target| target.weight == 0) {
+            return Err(Error::Logics(String::from("target weight has to be at least 1")));
+        }
+
+        // reverse rules dial out instead of listening
+        if rule.listen.is_empty() && rule.protocol != RuleProtocol::Reverse {
+            return Err(Error::Logics(String::from("rule has no listen address")));
+        }
+
+        // rule targets along with the targets of routes
+        let targets = || {
+            rule.target
+                .addrs
+                .iter()
+                .map(|target| &target.addr)
+                .chain(
+                    rule.config
+                        .routing
+                        .iter()
+                        .flat_map(|routing| routing.routes.iter().map(|route| &route.target)),
+                )
+                .chain(
+                    rule.config
+                        .http
+                        .iter()
+                        .flat_map(|http| http.routes.iter().map(|route| &route.target)),
+                )
+                .chain(rule.config.mirror.iter())
+                .chain(rule.config.overflow.iter().filter_map(|overflow| match overflow {
+                    RuleOverflow::Redirect(target) => Some(target),
+                    _ => None,
+                }))
+        };
+
+        if !matches!(rule.protocol, RuleProtocol::Tcp | RuleProtocol::Http)
+            && let Some(addr) = rule.listen.iter().chain(targets()).find(|addr| addr.is_unix())
+        {
+            return Err(Error::Logics(format!(
+                "unix socket address {} is only supported by tcp and http rules",
+                addr
+            )));
+        }
+
+        if rule.config.transparent.is_some() && rule.listen.iter().any(|addr| addr.is_unix()) {
+            return Err(Error::Logics(String::from(
+                "transparent mode is not supported on unix socket listeners",
+            )));
+        }
+
+        if rule.config.tls.is_some() && rule.protocol == RuleProtocol::Udp {
+            return Err(Error::Logics(String::from("tls is not supported by udp rules")));
+        }
+
+        if rule.config.routing.is_some() && matches!(rule.protocol, RuleProtocol::Udp | RuleProtocol::Http) {
+            return Err(Error::Logics(String::from(
+                "routing is not supported by udp and http rules",
+            )));
+        }
+
+        if rule.config.mirror.is_some() && rule.protocol == RuleProtocol::Http {
+            return Err(Error::Logics(String::from("mirroring is not supported by http rules")));
+        }
+
+        if rule.config.http.is_some() && rule.protocol != RuleProtocol::Http {
+            return Err(Error::Logics(String::from("http settings require an http rule")));
+        }
+
+        if let Some(upstream) = &rule.config.upstream {
+            Self::validate_upstream(rule, upstream)?;
+        }
+
+        if rule.protocol == RuleProtocol::Proxy {
+            Self::validate_proxy(rule)?;
+        } else if rule.config.proxy.is_some() {
+            return Err(Error::Logics(String::from("proxy settings require a proxy rule")));
+        }
+
+        if rule.protocol == RuleProtocol::Tunnel {
+            Self::validate_tunnel(rule)?;
+        } else if rule.config.tunnel.is_some() {
+            return Err(Error::Logics(String::from("tunnel settings require a tunnel rule")));
+        }
+
+        if rule.protocol == RuleProtocol::Reverse {
+            Self::validate_reverse(rule)?;
+        } else if rule.config.reverse.is_some() {
+            return Err(Error::Logics(String::from("reverse settings require a reverse rule")));
+        }
+
+        if rule.config.peer.is_some() || rule.config.agent.is_some() {
+            Self::validate_peer(rule)?;
+        }
+
+        if let Some(udp_over_tcp) = rule.config.udp_over_tcp {
+            Self::validate_udp_over_tcp(rule, udp_over_tcp)?;
+        }
+
+        if let Some(outlier_detection) = &rule.config.outlier_detection {
+            Self::validate_outlier_detection(rule, outlier_detection)?;
+        }
+
+        if let Some(retries) = &rule.config.retries {
+            Self::validate_retries(rule, retries)?;
+        }
+
+        if let Some(overflow) = &rule.config.overflow {
+            Self::validate_overflow(rule, overflow)?;
+        }
+
+        if rule.config.quota.as_ref().is_some_and(|quota| quota.bytes == 0) {
+            return Err(Error::Logics(String::from("quota has to allow at least 1 byte")));
+        }
+
+        if let Some(schedule) = &rule.config.schedule {
+            Self::validate_schedule(schedule)?;
+        }
+
+        // every target has to cover the whole listen ranges, or be a single port all listen ports map onto
+        for listen in rule.listen.iter() {
+            if let Some(addr) = targets().find(|addr| addr.is_range() && addr.port_count() != listen.port_count()) {
+                return Err(Error::Logics(format!(
+                    "target port range {} does not match listen port range {}",
+                    addr, listen
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_upstream(rule: &Rule, upstream: &RuleUpstream) -> Result<(), Error> {
+        // the mirror is connected to directly
+        let unix_target = rule
+            .target
+            .addrs
+            .iter()
+            .map(|target| &target.addr)
+            .chain(
+                rule.config
+                    .routing
+                    .iter()
+                    .flat_map(|routing| routing.routes.iter().map(|route| &route.target)),
+            )
+            .chain(
+                rule.config
+                    .http
+                    .iter()
+                    .flat_map(|http| http.routes.iter().map(|route| &route.target)),
+            )
+            .find(|addr| addr.is_unix());
+        if let Some(addr) = unix_target {
+            return Err(Error::Logics(format!(
+                "unix socket target {} can't be reached through an upstream proxy",
+                addr
+            )));
+        }
+
+        if rule
+            .config
+            .transparent
+            .as_ref()
+            .is_some_and(|transparent| transparent.spoof_source)
+        {
+            return Err(Error::Logics(String::from(
+                "source spoofing is not supported with an upstream proxy",
+            )));
+        }
+
+        // encapsulated datagrams travel over tcp, which any upstream proxy carries
+        if upstream.protocol == RuleUpstreamProtocol::Http
+            && matches!(rule.protocol, RuleProtocol::Udp | RuleProtocol::TcpUdp)
+            && rule.config.udp_over_tcp.is_none()
+        {
+            return Err(Error::Logics(String::from(
+                "udp rules can only be chained through socks5 proxies",
+            )));
+        }
+
+        match (&upstream.username, &upstream.password) {
+            (Some(username), Some(password)) => {
+                if upstream.protocol == RuleUpstreamProtocol::Socks5
+                    && (username.is_empty() || username.len() > 255 || password.is_empty() || password.len() > 255)
+                {
+                    return Err(Error::Logics(String::from(
+                        "socks5 username and password have to be 1 to 255 bytes long",
+                    )));
+                }
+            }
+            (None, None) => {}
+            _ => {
+                return Err(Error::Logics(String::from(
+                    "upstream proxy credentials need both a username and a password",
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_proxy(rule: &Rule) -> Result<(), Error> {
+        if !rule.target.addrs.is_empty() {
+            return Err(Error::Logics(String::from(
+                "proxy rules have no targets, clients pick the destinations",
+            )));
+        }
+
+        if rule.config.transparent.is_some()
+            || rule.config.tls.is_some()
+            || rule.config.routing.is_some()
+            || rule.config.mirror.is_some()
+        {
+            return Err(Error::Logics(String::from(
+                "transparent mode, tls, routing and mirroring are not supported by proxy rules",
+            )));
+        }
+
+        let Some(proxy) = &rule.config.proxy else {
+            return Ok(());
+        };
+
+        match (&proxy.username, &proxy.password) {
+            (Some(username), Some(password)) => {
+                // the socks5 username/password method carries both with a one byte length
+                if username.is_empty() || username.len() > 255 || password.is_empty() || password.len() > 255 {
+                    return Err(Error::Logics(String::from(
+                        "proxy username and password have to be 1 to 255 bytes long",
+                    )));
+                }
+            }
+            (None, None) => {}
+            _ => {
+                return Err(Error::Logics(String::from(
+                    "proxy credentials need both a username and a password",
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_tunnel(rule: &Rule) -> Result<(), Error> {
+        if !rule.target.addrs.is_empty() {
+            return Err(Error::Logics(String::from(
+                "tunnel rules have no targets, peers pick the destinations",
+            )));
+        }
+
+        if rule.config.transparent.is_some()
+            || rule.config.tls.is_some()
+            || rule.config.routing.is_some()
+            || rule.config.mirror.is_some()
+        {
+            return Err(Error::Logics(String::from(
+                "transparent mode, tls, routing and mirroring are not supported by tunnel rules",
+            )));
+        }
+
+        match &rule.config.tunnel {
+            Some(tunnel) => Self::validate_tunnel_key(&tunnel.key),
+            None => Err(Error::Logics(String::from("tunnel rules require a tunnel key"))),
+        }
+    }
+
+    fn validate_reverse(rule: &Rule) -> Result<(), Error> {
+        if !rule.listen.is_empty() {
+            return Err(Error::Logics(String::from(
+                "reverse rules have no listen addresses, they dial out to the public pedicab",
+            )));
+        }
+
+        if rule.config.transparent.is_some()
+            || rule.config.tls.is_some()
+            || rule.config.routing.is_some()
+            || rule.config.mirror.is_some()
+        {
+            return Err(Error::Logics(String::from(
+                "transparent mode, tls, routing and mirroring are not supported by reverse rules",
+            )));
+        }
+
+        if rule.config.upstream.is_some() || rule.config.peer.is_some() || rule.config.agent.is_some() {
+            return Err(Error::Logics(String::from(
+                "reverse rules connect to their targets directly",
+            )));
+        }
+
+        let Some(reverse) = &rule.config.reverse else {
+            return Err(Error::Logics(String::from("reverse rules require reverse settings")));
+        };
+
+        // the name is sent with a one byte length
+        if reverse.name.is_empty() || reverse.name.len() > 255 {
+            return Err(Error::Logics(String::from("agent name has to be 1 to 255 bytes long")));
+        }
+
+        Self::validate_tunnel_key(&reverse.key)
+    }
+
+    // Rules carried through a tunnel, either to a peer or back to an agent.
+    fn validate_peer(rule: &Rule) -> Result<(), Error> {
+        if rule.config.upstream.is_some() {
+            return Err(Error::Logics(String::from(
+                "a tunnel can't be combined with an upstream proxy",
+            )));
+        }
+
+        if rule.config.peer.is_some() && rule.config.agent.is_some() {
+            return Err(Error::Logics(String::from(
+                "a rule is carried either to a peer or to an agent",
+            )));
+        }
+
+        // the mirror is connected to directly
+        let unix_target = rule
+            .target
+            .addrs
+            .iter()
+            .map(|target| &target.addr)
+            .chain(
+                rule.config
+                    .routing
+                    .iter()
+                    .flat_map(|routing| routing.routes.iter().map(|route| &route.target)),
+            )
+            .chain(
+                rule.config
+                    .http
+                    .iter()
+                    .flat_map(|http| http.routes.iter().map(|route| &route.target)),
+            )
+            .find(|addr| addr.is_unix());
+        if let Some(addr) = unix_target {
+            return Err(Error::Logics(format!(
+                "unix socket target {} can't be reached through a tunnel",
+                addr
+            )));
+        }
+
+        if rule
+            .config
+            .transparent
+            .as_ref()
+            .is_some_and(|transparent| transparent.spoof_source)
+        {
+            return Err(Error::Logics(String::from(
+                "source spoofing is not supported with a tunnel",
+            )));
+        }
+
+        match (&rule.config.peer, &rule.config.agent) {
+            (Some(peer), _) => Self::validate_tunnel_key(&peer.key),
+            (None, Some(agent)) if agent.is_empty() => Err(Error::Logics(String::from("agent name is empty"))),
+            _ => Ok(()),
+        }
+    }
+
+    fn validate_outlier_detection(rule: &Rule, outlier_detection: &RuleOutlierDetection) -> Result<(), Error> {
+        if matches!(
+            rule.protocol,
+            RuleProtocol::Proxy | RuleProtocol::Tunnel | RuleProtocol::Reverse
+        ) {
+            return Err(Error::Logics(String::from(
+                "outlier detection is not supported by proxy, tunnel and reverse rules",
+            )));
+        }
+
+        if outlier_detection.consecutive_failures == 0 {
+            return Err(Error::Logics(String::from(
+                "outlier detection needs at least one failure to eject a target",
+            )));
+        }
+
+        if outlier_detection.ejection_time == 0 || outlier_detection.max_ejection_time < outlier_detection.ejection_time
+        {
+            return Err(Error::Logics(String::from(
+                "ejection time has to be at least 1 second and at most the max ejection time",
+            )));
+        }
+
+        if outlier_detection.max_ejected_percent > 100 {
+            return Err(Error::Logics(String::from("max ejected percent can't exceed 100")));
+        }
+
+        Ok(())
+    }
+
+    fn validate_retries(rule: &Rule, retries: &RuleRetries) -> Result<(), Error> {
+        if !matches!(
+            rule.protocol,
+            RuleProtocol::Tcp | RuleProtocol::Http | RuleProtocol::TcpUdp
+        ) {
+            return Err(Error::Logics(String::from(
+                "connect retries are only supported by tcp, tcp_udp and http rules",
+            )));
+        }
+
+        if retries.connect_timeout == 0 || retries.deadline < retries.connect_timeout {
+            return Err(Error::Logics(String::from(
+                "connect timeout has to be at least 1 millisecond and at most the retry deadline",
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn validate_overflow(rule: &Rule, overflow: &RuleOverflow) -> Result<(), Error> {
+        // udp and reverse rules don't take connections under a limit
+        if matches!(rule.protocol, RuleProtocol::Udp | RuleProtocol::Reverse) {
+            return Err(Error::Logics(String::from(
+                "overflow settings are not supported by udp and reverse rules",
+            )));
+        }
+
+        match overflow {
+            RuleOverflow::Queue(queue) if queue.size == 0 || queue.timeout == 0 => Err(Error::Logics(String::from(
+                "overflow queue needs a size and a timeout of at least 1",
+            ))),
+            RuleOverflow::Redirect(_) if matches!(rule.protocol, RuleProtocol::Proxy | RuleProtocol::Tunnel) => {
+                Err(Error::Logics(String::from(
+                    "overflow redirects are not supported by proxy and tunnel rules",
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn validate_schedule(schedule: &RuleSchedule) -> Result<(), Error> {
+        if let (Some(start), Some(end)) = (schedule.start, schedule.end)
+            && start >= end
+        {
+            return Err(Error::Logics(String::from("schedule has to start before it ends")));
+        }
+
+        if schedule.windows.iter().any(|window| window.from == window.to) {
+            return Err(Error::Logics(String::from(
+                "schedule windows have to close at another time than they open",
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn validate_udp_over_tcp(rule: &Rule, udp_over_tcp: RuleUdpOverTcp) -> Result<(), Error> {
+        match udp_over_tcp {
+            RuleUdpOverTcp::Encapsulate if rule.protocol != RuleProtocol::Udp => Err(Error::Logics(String::from(
+                "udp over tcp encapsulation requires a udp rule",
+            ))),
+            RuleUdpOverTcp::Decapsulate if rule.protocol != RuleProtocol::Tcp => Err(Error::Logics(String::from(
+                "udp over tcp decapsulation requires a tcp rule",
+            ))),
+            RuleUdpOverTcp::Encapsulate => Ok(()),
+            RuleUdpOverTcp::Decapsulate => {
+                // the decapsulated datagrams are sent to the targets directly
+                if rule.config.mirror.is_some()
+                    || rule.config.upstream.is_some()
+                    || rule.config.peer.is_some()
+                    || rule.config.agent.is_some()
+                {
+                    return Err(Error::Logics(String::from(
+                        "mirroring, upstream proxies and tunnels are not supported with udp over tcp decapsulation",
+                    )));
+                }
+
+                let unix_target = rule
+                    .target
+                    .addrs
+                    .iter()
+                    .map(|target| &target.addr)
+                    .chain(
+                        rule.config
+                            .routing
+                            .iter()
+                            .flat_map(|routing| routing.routes.iter().map(|route| &route.target)),
+                    )
+                    .find(|addr| addr.is_unix());
+                if let Some(addr) = unix_target {
+                    return Err(Error::Logics(format!(
+                        "unix socket target {} can't receive decapsulated datagrams",
+                        addr
+                    )));
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    fn validate_tunnel_key(key: &str) -> Result<(), Error> {
+        if key.len() < 16 {
+            return Err(Error::Logics(String::from(
+                "tunnel key has to be at least 16 bytes long",
+            )));
+        }
+
+        Ok(())
+    }
 }
 
 impl RuleDataAccessLayer {
@@ -145,7 +649,7 @@ impl RuleDataAccessLayer {
         let rule = Rule {
             id: id.into(),
             name: params.name,
-            listen: params.listen,
+            listen: params.listen.into(),
             target: params.target,
             protocol: params.protocol,
             config: params.config.unwrap_or_default(),
@@ -155,6 +659,8 @@ impl RuleDataAccessLayer {
             remarks: params.remarks.unwrap_or(String::from("")),
         };
 
+        Self::validate(&rule)?;
+
         let key = Self::id_to_key(&id);
         let buffer = bincode::encode_to_vec(&rule, bincode::config::standard())?;
 
@@ -175,7 +681,7 @@ impl RuleDataAccessLayer {
             rule.name = name;
         }
         if let Some(listen) = params.listen {
-            rule.listen = listen;
+            rule.listen = listen.into();
         }
         if let Some(target) = params.target {
             rule.target = target;
@@ -196,6 +702,8 @@ impl RuleDataAccessLayer {
             rule.remarks = remarks;
         }
 
+        Self::validate(&rule)?;
+
         let buffer = bincode::encode_to_vec(&rule, bincode::config::standard())?;
         self.db.insert(key, buffer)?;
         self.db.flush_async().await?;
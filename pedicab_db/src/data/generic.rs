@@ -31,3 +31,20 @@ impl From<CompactUuid> for uuid::Uuid {
         uuid.as_uuid()
     }
 }
+
+// Accepts either a single value or a list of values when deserializing input.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T> From<OneOrMany<T>> for Vec<T> {
+    fn from(value: OneOrMany<T>) -> Self {
+        match value {
+            OneOrMany::One(value) => vec![value],
+            OneOrMany::Many(values) => values,
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use bincode::{Decode, Encode};
+use serde::{Deserialize, Serialize};
+
+// How finely the history is kept, the older it gets the coarser.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryResolution {
+    Second,
+    Minute,
+    Hour,
+}
+
+impl HistoryResolution {
+    pub const ALL: [HistoryResolution; 3] = [
+        HistoryResolution::Second,
+        HistoryResolution::Minute,
+        HistoryResolution::Hour,
+    ];
+
+    pub fn seconds(&self) -> u64 {
+        match self {
+            HistoryResolution::Second => 1,
+            HistoryResolution::Minute => 60,
+            HistoryResolution::Hour => 60 * 60,
+        }
+    }
+
+    // How long points of this resolution are kept, in seconds.
+    pub fn retention(&self) -> u64 {
+        match self {
+            HistoryResolution::Second => 60 * 60,
+            HistoryResolution::Minute => 24 * 60 * 60,
+            HistoryResolution::Hour => 365 * 24 * 60 * 60,
+        }
+    }
+
+    pub fn tree(&self) -> &'static str {
+        match self {
+            HistoryResolution::Second => "history_1s",
+            HistoryResolution::Minute => "history_1m",
+            HistoryResolution::Hour => "history_1h",
+        }
+    }
+}
+
+// A point of a history series, samples falling into the same interval are merged into one.
+pub trait HistoryPoint: Encode + Decode<()> + Default {
+    fn timestamp(&self) -> u64;
+
+    fn set_timestamp(&mut self, timestamp: u64);
+
+    fn merge(&mut self, other: &Self);
+}
+
+// The traffic of a rule over an interval.
+#[derive(Debug, Clone, Encode, Decode, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub struct RuleHistoryPoint {
+    // start of the interval in seconds since the unix epoch
+    pub timestamp: u64,
+    // seconds of the interval that were sampled, less than its length while pedicab or the rule was
+    // down
+    pub seconds: u64,
+    pub bytes: u64,
+    // bytes per second over the sampled seconds
+    pub speed: u64,
+    // the most connections and sessions open when sampled
+    pub connections: u64,
+}
+
+impl HistoryPoint for RuleHistoryPoint {
+    fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    fn set_timestamp(&mut self, timestamp: u64) {
+        self.timestamp = timestamp;
+    }
+
+    fn merge(&mut self, other: &Self) {
+        self.seconds += other.seconds;
+        self.bytes += other.bytes;
+        self.speed = self.bytes / self.seconds.max(1);
+        self.connections = self.connections.max(other.connections);
+    }
+}
+
+// The load of the host over an interval.
+#[derive(Debug, Clone, Encode, Decode, PartialEq, Serialize, Deserialize, Default)]
+pub struct HostHistoryPoint {
+    // start of the interval in seconds since the unix epoch
+    pub timestamp: u64,
+    // seconds of the interval that were sampled
+    pub seconds: u64,
+    // averages over the sampled seconds, cpu usage in percent and memory in bytes
+    pub cpu_usage: f32,
+    pub memory_used: u64,
+    // bytes through the network interfaces, loopback aside
+    pub received: u64,
+    pub sent: u64,
+}
+
+impl HistoryPoint for HostHistoryPoint {
+    fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    fn set_timestamp(&mut self, timestamp: u64) {
+        self.timestamp = timestamp;
+    }
+
+    fn merge(&mut self, other: &Self) {
+        let seconds = self.seconds + other.seconds;
+        if seconds > 0 {
+            self.cpu_usage =
+                (self.cpu_usage * self.seconds as f32 + other.cpu_usage * other.seconds as f32) / seconds as f32;
+            self.memory_used = ((self.memory_used as u128 * self.seconds as u128
+                + other.memory_used as u128 * other.seconds as u128)
+                / seconds as u128) as u64;
+        }
+        self.seconds = seconds;
+        self.received += other.received;
+        self.sent += other.sent;
+    }
+}
@@ -1,8 +1,284 @@
-use std::net::SocketAddr;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+};
 
 use bincode::{Decode, Encode};
 use serde::{Deserialize, Serialize};
 
+// A socket address covering a consecutive port range, e.g. `0.0.0.0:20000-20100`. Single ports are
+// represented with `port_end` equal to the port of `addr`.
+#[derive(Copy, Clone, Debug, Encode, Decode, PartialEq, Eq, Hash)]
+pub struct SocketAddrRange {
+    pub addr: SocketAddr,
+    pub port_end: u16,
+}
+
+impl SocketAddrRange {
+    pub fn port_count(&self) -> usize {
+        (self.port_end as usize).saturating_sub(self.addr.port() as usize) + 1
+    }
+
+    pub fn is_range(&self) -> bool {
+        self.port_count() > 1
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        (self.addr.port()..=self.port_end).map(|port| SocketAddr::new(self.addr.ip(), port))
+    }
+
+    pub fn contains(&self, addr: SocketAddr) -> bool {
+        addr.ip() == self.addr.ip() && (self.addr.port()..=self.port_end).contains(&addr.port())
+    }
+
+    // Resolve the address for the `offset`-th port of a listen range. A single port target maps every
+    // listen port onto itself, while a target range is mapped port by port.
+    pub fn resolve(&self, offset: usize) -> SocketAddr {
+        if self.is_range() {
+            SocketAddr::new(self.addr.ip(), self.addr.port().saturating_add(offset as u16))
+        } else {
+            self.addr
+        }
+    }
+}
+
+impl From<SocketAddr> for SocketAddrRange {
+    fn from(addr: SocketAddr) -> Self {
+        SocketAddrRange {
+            addr,
+            port_end: addr.port(),
+        }
+    }
+}
+
+impl fmt::Display for SocketAddrRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_range() {
+            write!(f, "{}-{}", self.addr, self.port_end)
+        } else {
+            write!(f, "{}", self.addr)
+        }
+    }
+}
+
+impl FromStr for SocketAddrRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, ports) = s.rsplit_once(':').ok_or_else(|| format!("invalid address: {}", s))?;
+
+        let (port_start, port_end) = match ports.split_once('-') {
+            Some((start, end)) => (start, end),
+            None => (ports, ports),
+        };
+
+        let addr = format!("{}:{}", host, port_start)
+            .parse::<SocketAddr>()
+            .map_err(|e| format!("invalid address {}: {}", s, e))?;
+        let port_end = port_end
+            .parse::<u16>()
+            .map_err(|e| format!("invalid port range {}: {}", s, e))?;
+
+        if port_end < addr.port() {
+            return Err(format!("invalid port range {}: end port is lower than start port", s));
+        }
+
+        Ok(SocketAddrRange { addr, port_end })
+    }
+}
+
+impl Serialize for SocketAddrRange {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for SocketAddrRange {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+// An IP network in CIDR notation, e.g. `10.0.0.0/8`. A plain address stands for a network holding
+// just that address.
+#[derive(Copy, Clone, Debug, Encode, Decode, PartialEq, Eq, Hash)]
+pub struct IpNetwork {
+    pub addr: IpAddr,
+    pub prefix: u8,
+}
+
+impl IpNetwork {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                (u32::from(network) ^ u32::from(ip)) & mask == 0
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                (u128::from(network) ^ u128::from(ip)) & mask == 0
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|e| format!("invalid network {}: {}", s, e))?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max_prefix)
+                .ok_or_else(|| format!("invalid network {}: prefix has to be at most {}", s, max_prefix))?,
+            None => max_prefix,
+        };
+
+        Ok(IpNetwork { addr, prefix })
+    }
+}
+
+impl Serialize for IpNetwork {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for IpNetwork {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+// A listen or target address of a rule, either an IP socket address (range) or a unix domain socket
+// path written as `unix:/path/to.sock`.
+#[derive(Clone, Debug, Encode, Decode, PartialEq, Eq, Hash)]
+pub enum RuleAddr {
+    Inet(SocketAddrRange),
+    Unix(PathBuf),
+}
+
+impl RuleAddr {
+    pub fn port_count(&self) -> usize {
+        match self {
+            RuleAddr::Inet(range) => range.port_count(),
+            RuleAddr::Unix(_) => 1,
+        }
+    }
+
+    pub fn is_range(&self) -> bool {
+        self.port_count() > 1
+    }
+
+    pub fn is_unix(&self) -> bool {
+        matches!(self, RuleAddr::Unix(_))
+    }
+
+    pub fn iter(&self) -> Box<dyn Iterator<Item = Endpoint> + Send + '_> {
+        match self {
+            RuleAddr::Inet(range) => Box::new(range.iter().map(Endpoint::Inet)),
+            RuleAddr::Unix(path) => Box::new(std::iter::once(Endpoint::Unix(path.clone()))),
+        }
+    }
+
+    pub fn resolve(&self, offset: usize) -> Endpoint {
+        match self {
+            RuleAddr::Inet(range) => Endpoint::Inet(range.resolve(offset)),
+            RuleAddr::Unix(path) => Endpoint::Unix(path.clone()),
+        }
+    }
+}
+
+impl fmt::Display for RuleAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuleAddr::Inet(range) => write!(f, "{}", range),
+            RuleAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl FromStr for RuleAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some("") => Err(format!("invalid address: {}", s)),
+            Some(path) => Ok(RuleAddr::Unix(PathBuf::from(path))),
+            None => s.parse().map(RuleAddr::Inet),
+        }
+    }
+}
+
+impl Serialize for RuleAddr {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for RuleAddr {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+// A single address resolved from a `RuleAddr`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    Inet(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Inet(addr) => write!(f, "{}", addr),
+            Endpoint::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Encode, Decode, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum RuleTargetPolicy {
@@ -14,20 +290,125 @@ pub enum RuleTargetPolicy {
     LeastConnections,
     // Random policy
     Random,
+    // Round-robin policy spreading connections in proportion to the target weights
+    WeightedRoundRobin,
+    // Random policy picking targets with a probability in proportion to their weights
+    WeightedRandom,
+    // Consistent hashing of the client IP, so a client sticks to the same target
+    SourceIpHash,
+    // Consistent hashing of the client IP and port
+    SourceAddrHash,
 }
 
 #[derive(Clone, Debug, Encode, Decode, PartialEq, Eq, Serialize, Deserialize)]
 pub struct RuleTarget {
-    pub addrs: Vec<SocketAddr>,
+    pub addrs: Vec<RuleTargetAddr>,
     pub policy: RuleTargetPolicy,
 }
 
+// A target address along with how it takes part in the rotation. Targets with the default settings
+// are written as a plain address.
+#[derive(Clone, Debug, Encode, Decode, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "RuleTargetAddrRepr", into = "RuleTargetAddrRepr")]
+pub struct RuleTargetAddr {
+    pub addr: RuleAddr,
+    // share of the connections under the weighted, least connections and hashing policies
+    pub weight: u32,
+    // only used once none of the other targets can be connected to
+    pub backup: bool,
+    pub state: RuleTargetState,
+}
+
+impl From<RuleAddr> for RuleTargetAddr {
+    fn from(addr: RuleAddr) -> Self {
+        RuleTargetAddr {
+            addr,
+            weight: 1,
+            backup: false,
+            state: RuleTargetState::Active,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum RuleTargetAddrRepr {
+    Plain(RuleAddr),
+    Full {
+        addr: RuleAddr,
+        #[serde(default = "default_weight")]
+        weight: u32,
+        #[serde(default)]
+        backup: bool,
+        #[serde(default)]
+        state: RuleTargetState,
+    },
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+impl From<RuleTargetAddrRepr> for RuleTargetAddr {
+    fn from(repr: RuleTargetAddrRepr) -> Self {
+        match repr {
+            RuleTargetAddrRepr::Plain(addr) => addr.into(),
+            RuleTargetAddrRepr::Full {
+                addr,
+                weight,
+                backup,
+                state,
+            } => RuleTargetAddr {
+                addr,
+                weight,
+                backup,
+                state,
+            },
+        }
+    }
+}
+
+impl From<RuleTargetAddr> for RuleTargetAddrRepr {
+    fn from(target: RuleTargetAddr) -> Self {
+        if target.weight == 1 && !target.backup && target.state == RuleTargetState::Active {
+            return RuleTargetAddrRepr::Plain(target.addr);
+        }
+
+        RuleTargetAddrRepr::Full {
+            addr: target.addr,
+            weight: target.weight,
+            backup: target.backup,
+            state: target.state,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Encode, Decode, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleTargetState {
+    #[default]
+    Active,
+    // takes no new connections, the established ones carry on until they close
+    Draining,
+    // takes no new connections and the established ones are closed
+    Disabled,
+}
+
 #[derive(Clone, Debug, Encode, Decode, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum RuleProtocol {
     Tcp,
     Udp,
     TcpUdp,
+    // HTTP/1.1 reverse proxy
+    Http,
+    // SOCKS5 and HTTP CONNECT proxy, clients pick the destinations and rule targets are not used
+    Proxy,
+    // receiving end of encrypted tunnels from peer pedicab instances, which pick the destinations
+    Tunnel,
+    // dials out to the tunnel rule of a public pedicab and exposes the rule targets to its rules, for
+    // targets behind NAT; has no listen addresses
+    Reverse,
 }
 
 #[derive(Clone, Debug, Encode, Decode, PartialEq, Eq, Serialize, Deserialize, Default)]
@@ -36,6 +417,494 @@ pub struct RuleConfig {
     pub bandwidth: Option<u64>,
     // limit maximum rule connections count
     pub connections: Option<u64>,
+    // permission bits of unix socket listeners, written as an octal string such as "660"
+    #[serde(default, with = "octal_mode")]
+    pub unix_socket_mode: Option<u32>,
+    // transparent proxy (TPROXY) mode, linux only
+    pub transparent: Option<RuleTransparent>,
+    // TLS termination and origination, tcp only
+    pub tls: Option<RuleTls>,
+    // pick the target of each connection by inspecting its first bytes, tcp only
+    pub routing: Option<RuleRouting>,
+    // reverse proxy settings, http only
+    pub http: Option<RuleHttp>,
+    // receives a copy of the client to server traffic, its replies are discarded
+    pub mirror: Option<RuleAddr>,
+    // reach the targets through a proxy instead of connecting to them directly
+    pub upstream: Option<RuleUpstream>,
+    // proxy server settings, proxy only
+    pub proxy: Option<RuleProxy>,
+    // tunnel server settings, tunnel only
+    pub tunnel: Option<RuleTunnel>,
+    // carry the traffic through an encrypted tunnel to a peer pedicab, which connects to the targets
+    pub peer: Option<RulePeer>,
+    // reverse tunnel settings, reverse only
+    pub reverse: Option<RuleReverse>,
+    // carry the traffic through the reverse tunnel of the agent registered under this name with a
+    // tunnel rule of this instance; targets are addresses on the side of the agent
+    pub agent: Option<String>,
+    // carry datagrams over tcp connections, length prefixed
+    pub udp_over_tcp: Option<RuleUdpOverTcp>,
+    // take targets out of the rotation for a while once they keep failing
+    pub outlier_detection: Option<RuleOutlierDetection>,
+    // retry failed connects to the targets, tcp and http only
+    pub retries: Option<RuleRetries>,
+    // what happens to connections above the connection limit, rejected by default
+    pub overflow: Option<RuleOverflow>,
+    // bytes the rule may forward per day, week or month
+    pub quota: Option<RuleQuota>,
+    // times the rule runs at, outside of them it is stopped but stays enabled
+    pub schedule: Option<RuleSchedule>,
+    // seconds since the unix epoch the rule is disabled at
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Clone, Debug, Encode, Decode, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub struct RuleTransparent {
+    // forward to the original destination of intercepted traffic instead of the rule targets
+    #[serde(default)]
+    pub original_destination: bool,
+    // connect to the target using the client address as source
+    #[serde(default)]
+    pub spoof_source: bool,
+}
+
+#[derive(Clone, Debug, Encode, Decode, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub struct RuleTls {
+    // terminate TLS from clients
+    pub server: Option<RuleTlsServer>,
+    // speak TLS to the targets
+    pub client: Option<RuleTlsClient>,
+}
+
+#[derive(Clone, Debug, Encode, Decode, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuleTlsServer {
+    // PEM encoded certificate chain
+    pub certificate: String,
+    // PEM encoded private key
+    pub key: String,
+}
+
+#[derive(Clone, Debug, Encode, Decode, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub struct RuleTlsClient {
+    // server name sent as SNI and verified against the target certificate, defaults to the target IP
+    pub sni: Option<String>,
+    // PEM encoded CA certificates trusted for the targets, the webpki roots are used if empty
+    pub ca: Option<String>,
+    // skip target certificate verification
+    #[serde(default)]
+    pub insecure: bool,
+}
+
+#[derive(Clone, Debug, Encode, Decode, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuleRouting {
+    // checked in order, the first matching route wins
+    pub routes: Vec<RuleRoute>,
+    // close connections no route matched instead of forwarding them to the rule targets
+    #[serde(default)]
+    pub reject_unmatched: bool,
+    // how long to wait for the client to send enough bytes to match on, in milliseconds
+    pub timeout: Option<u64>,
+}
+
+#[derive(Clone, Debug, Encode, Decode, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuleRoute {
+    #[serde(rename = "match")]
+    pub matcher: RuleRouteMatcher,
+    pub target: RuleAddr,
+}
+
+#[derive(Clone, Debug, Encode, Decode, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleRouteMatcher {
+    // server names from the TLS ClientHello, "*.example.com" matches any subdomain
+    Sni(Vec<String>),
+    // SSH version banner
+    Ssh,
+    // any TLS ClientHello
+    Tls,
+    // HTTP/1.x request line or HTTP/2 connection preface
+    Http,
+    // connections starting with the given text
+    Prefix(String),
+    // regular expression on the first bytes of the connection, use `(?-u)\xNN` to match binary
+    Regex(String),
+}
+
+#[derive(Clone, Debug, Encode, Decode, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub struct RuleHttp {
+    // checked in order, requests no route matched go to the rule targets
+    pub routes: Vec<RuleHttpRoute>,
+}
+
+#[derive(Clone, Debug, Encode, Decode, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuleHttpRoute {
+    // key of the route in stats
+    pub name: Option<String>,
+    // matches any host if empty, "*.example.com" matches any subdomain
+    pub host: Option<String>,
+    // matches any path if empty
+    pub path_prefix: Option<String>,
+    pub target: RuleAddr,
+}
+
+impl RuleHttpRoute {
+    pub fn label(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => format!(
+                "{}{}",
+                self.host.as_deref().unwrap_or("*"),
+                self.path_prefix.as_deref().unwrap_or("/")
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Encode, Decode, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuleUpstream {
+    pub protocol: RuleUpstreamProtocol,
+    pub addr: SocketAddr,
+    // username/password authentication for socks5, basic authentication for http
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+#[derive(Clone, Debug, Encode, Decode, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleUpstreamProtocol {
+    // supports udp rules through UDP ASSOCIATE
+    Socks5,
+    // HTTP CONNECT, tcp only
+    Http,
+}
+
+#[derive(Clone, Debug, Encode, Decode, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub struct RuleProxy {
+    // clients have to authenticate if set, with username/password for socks5 and basic
+    // authentication for http
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // destinations clients may connect to, any but loopback and link-local ones if empty, those have
+    // to be allowed explicitly
+    #[serde(default)]
+    pub allow: Vec<IpNetwork>,
+}
+
+#[derive(Clone, Debug, Encode, Decode, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuleTunnel {
+    // shared with the peers, which have to use the same one
+    pub key: String,
+    // destinations peers may connect to, any if empty
+    #[serde(default)]
+    pub allow: Vec<IpNetwork>,
+}
+
+#[derive(Clone, Debug, Encode, Decode, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct RulePeer {
+    // listen address of the tunnel rule of the peer
+    pub addr: SocketAddr,
+    pub key: String,
+}
+
+#[derive(Copy, Clone, Debug, Encode, Decode, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleUdpOverTcp {
+    // udp rules, the datagrams of each client session go over a tcp connection to the target
+    Encapsulate,
+    // tcp rules, the datagrams carried by each connection are sent on to the target over udp
+    Decapsulate,
+}
+
+#[derive(Clone, Debug, Encode, Decode, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleOverflow {
+    // close the connection right away
+    Reject,
+    // hold the connection until another one closes
+    Queue(RuleOverflowQueue),
+    // send the connection to this target instead, it doesn't count against the limit
+    Redirect(RuleAddr),
+}
+
+#[derive(Clone, Debug, Encode, Decode, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuleOverflowQueue {
+    // connections waiting at most, further ones are rejected
+    #[serde(default = "default_queue_size")]
+    pub size: u32,
+    // milliseconds a connection waits at most before it is closed
+    #[serde(default = "default_queue_timeout")]
+    pub timeout: u64,
+}
+
+fn default_queue_size() -> u32 {
+    100
+}
+
+fn default_queue_timeout() -> u64 {
+    5000
+}
+
+// Bytes a rule may forward per calendar period in UTC, in both directions as counted by the rule
+// bandwidth. A rule that used up its quota is stopped until the next period starts.
+#[derive(Clone, Debug, Encode, Decode, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuleQuota {
+    pub bytes: u64,
+    pub period: RuleQuotaPeriod,
+}
+
+#[derive(Clone, Copy, Debug, Encode, Decode, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleQuotaPeriod {
+    Day,
+    // starts on mondays
+    Week,
+    Month,
+}
+
+impl RuleQuotaPeriod {
+    // Start and end of the period `now` falls into, all in seconds since the unix epoch.
+    pub fn bounds(&self, now: u64) -> (u64, u64) {
+        let day = now / 86400;
+        let (start, end) = match self {
+            RuleQuotaPeriod::Day => (day, day + 1),
+            // the epoch was a thursday, so the first week is cut short at the epoch
+            RuleQuotaPeriod::Week => {
+                let end = day + 7 - (day + 3) % 7;
+                (end.saturating_sub(7), end)
+            }
+            RuleQuotaPeriod::Month => {
+                let start = day + 1 - day_of_month(day);
+                // 31 days after the first of a month always fall into the next one
+                (start, start + 32 - day_of_month(start + 31))
+            }
+        };
+        (start * 86400, end * 86400)
+    }
+}
+
+// Day of the month of a day counted from the epoch, from Howard Hinnant's `civil_from_days`.
+fn day_of_month(day: u64) -> u64 {
+    let z = day + 719468;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    doy - (153 * mp + 2) / 5 + 1
+}
+
+// Times a rule runs at, in UTC.
+#[derive(Clone, Debug, Encode, Decode, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuleSchedule {
+    // seconds since the unix epoch the rule starts and stops running at
+    pub start: Option<u64>,
+    pub end: Option<u64>,
+    // recurring windows the rule runs in between them, it runs around the clock if there are none
+    #[serde(default)]
+    pub windows: Vec<RuleScheduleWindow>,
+}
+
+impl RuleSchedule {
+    // Whether the rule runs at `now`, in seconds since the unix epoch.
+    pub fn is_active(&self, now: u64) -> bool {
+        if self.start.is_some_and(|start| now < start) || self.end.is_some_and(|end| now >= end) {
+            return false;
+        }
+
+        self.windows.is_empty() || self.windows.iter().any(|window| window.contains(now))
+    }
+}
+
+#[derive(Clone, Debug, Encode, Decode, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuleScheduleWindow {
+    // days of the week the window opens on, every day if empty
+    #[serde(default)]
+    pub days: Vec<RuleWeekday>,
+    // minutes since midnight the window opens and closes at, written as "HH:MM"; a window closing
+    // before it opens ends on the next day
+    #[serde(with = "time_of_day")]
+    pub from: u32,
+    #[serde(with = "time_of_day")]
+    pub to: u32,
+}
+
+impl RuleScheduleWindow {
+    fn contains(&self, now: u64) -> bool {
+        let day = now / 86400;
+        let minute = (now % 86400 / 60) as u32;
+        let opens_on = |day: u64| self.days.is_empty() || self.days.contains(&RuleWeekday::of_day(day));
+
+        if self.from < self.to {
+            opens_on(day) && (self.from..self.to).contains(&minute)
+        } else {
+            (opens_on(day) && minute >= self.from) || (day > 0 && opens_on(day - 1) && minute < self.to)
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Encode, Decode, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleWeekday {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+impl RuleWeekday {
+    // Weekday of a day counted from the epoch, which was a thursday.
+    fn of_day(day: u64) -> Self {
+        match day % 7 {
+            0 => RuleWeekday::Thu,
+            1 => RuleWeekday::Fri,
+            2 => RuleWeekday::Sat,
+            3 => RuleWeekday::Sun,
+            4 => RuleWeekday::Mon,
+            5 => RuleWeekday::Tue,
+            _ => RuleWeekday::Wed,
+        }
+    }
+}
+
+// Connect attempts of a client connection. Without retries every target is tried once in the order
+// of the policy.
+#[derive(Clone, Debug, Encode, Decode, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuleRetries {
+    // attempts after the first one
+    #[serde(default = "default_retry_attempts")]
+    pub attempts: u32,
+    // milliseconds a single attempt may take
+    #[serde(default = "default_connect_timeout")]
+    pub connect_timeout: u64,
+    // milliseconds all attempts of a connection may take together
+    #[serde(default = "default_retry_deadline")]
+    pub deadline: u64,
+    // milliseconds to wait before the first retry, doubled for every further one
+    #[serde(default = "default_retry_backoff")]
+    pub backoff: u64,
+    // retry the target that failed instead of moving on to the next one
+    #[serde(default)]
+    pub same_target: bool,
+}
+
+fn default_retry_attempts() -> u32 {
+    2
+}
+
+fn default_connect_timeout() -> u64 {
+    3000
+}
+
+fn default_retry_deadline() -> u64 {
+    10000
+}
+
+fn default_retry_backoff() -> u64 {
+    100
+}
+
+// Passive outlier detection, judging targets by the connections and sessions of the rule itself.
+// Failed connects, resets and targets that time out without answering count as failures, as do
+// refused datagrams.
+#[derive(Clone, Debug, Encode, Decode, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuleOutlierDetection {
+    // failures in a row that eject a target
+    #[serde(default = "default_consecutive_failures")]
+    pub consecutive_failures: u32,
+    // seconds a target stays ejected, doubled for every ejection following closely on the last one
+    #[serde(default = "default_ejection_time")]
+    pub ejection_time: u64,
+    #[serde(default = "default_max_ejection_time")]
+    pub max_ejection_time: u64,
+    // share of the targets of the rule that may be ejected at the same time, in percent
+    #[serde(default = "default_max_ejected_percent")]
+    pub max_ejected_percent: u8,
+}
+
+fn default_consecutive_failures() -> u32 {
+    5
+}
+
+fn default_ejection_time() -> u64 {
+    30
+}
+
+fn default_max_ejection_time() -> u64 {
+    300
+}
+
+fn default_max_ejected_percent() -> u8 {
+    50
+}
+
+#[derive(Clone, Debug, Encode, Decode, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuleReverse {
+    // listen address of the tunnel rule of the public pedicab
+    pub addr: SocketAddr,
+    pub key: String,
+    // the agent name rules of the public pedicab refer to
+    pub name: String,
+}
+
+mod octal_mode {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(mode: &Option<u32>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match mode {
+            Some(mode) => serializer.serialize_str(&format!("{:o}", mode)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match Option::<String>::deserialize(deserializer)? {
+            Some(mode) => u32::from_str_radix(&mode, 8)
+                .map(Some)
+                .map_err(|e| serde::de::Error::custom(format!("invalid unix socket mode {}: {}", mode, e))),
+            None => Ok(None),
+        }
+    }
+}
+
+mod time_of_day {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(minutes: &u32, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&format!("{:02}:{:02}", minutes / 60, minutes % 60))
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<u32, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let time = String::deserialize(deserializer)?;
+
+        // "24:00" closes a window at the end of the day
+        match time
+            .split_once(':')
+            .map(|(hours, minutes)| (hours.parse::<u32>(), minutes.parse::<u32>()))
+        {
+            Some((Ok(hours), Ok(minutes))) if (hours < 24 && minutes < 60) || (hours == 24 && minutes == 0) => {
+                Ok(hours * 60 + minutes)
+            }
+            _ => Err(serde::de::Error::custom(format!(
+                "invalid time of day {}, expected HH:MM",
+                time
+            ))),
+        }
+    }
 }
 
 #[derive(Clone, Debug, Encode, Decode, PartialEq, Eq, Serialize, Deserialize, Default)]
@@ -56,6 +925,72 @@ pub struct RuleStats {
     pub bandwidth: u64,
     pub failed_times: u64,
     pub last_failed_message: String,
+    // connections closed because no route matched them
+    pub unmatched_connections: u64,
+    // connect attempts repeated after a failed one
+    pub retries: u64,
+    // connections above the connection limit
+    pub overflow: RuleStatsOverflow,
+    // byte quota usage, kept across restarts and stats resets
+    pub quota: RuleStatsQuota,
+    // latency of targets and how long connections last, since the rule was enabled or its stats reset
+    pub latency: RuleStatsLatency,
+    // requests of http rules by route, requests sent to the rule targets are under "default"
+    pub http: BTreeMap<String, RuleStatsHttp>,
+}
+
+#[derive(Debug, Clone, Encode, Decode, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub struct RuleStatsOverflow {
+    // connections waiting in the queue right now
+    #[serde(rename = "rt_queued")]
+    pub queued: u64,
+    pub rejected: u64,
+    pub redirected: u64,
+    // connections that got a slot after waiting, and those that gave up
+    pub dequeued: u64,
+    pub timed_out: u64,
+    // milliseconds the dequeued connections waited in total, and the longest wait
+    pub wait_time: u64,
+    pub max_wait_time: u64,
+}
+
+#[derive(Debug, Clone, Encode, Decode, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub struct RuleStatsQuota {
+    // bytes used in the current period
+    pub used: u64,
+    // rule bandwidth when the usage was last brought up to date
+    pub base: u64,
+    // bounds of the current period in seconds since the unix epoch, the quota resets at its end
+    pub period_start: u64,
+    pub period_end: u64,
+}
+
+#[derive(Debug, Clone, Encode, Decode, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub struct RuleStatsLatency {
+    // from starting to connect to a target until the connection is up, tls included
+    pub connect: RuleStatsPercentiles,
+    // from a connection or session being set up until the first byte back from its target, for http
+    // rules from sending a request until its response arrives
+    pub first_byte: RuleStatsPercentiles,
+    // how long connections and sessions stayed open
+    pub duration: RuleStatsPercentiles,
+}
+
+// Percentiles of measurements in microseconds, precise to an eighth of their value.
+#[derive(Debug, Clone, Encode, Decode, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub struct RuleStatsPercentiles {
+    pub count: u64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub max: u64,
+}
+
+#[derive(Debug, Clone, Encode, Decode, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub struct RuleStatsHttp {
+    pub requests: u64,
+    // responses by status code
+    pub statuses: BTreeMap<u16, u64>,
 }
 
 #[derive(Debug, Clone, Encode, Decode, PartialEq, Eq, Serialize, Deserialize, Default)]
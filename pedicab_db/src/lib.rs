@@ -2,6 +2,7 @@ use std::path::PathBuf;
 
 pub mod dal;
 pub mod data;
+pub mod migration;
 pub mod model;
 
 pub fn new_db(path: PathBuf, flush_every_ms: u64) -> Result<sled::Db, sled::Error> {
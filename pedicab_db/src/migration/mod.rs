@@ -0,0 +1,127 @@
+use sled::{Batch, Db};
+
+use crate::{data::generic::CompactUuid, model::rule::Rule};
+
+mod v0;
+mod v1;
+mod v10;
+mod v11;
+mod v12;
+mod v13;
+mod v14;
+mod v15;
+mod v16;
+mod v17;
+mod v18;
+mod v19;
+mod v2;
+mod v3;
+mod v4;
+mod v5;
+mod v6;
+mod v7;
+mod v8;
+mod v9;
+
+// Rules are stored bincode-encoded, which is not self-describing, so every change to the layout of
+// `Rule` bumps the schema version. The layouts of older versions are frozen in the `vN` modules.
+pub const SCHEMA_VERSION: u32 = 20;
+
+const SCHEMA_VERSION_KEY: &[u8] = b"__schema_version";
+const RULE_INDEX_KEY: &[u8] = b"__rule_index";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Database error: {0}")]
+    Db(#[from] sled::Error),
+
+    #[error("Decode error: {0}")]
+    DecodeError(#[from] bincode::error::DecodeError),
+
+    #[error("Encode error: {0}")]
+    EncodeError(#[from] bincode::error::EncodeError),
+
+    #[error("Database schema v{0} is outdated (current v{SCHEMA_VERSION}), migrations have to be executed")]
+    Pending(u32),
+
+    #[error("Database schema v{0} is newer than supported (current v{SCHEMA_VERSION})")]
+    Unsupported(u32),
+}
+
+fn stored_version(db: &Db) -> Result<u32, Error> {
+    match db.get(SCHEMA_VERSION_KEY)? {
+        Some(data) => Ok(bincode::decode_from_slice::<u32, _>(&data, bincode::config::standard())?.0),
+        None => Ok(0),
+    }
+}
+
+fn decode<T: bincode::Decode<()>>(data: &[u8]) -> Result<T, Error> {
+    Ok(bincode::decode_from_slice::<T, _>(data, bincode::config::standard())?.0)
+}
+
+fn upgrade(version: u32, data: &[u8]) -> Result<Rule, Error> {
+    match version {
+        0 => Ok(v0::upgrade(decode(data)?)),
+        1 => Ok(v1::upgrade(decode(data)?)),
+        2 => Ok(v2::upgrade(decode(data)?)),
+        3 => Ok(v3::upgrade(decode(data)?)),
+        4 => Ok(v4::upgrade(decode(data)?)),
+        5 => Ok(v5::upgrade(decode(data)?)),
+        6 => Ok(v6::upgrade(decode(data)?)),
+        7 => Ok(v7::upgrade(decode(data)?)),
+        8 => Ok(v8::upgrade(decode(data)?)),
+        9 => Ok(v9::upgrade(decode(data)?)),
+        10 => Ok(v10::upgrade(decode(data)?)),
+        11 => Ok(v11::upgrade(decode(data)?)),
+        12 => Ok(v12::upgrade(decode(data)?)),
+        13 => Ok(v13::upgrade(decode(data)?)),
+        14 => Ok(v14::upgrade(decode(data)?)),
+        15 => Ok(v15::upgrade(decode(data)?)),
+        16 => Ok(v16::upgrade(decode(data)?)),
+        17 => Ok(v17::upgrade(decode(data)?)),
+        18 => Ok(v18::upgrade(decode(data)?)),
+        19 => Ok(v19::upgrade(decode(data)?)),
+        version => Err(Error::Unsupported(version)),
+    }
+}
+
+// Bring the stored rules up to the current schema. An outdated database holding rules is only
+// migrated when `execute` is set, so upgrades never happen behind the user's back.
+pub fn run(db: &Db, execute: bool) -> Result<(), Error> {
+    let version = stored_version(db)?;
+
+    if version == SCHEMA_VERSION {
+        return Ok(());
+    }
+    if version > SCHEMA_VERSION {
+        return Err(Error::Unsupported(version));
+    }
+
+    let ids = match db.get(RULE_INDEX_KEY)? {
+        Some(data) => decode::<Vec<CompactUuid>>(&data)?,
+        None => Vec::new(),
+    };
+
+    if !ids.is_empty() && !execute {
+        return Err(Error::Pending(version));
+    }
+
+    let mut batch = Batch::default();
+
+    for id in ids {
+        if let Some(data) = db.get(id.0)? {
+            let rule = upgrade(version, &data)?;
+            batch.insert(&id.0, bincode::encode_to_vec(&rule, bincode::config::standard())?);
+        }
+    }
+
+    batch.insert(
+        SCHEMA_VERSION_KEY,
+        bincode::encode_to_vec(SCHEMA_VERSION, bincode::config::standard())?,
+    );
+
+    db.apply_batch(batch)?;
+    db.flush()?;
+
+    Ok(())
+}
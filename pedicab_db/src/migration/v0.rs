@@ -0,0 +1,46 @@
+use std::net::SocketAddr;
+
+use bincode::Decode;
+
+use super::{v1, v2, v5::RuleStats};
+use crate::data::{generic::CompactUuid, rule::*};
+
+// Initial layout, with a single listen port and single port targets.
+
+#[derive(Decode)]
+pub struct Rule {
+    pub id: CompactUuid,
+    pub name: String,
+    pub listen: SocketAddr,
+    pub target: RuleTarget,
+    pub protocol: RuleProtocol,
+    pub config: v2::RuleConfig,
+    pub enabled: bool,
+    pub status: RuleStatus,
+    pub stats: RuleStats,
+    pub remarks: String,
+}
+
+#[derive(Decode)]
+pub struct RuleTarget {
+    pub addrs: Vec<SocketAddr>,
+    pub policy: RuleTargetPolicy,
+}
+
+pub(super) fn upgrade(rule: Rule) -> crate::model::rule::Rule {
+    v1::upgrade(v1::Rule {
+        id: rule.id,
+        name: rule.name,
+        listen: rule.listen.into(),
+        target: v2::RuleTarget {
+            addrs: rule.target.addrs.into_iter().map(Into::into).collect(),
+            policy: rule.target.policy,
+        },
+        protocol: rule.protocol,
+        config: rule.config,
+        enabled: rule.enabled,
+        status: rule.status,
+        stats: rule.stats,
+        remarks: rule.remarks,
+    })
+}
@@ -0,0 +1,38 @@
+use bincode::Decode;
+
+use super::{
+    v2::{self, RuleConfig, RuleTarget},
+    v5::RuleStats,
+};
+use crate::data::{generic::CompactUuid, rule::*};
+
+// Single listen address, possibly covering a port range.
+
+#[derive(Decode)]
+pub struct Rule {
+    pub id: CompactUuid,
+    pub name: String,
+    pub listen: SocketAddrRange,
+    pub target: RuleTarget,
+    pub protocol: RuleProtocol,
+    pub config: RuleConfig,
+    pub enabled: bool,
+    pub status: RuleStatus,
+    pub stats: RuleStats,
+    pub remarks: String,
+}
+
+pub(super) fn upgrade(rule: Rule) -> crate::model::rule::Rule {
+    v2::upgrade(v2::Rule {
+        id: rule.id,
+        name: rule.name,
+        listen: vec![rule.listen],
+        target: rule.target,
+        protocol: rule.protocol,
+        config: rule.config,
+        enabled: rule.enabled,
+        status: rule.status,
+        stats: rule.stats,
+        remarks: rule.remarks,
+    })
+}
@@ -0,0 +1,66 @@
+use bincode::Decode;
+
+use super::{v12, v13::RuleTarget, v15::RuleStats};
+use crate::data::{generic::CompactUuid, rule::*};
+
+// Encrypted peer tunnels.
+
+#[derive(Decode)]
+pub struct Rule {
+    pub id: CompactUuid,
+    pub name: String,
+    pub listen: Vec<RuleAddr>,
+    pub target: RuleTarget,
+    pub protocol: RuleProtocol,
+    pub config: RuleConfig,
+    pub enabled: bool,
+    pub status: RuleStatus,
+    pub stats: RuleStats,
+    pub remarks: String,
+}
+
+#[derive(Decode)]
+pub struct RuleConfig {
+    pub bandwidth: Option<u64>,
+    pub connections: Option<u64>,
+    pub unix_socket_mode: Option<u32>,
+    pub transparent: Option<RuleTransparent>,
+    pub tls: Option<RuleTls>,
+    pub routing: Option<RuleRouting>,
+    pub http: Option<RuleHttp>,
+    pub mirror: Option<RuleAddr>,
+    pub upstream: Option<RuleUpstream>,
+    pub proxy: Option<RuleProxy>,
+    pub tunnel: Option<RuleTunnel>,
+    pub peer: Option<RulePeer>,
+}
+
+pub(super) fn upgrade(rule: Rule) -> crate::model::rule::Rule {
+    v12::upgrade(v12::Rule {
+        id: rule.id,
+        name: rule.name,
+        listen: rule.listen,
+        target: rule.target,
+        protocol: rule.protocol,
+        config: v12::RuleConfig {
+            bandwidth: rule.config.bandwidth,
+            connections: rule.config.connections,
+            unix_socket_mode: rule.config.unix_socket_mode,
+            transparent: rule.config.transparent,
+            tls: rule.config.tls,
+            routing: rule.config.routing,
+            http: rule.config.http,
+            mirror: rule.config.mirror,
+            upstream: rule.config.upstream,
+            proxy: rule.config.proxy,
+            tunnel: rule.config.tunnel,
+            peer: rule.config.peer,
+            reverse: None,
+            agent: None,
+        },
+        enabled: rule.enabled,
+        status: rule.status,
+        stats: rule.stats,
+        remarks: rule.remarks,
+    })
+}
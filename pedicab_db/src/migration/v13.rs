@@ -0,0 +1,79 @@
+use bincode::Decode;
+
+use super::{v14, v15::RuleStats};
+use crate::data::{generic::CompactUuid, rule::*};
+
+// UDP over TCP.
+
+#[derive(Decode)]
+pub struct Rule {
+    pub id: CompactUuid,
+    pub name: String,
+    pub listen: Vec<RuleAddr>,
+    pub target: RuleTarget,
+    pub protocol: RuleProtocol,
+    pub config: RuleConfig,
+    pub enabled: bool,
+    pub status: RuleStatus,
+    pub stats: RuleStats,
+    pub remarks: String,
+}
+
+#[derive(Decode)]
+pub struct RuleTarget {
+    pub addrs: Vec<RuleAddr>,
+    pub policy: RuleTargetPolicy,
+}
+
+#[derive(Decode)]
+pub struct RuleConfig {
+    pub bandwidth: Option<u64>,
+    pub connections: Option<u64>,
+    pub unix_socket_mode: Option<u32>,
+    pub transparent: Option<RuleTransparent>,
+    pub tls: Option<RuleTls>,
+    pub routing: Option<RuleRouting>,
+    pub http: Option<RuleHttp>,
+    pub mirror: Option<RuleAddr>,
+    pub upstream: Option<RuleUpstream>,
+    pub proxy: Option<RuleProxy>,
+    pub tunnel: Option<RuleTunnel>,
+    pub peer: Option<RulePeer>,
+    pub reverse: Option<RuleReverse>,
+    pub agent: Option<String>,
+    pub udp_over_tcp: Option<RuleUdpOverTcp>,
+}
+
+pub(super) fn upgrade(rule: Rule) -> crate::model::rule::Rule {
+    v14::upgrade(v14::Rule {
+        id: rule.id,
+        name: rule.name,
+        listen: rule.listen,
+        target: crate::data::rule::RuleTarget {
+            addrs: rule.target.addrs.into_iter().map(RuleTargetAddr::from).collect(),
+            policy: rule.target.policy,
+        },
+        protocol: rule.protocol,
+        config: v14::RuleConfig {
+            bandwidth: rule.config.bandwidth,
+            connections: rule.config.connections,
+            unix_socket_mode: rule.config.unix_socket_mode,
+            transparent: rule.config.transparent,
+            tls: rule.config.tls,
+            routing: rule.config.routing,
+            http: rule.config.http,
+            mirror: rule.config.mirror,
+            upstream: rule.config.upstream,
+            proxy: rule.config.proxy,
+            tunnel: rule.config.tunnel,
+            peer: rule.config.peer,
+            reverse: rule.config.reverse,
+            agent: rule.config.agent,
+            udp_over_tcp: rule.config.udp_over_tcp,
+        },
+        enabled: rule.enabled,
+        status: rule.status,
+        stats: rule.stats,
+        remarks: rule.remarks,
+    })
+}
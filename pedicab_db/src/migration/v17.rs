@@ -0,0 +1,103 @@
+use std::collections::BTreeMap;
+
+use bincode::Decode;
+
+use super::v18;
+use crate::data::{generic::CompactUuid, rule::*};
+
+// Connection overflow policies.
+
+#[derive(Decode)]
+pub struct Rule {
+    pub id: CompactUuid,
+    pub name: String,
+    pub listen: Vec<RuleAddr>,
+    pub target: RuleTarget,
+    pub protocol: RuleProtocol,
+    pub config: RuleConfig,
+    pub enabled: bool,
+    pub status: RuleStatus,
+    pub stats: RuleStats,
+    pub remarks: String,
+}
+
+#[derive(Decode)]
+pub struct RuleConfig {
+    pub bandwidth: Option<u64>,
+    pub connections: Option<u64>,
+    pub unix_socket_mode: Option<u32>,
+    pub transparent: Option<RuleTransparent>,
+    pub tls: Option<RuleTls>,
+    pub routing: Option<RuleRouting>,
+    pub http: Option<RuleHttp>,
+    pub mirror: Option<RuleAddr>,
+    pub upstream: Option<RuleUpstream>,
+    pub proxy: Option<RuleProxy>,
+    pub tunnel: Option<RuleTunnel>,
+    pub peer: Option<RulePeer>,
+    pub reverse: Option<RuleReverse>,
+    pub agent: Option<String>,
+    pub udp_over_tcp: Option<RuleUdpOverTcp>,
+    pub outlier_detection: Option<RuleOutlierDetection>,
+    pub retries: Option<RuleRetries>,
+    pub overflow: Option<RuleOverflow>,
+}
+
+#[derive(Decode)]
+pub struct RuleStats {
+    pub connections: RuleStatsConnections,
+    pub speed: u64,
+    pub bandwidth: u64,
+    pub failed_times: u64,
+    pub last_failed_message: String,
+    pub unmatched_connections: u64,
+    pub retries: u64,
+    pub overflow: RuleStatsOverflow,
+    pub http: BTreeMap<String, RuleStatsHttp>,
+}
+
+pub(super) fn upgrade(rule: Rule) -> crate::model::rule::Rule {
+    v18::upgrade(v18::Rule {
+        id: rule.id,
+        name: rule.name,
+        listen: rule.listen,
+        target: rule.target,
+        protocol: rule.protocol,
+        config: v18::RuleConfig {
+            bandwidth: rule.config.bandwidth,
+            connections: rule.config.connections,
+            unix_socket_mode: rule.config.unix_socket_mode,
+            transparent: rule.config.transparent,
+            tls: rule.config.tls,
+            routing: rule.config.routing,
+            http: rule.config.http,
+            mirror: rule.config.mirror,
+            upstream: rule.config.upstream,
+            proxy: rule.config.proxy,
+            tunnel: rule.config.tunnel,
+            peer: rule.config.peer,
+            reverse: rule.config.reverse,
+            agent: rule.config.agent,
+            udp_over_tcp: rule.config.udp_over_tcp,
+            outlier_detection: rule.config.outlier_detection,
+            retries: rule.config.retries,
+            overflow: rule.config.overflow,
+            quota: None,
+        },
+        enabled: rule.enabled,
+        status: rule.status,
+        stats: v18::RuleStats {
+            connections: rule.stats.connections,
+            speed: rule.stats.speed,
+            bandwidth: rule.stats.bandwidth,
+            failed_times: rule.stats.failed_times,
+            last_failed_message: rule.stats.last_failed_message,
+            unmatched_connections: rule.stats.unmatched_connections,
+            retries: rule.stats.retries,
+            overflow: rule.stats.overflow,
+            quota: Default::default(),
+            http: rule.stats.http,
+        },
+        remarks: rule.remarks,
+    })
+}
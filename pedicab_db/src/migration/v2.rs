@@ -0,0 +1,54 @@
+use bincode::Decode;
+
+use super::{v3, v5::RuleStats, v13};
+use crate::data::{generic::CompactUuid, rule::*};
+
+// Several listen addresses, all of them IP socket addresses.
+
+#[derive(Decode)]
+pub struct Rule {
+    pub id: CompactUuid,
+    pub name: String,
+    pub listen: Vec<SocketAddrRange>,
+    pub target: RuleTarget,
+    pub protocol: RuleProtocol,
+    pub config: RuleConfig,
+    pub enabled: bool,
+    pub status: RuleStatus,
+    pub stats: RuleStats,
+    pub remarks: String,
+}
+
+#[derive(Decode)]
+pub struct RuleTarget {
+    pub addrs: Vec<SocketAddrRange>,
+    pub policy: RuleTargetPolicy,
+}
+
+#[derive(Decode)]
+pub struct RuleConfig {
+    pub bandwidth: Option<u64>,
+    pub connections: Option<u64>,
+}
+
+pub(super) fn upgrade(rule: Rule) -> crate::model::rule::Rule {
+    v3::upgrade(v3::Rule {
+        id: rule.id,
+        name: rule.name,
+        listen: rule.listen.into_iter().map(RuleAddr::Inet).collect(),
+        target: v13::RuleTarget {
+            addrs: rule.target.addrs.into_iter().map(RuleAddr::Inet).collect(),
+            policy: rule.target.policy,
+        },
+        protocol: rule.protocol,
+        config: v3::RuleConfig {
+            bandwidth: rule.config.bandwidth,
+            connections: rule.config.connections,
+            unix_socket_mode: None,
+        },
+        enabled: rule.enabled,
+        status: rule.status,
+        stats: rule.stats,
+        remarks: rule.remarks,
+    })
+}
@@ -1,4 +1,4 @@
-use std::{hash::Hasher, net::SocketAddr};
+use std::hash::Hasher;
 
 use ahash::AHasher;
 use bincode::{Decode, Encode};
@@ -15,7 +15,7 @@ pub struct Rule {
     pub id: CompactUuid,
     pub name: String,
 
-    pub listen: SocketAddr,
+    pub listen: Vec<RuleAddr>,
     pub target: RuleTarget,
     pub protocol: RuleProtocol,
     pub config: RuleConfig,
@@ -50,10 +50,23 @@ where
 }
 
 impl Rule {
+    // Every listen address of the rule, paired with its offset inside the port range it belongs to.
+    pub fn listen_addrs(&self) -> impl Iterator<Item = (usize, Endpoint)> + '_ {
+        self.listen.iter().flat_map(|range| range.iter().enumerate())
+    }
+
+    pub fn listen_display(&self) -> String {
+        self.listen
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
     pub fn digest_config(&self) -> u64 {
         let mut fields_to_hash = Vec::new();
 
-        bincode::encode_into_std_write(self.listen, &mut fields_to_hash, bincode::config::standard()).unwrap();
+        bincode::encode_into_std_write(&self.listen, &mut fields_to_hash, bincode::config::standard()).unwrap();
         bincode::encode_into_std_write(&self.target, &mut fields_to_hash, bincode::config::standard()).unwrap();
         bincode::encode_into_std_write(&self.protocol, &mut fields_to_hash, bincode::config::standard()).unwrap();
         bincode::encode_into_std_write(&self.config, &mut fields_to_hash, bincode::config::standard()).unwrap();
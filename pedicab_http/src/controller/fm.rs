@@ -1,12 +1,25 @@
+use std::convert::Infallible;
+
 use axum::{
-    extract::{Path, State},
-    response::IntoResponse,
+    Json,
+    body::Body,
+    extract::{Path, Query, State},
+    response::{IntoResponse, Response},
+};
+use futures::stream;
+use http::{
+    StatusCode,
+    header::{CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_TYPE},
 };
-use http::StatusCode;
+use pedicab_core::capture::CaptureParams;
+use pedicab_db::dal::history::{self, FindHistoryParams};
 use tracing::error;
 use uuid::Uuid;
 
-use crate::{AppState, data::response::BaseResponse};
+use crate::{
+    AppState,
+    data::{body::InputBody, query::TopQuery, response::BaseResponse},
+};
 
 pub async fn get_running_rules(State(state): State<AppState>) -> impl IntoResponse {
     let rules = state.fm.get_rules().await;
@@ -52,3 +65,84 @@ pub async fn reset_stat(State(state): State<AppState>, Path(rule_id): Path<Uuid>
         }
     }
 }
+
+pub async fn get_history(
+    State(state): State<AppState>, Path(rule_id): Path<Uuid>, Query(params): Query<FindHistoryParams>,
+) -> impl IntoResponse {
+    match state.dal.history.find_rule(rule_id, params).await {
+        Ok(history) => BaseResponse::success(history),
+        Err(err @ history::Error::Logics(_)) => BaseResponse::error(StatusCode::BAD_REQUEST, err),
+        Err(err) => {
+            error!("failed to fetch rule history: {}", err);
+            BaseResponse::server_error(err)
+        }
+    }
+}
+
+pub async fn get_targets(State(state): State<AppState>, Path(rule_id): Path<Uuid>) -> impl IntoResponse {
+    match state.fm.get_targets(rule_id).await {
+        Ok(targets) => BaseResponse::success(targets),
+        Err(err) => {
+            error!("failed to get targets: {}", err);
+            BaseResponse::error(StatusCode::BAD_REQUEST, err)
+        }
+    }
+}
+
+pub async fn get_clients(
+    State(state): State<AppState>, Path(rule_id): Path<Uuid>, Query(query): Query<TopQuery>,
+) -> impl IntoResponse {
+    match state.fm.get_clients(rule_id, query.top).await {
+        Ok(clients) => BaseResponse::success(clients),
+        Err(err) => {
+            error!("failed to get clients: {}", err);
+            BaseResponse::error(StatusCode::BAD_REQUEST, err)
+        }
+    }
+}
+
+pub async fn start_capture(
+    State(state): State<AppState>, Path(rule_id): Path<Uuid>, Json(body): Json<InputBody<CaptureParams>>,
+) -> impl IntoResponse {
+    match state.fm.start_capture(rule_id, body.data).await {
+        Ok(status) => BaseResponse::success(status),
+        Err(err) => {
+            error!("failed to start capture: {}", err);
+            BaseResponse::error(StatusCode::BAD_REQUEST, err)
+        }
+    }
+}
+
+pub async fn get_capture(State(state): State<AppState>, Path(rule_id): Path<Uuid>) -> Response {
+    match state.fm.get_capture(rule_id) {
+        Some(file) => {
+            let size = file.iter().map(|part| part.len()).sum::<usize>();
+            // the parts are shared with the capture, which may keep recording meanwhile
+            let body = Body::from_stream(stream::iter(file.into_iter().map(Ok::<_, Infallible>)));
+
+            (
+                [
+                    (CONTENT_TYPE, String::from("application/x-pcapng")),
+                    (CONTENT_LENGTH, size.to_string()),
+                    (
+                        CONTENT_DISPOSITION,
+                        format!("attachment; filename=\"{}.pcapng\"", rule_id),
+                    ),
+                ],
+                body,
+            )
+                .into_response()
+        }
+        None => BaseResponse::<()>::error(StatusCode::BAD_REQUEST, "capture not found").into_response(),
+    }
+}
+
+pub async fn remove_capture(State(state): State<AppState>, Path(rule_id): Path<Uuid>) -> impl IntoResponse {
+    match state.fm.remove_capture(rule_id) {
+        Ok(_) => BaseResponse::success("ok"),
+        Err(err) => {
+            error!("failed to remove capture: {}", err);
+            BaseResponse::error(StatusCode::BAD_REQUEST, err)
+        }
+    }
+}
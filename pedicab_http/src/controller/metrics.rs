@@ -1,5 +1,11 @@
-use axum::{extract::State, response::IntoResponse};
+use axum::{
+    extract::{Query, State},
+    response::IntoResponse,
+};
+use http::StatusCode;
+use pedicab_db::dal::history::{self, FindHistoryParams};
 use sysinfo::{Disks, Networks, System};
+use tracing::error;
 
 use crate::{
     AppState,
@@ -83,3 +89,14 @@ pub async fn get_host_info() -> impl IntoResponse {
 
     BaseResponse::success(host_info)
 }
+
+pub async fn get_history(State(state): State<AppState>, Query(params): Query<FindHistoryParams>) -> impl IntoResponse {
+    match state.dal.history.find_host(params).await {
+        Ok(history) => BaseResponse::success(history),
+        Err(err @ history::Error::Logics(_)) => BaseResponse::error(StatusCode::BAD_REQUEST, err),
+        Err(err) => {
+            error!("failed to fetch host history: {}", err);
+            BaseResponse::server_error(err)
+        }
+    }
+}
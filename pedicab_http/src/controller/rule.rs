@@ -58,7 +58,12 @@ pub async fn update_rule(
 
 pub async fn delete_rule(State(state): State<AppState>, Path(rule_id): Path<Uuid>) -> impl IntoResponse {
     match state.dal.rule.delete(rule_id).await {
-        Ok(_) => BaseResponse::success("ok"),
+        Ok(_) => {
+            if let Err(err) = state.dal.history.delete_rule(rule_id).await {
+                error!("failed to delete rule history: {}", err);
+            }
+            BaseResponse::success("ok")
+        }
         Err(err) => {
             error!("failed to delete rule by id: {}", err);
             BaseResponse::server_error(err)
@@ -1,3 +1,4 @@
 pub(super) mod body;
 pub(super) mod metrics;
+pub(super) mod query;
 pub(super) mod response;
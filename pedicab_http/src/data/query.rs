@@ -0,0 +1,10 @@
+#[derive(Debug, serde::Deserialize)]
+pub struct TopQuery {
+    // entries listed at most, heaviest first
+    #[serde(default = "default_top")]
+    pub top: usize,
+}
+
+fn default_top() -> usize {
+    10
+}
@@ -65,14 +65,24 @@ pub fn build_router(app_state: AppState) -> Router<AppState> {
                             "/stats/{rule_id}",
                             get(controller::fm::get_stat).delete(controller::fm::reset_stat),
                         )
-                        .route("/restart/{rule_id}", post(controller::fm::restart_rule)),
+                        .route("/stats/{rule_id}/clients", get(controller::fm::get_clients))
+                        .route("/stats/{rule_id}/history", get(controller::fm::get_history))
+                        .route("/restart/{rule_id}", post(controller::fm::restart_rule))
+                        .route("/targets/{rule_id}", get(controller::fm::get_targets))
+                        .route(
+                            "/capture/{rule_id}",
+                            get(controller::fm::get_capture)
+                                .post(controller::fm::start_capture)
+                                .delete(controller::fm::remove_capture),
+                        ),
                 )
                 .nest(
                     "/metrics",
                     Router::new()
                         .route("/system", get(controller::metrics::get_system_info))
                         .route("/network", get(controller::metrics::get_network_info))
-                        .route("/host", get(controller::metrics::get_host_info)),
+                        .route("/host", get(controller::metrics::get_host_info))
+                        .route("/history", get(controller::metrics::get_history)),
                 )
                 .nest(
                     "/health",